@@ -102,16 +102,16 @@ pub async fn deploy(
     .unwrap_or("Created");
   let suffix = resp
     .errors
-    .is_empty()
-    .then_some("")
-    .unwrap_or(" with error");
+    .has_errors()
+    .then_some(" with error")
+    .unwrap_or("");
   println!(
     "{prefix} service '{}' ({}){suffix}",
     resp.new_service.service.name(),
     resp.new_service.service.uuid()
   );
 
-  if !resp.errors.is_empty() {
+  if resp.errors.has_errors() {
     println!("Errors:");
     if resp.errors.start.is_some() {
       println!(
@@ -136,6 +136,12 @@ pub async fn deploy(
       );
     }
   }
+  if !resp.errors.warnings.is_empty() {
+    println!("Warnings:");
+    for warning in &resp.errors.warnings {
+      println!("  - {warning}");
+    }
+  }
 
   debug!("Response: {resp:#?}");
 
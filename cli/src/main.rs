@@ -96,8 +96,10 @@ fn main() -> anyhow::Result<()> {
         config,
         abel_path: abel_path.path().into(),
       };
+      // Services under development commonly talk to local ones
       let default_config = Config {
         auth_token: None,
+        egress_block_private: Some(false),
         ..Default::default()
       };
       let services = services
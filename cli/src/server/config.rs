@@ -1,9 +1,12 @@
+use abel_core::{EgressPolicy, EgressRule, HttpClientOptions, DEFAULT_STORAGE_CLOSE_TIMEOUT};
+use anyhow::Context;
 use clap::Parser;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 use uuid::Uuid;
 
@@ -40,6 +43,66 @@ pub struct ConfigArgs {
   /// Abel executor pool size [overrides config]
   #[clap(long)]
   pub pool_size: Option<usize>,
+
+  /// Default CPU time budget of services in milliseconds [overrides config]
+  #[clap(long)]
+  pub default_cpu_time: Option<u64>,
+
+  /// Maximum CPU time budget a service can request in milliseconds [overrides config]
+  #[clap(long)]
+  pub max_cpu_time: Option<u64>,
+
+  /// Default memory quota of services in bytes [overrides config]
+  #[clap(long)]
+  pub default_memory_limit: Option<usize>,
+
+  /// Maximum memory quota a service can request in bytes [overrides config]
+  #[clap(long)]
+  pub max_memory_limit: Option<usize>,
+
+  /// Default maximum rows a SQL statement of services can read or write [overrides config]
+  #[clap(long)]
+  pub default_sql_max_rows: Option<usize>,
+
+  /// Maximum rows of SQL statements a service can request [overrides config]
+  #[clap(long)]
+  pub max_sql_max_rows: Option<usize>,
+
+  /// Maximum request body size of services in bytes [overrides config]
+  #[clap(long)]
+  pub max_body_size: Option<u64>,
+
+  /// How long removing a service waits for its databases to be released in
+  /// milliseconds [overrides config]
+  #[clap(long)]
+  pub storage_close_timeout: Option<u64>,
+
+  /// Timeout of outbound HTTP requests in milliseconds, 0 for none [overrides config]
+  #[clap(long)]
+  pub http_timeout: Option<u64>,
+
+  /// Whether outbound HTTP requests follow redirects [overrides config]
+  #[clap(long)]
+  pub http_follow_redirects: Option<bool>,
+
+  /// Maximum size of outbound HTTP response bodies in bytes [overrides config]
+  #[clap(long)]
+  pub http_max_body_size: Option<usize>,
+
+  /// HTTP proxy of outbound HTTP requests; hosts are then resolved by the
+  /// proxy, so it requires `--egress-block-private false` [overrides config]
+  #[clap(long)]
+  pub http_proxy: Option<String>,
+
+  /// Hosts (`example.com`, `*.example.com`) and networks (`10.0.0.0/8`)
+  /// services are allowed to send requests to [overrides config]
+  #[clap(long)]
+  pub egress_allow: Option<Vec<EgressRule>>,
+
+  /// Whether to deny requests to private and loopback addresses not allowed
+  /// explicitly; must be false to use `--http-proxy` [overrides config]
+  #[clap(long)]
+  pub egress_block_private: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,6 +110,20 @@ pub struct Config {
   pub listen: SocketAddr,
   pub auth_token: Option<Uuid>,
   pub(crate) pool_size: Option<usize>,
+  pub(crate) default_cpu_time: Option<u64>,
+  pub(crate) max_cpu_time: Option<u64>,
+  pub(crate) default_memory_limit: Option<usize>,
+  pub(crate) max_memory_limit: Option<usize>,
+  pub(crate) default_sql_max_rows: Option<usize>,
+  pub(crate) max_sql_max_rows: Option<usize>,
+  pub(crate) max_body_size: Option<u64>,
+  pub(crate) storage_close_timeout: Option<u64>,
+  pub(crate) http_timeout: Option<u64>,
+  pub(crate) http_follow_redirects: Option<bool>,
+  pub(crate) http_max_body_size: Option<usize>,
+  pub(crate) http_proxy: Option<String>,
+  pub(crate) egress_allow: Option<Vec<EgressRule>>,
+  pub(crate) egress_block_private: Option<bool>,
 }
 
 impl Default for Config {
@@ -55,6 +132,20 @@ impl Default for Config {
       listen: ([127, 0, 0, 1], 3000).into(),
       auth_token: Some(Uuid::new_v4()),
       pool_size: None,
+      default_cpu_time: None,
+      max_cpu_time: None,
+      default_memory_limit: None,
+      max_memory_limit: None,
+      default_sql_max_rows: None,
+      max_sql_max_rows: None,
+      max_body_size: None,
+      storage_close_timeout: None,
+      http_timeout: None,
+      http_follow_redirects: None,
+      http_max_body_size: None,
+      http_proxy: None,
+      egress_allow: None,
+      egress_block_private: None,
     }
   }
 }
@@ -84,10 +175,102 @@ impl Config {
     args.listen.map(|x| self.listen = x);
     args.auth_token.map(|x| self.auth_token = Some(x));
     args.pool_size.map(|x| self.pool_size = Some(x));
+    args
+      .default_cpu_time
+      .map(|x| self.default_cpu_time = Some(x));
+    args.max_cpu_time.map(|x| self.max_cpu_time = Some(x));
+    args
+      .default_memory_limit
+      .map(|x| self.default_memory_limit = Some(x));
+    args
+      .max_memory_limit
+      .map(|x| self.max_memory_limit = Some(x));
+    args
+      .default_sql_max_rows
+      .map(|x| self.default_sql_max_rows = Some(x));
+    args
+      .max_sql_max_rows
+      .map(|x| self.max_sql_max_rows = Some(x));
+    args.max_body_size.map(|x| self.max_body_size = Some(x));
+    args
+      .storage_close_timeout
+      .map(|x| self.storage_close_timeout = Some(x));
+    args.http_timeout.map(|x| self.http_timeout = Some(x));
+    args
+      .http_follow_redirects
+      .map(|x| self.http_follow_redirects = Some(x));
+    args
+      .http_max_body_size
+      .map(|x| self.http_max_body_size = Some(x));
+    args.http_proxy.map(|x| self.http_proxy = Some(x));
+    args.egress_allow.map(|x| self.egress_allow = Some(x));
+    args
+      .egress_block_private
+      .map(|x| self.egress_block_private = Some(x));
     self
   }
 
   pub fn pool_size(&self) -> usize {
     self.pool_size.unwrap_or(*HALF_NUM_CPUS)
   }
+
+  pub fn default_cpu_time(&self) -> Duration {
+    Duration::from_millis(self.default_cpu_time.unwrap_or(1000))
+  }
+
+  pub fn max_cpu_time(&self) -> Duration {
+    Duration::from_millis(self.max_cpu_time.unwrap_or(10000))
+  }
+
+  pub fn default_memory_limit(&self) -> usize {
+    self.default_memory_limit.unwrap_or(64 << 20)
+  }
+
+  pub fn max_memory_limit(&self) -> usize {
+    self.max_memory_limit.unwrap_or(256 << 20)
+  }
+
+  pub fn default_sql_max_rows(&self) -> usize {
+    self.default_sql_max_rows.unwrap_or(10000)
+  }
+
+  pub fn max_sql_max_rows(&self) -> usize {
+    self.max_sql_max_rows.unwrap_or(100000)
+  }
+
+  pub fn storage_close_timeout(&self) -> Duration {
+    (self.storage_close_timeout)
+      .map(Duration::from_millis)
+      .unwrap_or(DEFAULT_STORAGE_CLOSE_TIMEOUT)
+  }
+
+  pub fn http_client(&self) -> anyhow::Result<HttpClientOptions> {
+    let default = HttpClientOptions::default();
+    let proxy = (self.http_proxy.as_deref())
+      .map(|x| {
+        x.parse()
+          .with_context(|| format!("invalid HTTP proxy '{x}'"))
+      })
+      .transpose()?;
+    Ok(HttpClientOptions {
+      timeout: match self.http_timeout {
+        Some(0) => None,
+        Some(x) => Some(Duration::from_millis(x)),
+        None => default.timeout,
+      },
+      follow_redirects: self
+        .http_follow_redirects
+        .unwrap_or(default.follow_redirects),
+      max_body_size: self.http_max_body_size.or(default.max_body_size),
+      proxy,
+    })
+  }
+
+  pub fn egress(&self) -> EgressPolicy {
+    let default = EgressPolicy::default();
+    EgressPolicy {
+      allow: self.egress_allow.clone().or(default.allow),
+      block_private: self.egress_block_private.unwrap_or(default.block_private),
+    }
+  }
 }
@@ -1,5 +1,6 @@
 use super::json_response_raw;
 use backtrace::Backtrace;
+use hyper::header::{HeaderValue, ALLOW};
 use hyper::{Body, Method, Response, StatusCode};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
@@ -158,8 +159,21 @@ impl From<&'static str> for Error {
 
 impl From<Error> for Response<Body> {
   fn from(x: Error) -> Self {
+    let allow = match &x.kind {
+      ErrorKind::Abel(error) => match error.kind() {
+        abel_core::ErrorKind::MethodNotAllowed { allowed, .. } => {
+          HeaderValue::from_str(&allowed.join(", ")).ok()
+        }
+        _ => None,
+      },
+      _ => None,
+    };
     let (status, body) = x.into_status_and_body();
-    json_response_raw(status, body)
+    let mut resp = json_response_raw(status, body);
+    if let Some(allow) = allow {
+      resp.headers_mut().insert(ALLOW, allow);
+    }
+    resp
   }
 }
 
@@ -179,17 +193,14 @@ pub enum ErrorKind {
   Unauthorized,
 
   // Errors when reading multipart body are *mostly* client-side, so they all
-  // currently use 400 Bad Request for simplicity.
+  // currently use 400 Bad Request for simplicity, except for size limits
+  // exceeded, which turn into `PayloadTooLarge`.
   //
   // This may change in the future if `multer::Error` proved not suitable to
   // be exposed to untrusted client.
   #[error(transparent)]
   #[strum(props(status = "400", error = "failed to read multipart body"))]
-  Multipart(
-    #[from]
-    #[serde(serialize_with = "serialize_error")]
-    multer::Error,
-  ),
+  Multipart(#[serde(serialize_with = "serialize_error")] multer::Error),
 
   #[error(transparent)]
   #[strum(props(status = "400", error = "failed to (de)serialize object"))]
@@ -209,11 +220,7 @@ pub enum ErrorKind {
 
   #[error(transparent)]
   #[strum(props(status = "500", error = "I/O error"))]
-  Io(
-    #[from]
-    #[serde(serialize_with = "serialize_error")]
-    tokio::io::Error,
-  ),
+  Io(#[serde(serialize_with = "serialize_error")] tokio::io::Error),
 
   #[error(transparent)]
   #[serde(skip)]
@@ -228,6 +235,33 @@ pub enum ErrorKind {
   },
 }
 
+impl From<multer::Error> for ErrorKind {
+  fn from(error: multer::Error) -> Self {
+    match error {
+      multer::Error::FieldSizeExceeded { limit, .. }
+      | multer::Error::StreamSizeExceeded { limit } => {
+        Self::Abel(abel_core::ErrorKind::PayloadTooLarge { limit }.into())
+      }
+      // Limits of the whole stream are checked when reading it
+      multer::Error::StreamReadFailed(error) if error.is::<multer::Error>() => {
+        Self::from(*error.downcast::<multer::Error>().unwrap())
+      }
+      error => Self::Multipart(error),
+    }
+  }
+}
+
+impl From<tokio::io::Error> for ErrorKind {
+  fn from(error: tokio::io::Error) -> Self {
+    // Multipart fields are read as streams of I/O results
+    if error.get_ref().is_some_and(|x| x.is::<multer::Error>()) {
+      let error = error.into_inner().unwrap().downcast::<multer::Error>();
+      return Self::from(*error.unwrap());
+    }
+    Self::Io(error)
+  }
+}
+
 fn serialize_error<E, S>(error: E, ser: S) -> Result<S::Ok, S::Error>
 where
   E: std::error::Error,
@@ -2,8 +2,11 @@ use super::error::ErrorKind::Unauthorized;
 use super::error::{method_not_allowed, ErrorAuthWrapper};
 use super::types::{OwnedServiceWithStatus, ServiceWithStatus};
 use super::upload::upload;
-use super::{authenticate, json_response, Metadata, Result, ServerState};
+use super::{
+  authenticate, json_response, read_saved_source, EnvOverrides, Metadata, Result, ServerState,
+};
 use crate::server::types::ServiceStatus::{Running, Stopped};
+use abel_core::service::Service;
 use abel_core::ErrorKind::{ServiceDropped, ServiceNotFound};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use log::{error, info};
@@ -30,7 +33,7 @@ pub(crate) async fn handle(
   let segments = path
     .split('/')
     .filter(|x| !x.is_empty())
-    .collect::<Box<_>>();
+    .collect::<Box<[_]>>();
 
   let auth = authenticate(&state, &req);
 
@@ -52,6 +55,14 @@ pub(crate) async fn handle(
         method,
       )),
 
+      (GET, [name, "env"]) => get_env(&state, name).await,
+      (PUT, [name, "env"]) => put_env(&state, (*name).into(), req).await,
+      (_, [_name, "env"]) => Err(method_not_allowed(&["GET", "PUT"], method)),
+
+      (GET, [name, "dead-jobs"]) => list_dead_jobs(&state, name).await,
+      (DELETE, [name, "dead-jobs"]) => purge_dead_jobs(&state, name).await,
+      (_, [_name, "dead-jobs"]) => Err(method_not_allowed(&["GET", "DELETE"], method)),
+
       (_, [..]) => Err((404, "path not found", json!({ "path": path })).into()),
     },
 
@@ -157,6 +168,59 @@ async fn start_stop(state: &ServerState, name: &str, query: &str) -> Result<Resp
   }
 }
 
+async fn get_env(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let env_path = state.abel_path.join(format!("services/{name}/env.json"));
+  json_response(StatusCode::OK, EnvOverrides::read(&env_path).await?)
+}
+
+/// Replaces environment overrides of a service and reloads it.
+async fn put_env(state: &ServerState, name: String, req: Request<Body>) -> Result<Response<Body>> {
+  let (uuid, running) = {
+    let service = state.abel.get_service(&name)?;
+    let uuid = service.upgrade().uuid();
+    (uuid, service.is_running())
+  };
+
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| ("failed to read request body", error.to_string()))?;
+  let env: EnvOverrides = serde_json::from_slice(&body)?;
+
+  let service_path = state.abel_path.join("services").join(&name);
+  let (source, mut config) = read_saved_source(&service_path).await?;
+  env.apply(&mut config);
+
+  let service = if running {
+    let (service, _replaced, _error_payload) = (state.abel)
+      .hot_update_service(name.clone(), Some(uuid), source, config)
+      .await?;
+    Service::Running(service)
+  } else {
+    let (service, _replaced, _error_payload) = (state.abel)
+      .load_service(name.clone(), Some(uuid), source, config)
+      .await?;
+    Service::Stopped(service)
+  };
+  env.write(&service_path.join("env.json")).await?;
+
+  info!(
+    "Updated environment of service '{name}' {}",
+    format!("({uuid})").dimmed()
+  );
+  json_response(StatusCode::OK, OwnedServiceWithStatus::from(service))
+}
+
+async fn list_dead_jobs(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  json_response(StatusCode::OK, state.abel.list_dead_jobs(name).await?)
+}
+
+async fn purge_dead_jobs(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let purged = state.abel.purge_dead_jobs(name).await?;
+  info!("Purged {purged} dead jobs of service '{name}'");
+  json_response(StatusCode::OK, json!({ "purged": purged }))
+}
+
 async fn remove(state: &ServerState, service_name: &str) -> Result<Response<Body>> {
   let removed = state.abel.remove_service(service_name).await?;
   tokio::fs::remove_dir_all(state.abel_path.join("services").join(service_name)).await?;
@@ -1,5 +1,7 @@
 use super::Result;
+use abel_core::Config;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
 use tokio::{fs, io};
 use uuid::Uuid;
@@ -28,3 +30,29 @@ impl Metadata {
     Ok(())
   }
 }
+
+/// Environment variables set through the management API.
+///
+/// They override those in `abel.json`, so that secrets can be kept out of the
+/// source.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EnvOverrides(pub HashMap<String, String>);
+
+impl EnvOverrides {
+  pub async fn read(path: &Path) -> io::Result<Self> {
+    match fs::read(path).await {
+      Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(error) => Err(error),
+    }
+  }
+
+  pub async fn write(&self, path: &Path) -> io::Result<()> {
+    fs::write(path, serde_json::to_string(self)?).await
+  }
+
+  pub fn apply(&self, config: &mut Config) {
+    (config.env).extend(self.0.iter().map(|(k, v)| (k.clone(), v.clone())));
+  }
+}
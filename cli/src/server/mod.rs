@@ -12,7 +12,6 @@ use crate::source::{AsarSource, SingleSource};
 use abel_core::service::Service;
 use abel_core::source::Source;
 use abel_core::{Abel, AbelOptions};
-use anyhow::bail;
 use config::{Config, ServerArgs};
 use error::Error;
 use handle::handle;
@@ -20,7 +19,7 @@ use hive_asar::Archive;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
 use log::{error, info, warn};
-use metadata::Metadata;
+use metadata::{EnvOverrides, Metadata};
 use owo_colors::OwoColorize;
 use serde::Serialize;
 use std::convert::Infallible;
@@ -37,6 +36,7 @@ pub struct ServerState {
   pub abel: Abel,
   pub abel_path: PathBuf,
   pub auth_token: Option<Uuid>,
+  pub max_body_size: Option<u64>,
 }
 
 pub async fn run(config: Config, state: Arc<ServerState>) -> anyhow::Result<()> {
@@ -82,9 +82,20 @@ pub async fn init_state(
       runtime_pool_size: config.pool_size(),
       local_storage_path,
       remote_cache_path: Some(remote_cache_path),
+      default_cpu_time: config.default_cpu_time(),
+      max_cpu_time: config.max_cpu_time(),
+      default_memory_limit: config.default_memory_limit(),
+      max_memory_limit: config.max_memory_limit(),
+      default_sql_max_rows: config.default_sql_max_rows(),
+      max_sql_max_rows: config.max_sql_max_rows(),
+      max_body_size: config.max_body_size,
+      http_client: config.http_client()?,
+      egress: config.egress(),
+      storage_close_timeout: config.storage_close_timeout(),
     })?,
     abel_path: abel_path.clone(),
     auth_token: config.auth_token,
+    max_body_size: config.max_body_size,
   });
   Ok((abel_path, config, state))
 }
@@ -136,32 +147,10 @@ pub async fn load_saved_services(state: &ServerState, services_path: &Path) -> a
         let metadata_path = service_folder.path().join("metadata.json");
         let mut metadata = Metadata::read(&metadata_path).await?;
 
-        let asar_path = service_folder.path().join("source.asar");
-        let lua_path = service_folder.path().join("source.lua");
-
-        let (source, config) = match (asar_path.exists(), lua_path.exists()) {
-          (true, false) => {
-            let mut archive = Archive::new_from_file(asar_path).await?;
-
-            let config = if let Ok(mut config_file) = archive.get("abel.json").await {
-              let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
-              config_file.read_to_end(&mut config_bytes).await?;
-              serde_json::from_slice(&config_bytes)?
-            } else {
-              Default::default()
-            };
-
-            let source = Source::new(AsarSource(archive));
-            (source, config)
-          }
-          (false, true) => {
-            let code = fs::read(lua_path).await?;
-            let source = Source::new(SingleSource::new(code));
-            (source, Default::default())
-          }
-          (true, true) => bail!("both source.asar and source.lua found"),
-          (false, false) => bail!("neither source.asar nor source.lua found"),
-        };
+        let (source, mut config) = read_saved_source(&service_folder.path()).await?;
+        EnvOverrides::read(&service_folder.path().join("env.json"))
+          .await?
+          .apply(&mut config);
 
         let (service, error_payload) = if metadata.started {
           let (service, _, error_payload) = (state.abel)
@@ -206,6 +195,44 @@ pub async fn load_saved_services(state: &ServerState, services_path: &Path) -> a
   Ok(())
 }
 
+/// Reads source and config of a service saved in `service_path`.
+pub(crate) async fn read_saved_source(service_path: &Path) -> Result<(Source, abel_core::Config)> {
+  let asar_path = service_path.join("source.asar");
+  let lua_path = service_path.join("source.lua");
+
+  match (asar_path.exists(), lua_path.exists()) {
+    (true, false) => {
+      let mut archive = Archive::new_from_file(asar_path).await?;
+
+      let config = if let Ok(mut config_file) = archive.get("abel.json").await {
+        let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
+        config_file.read_to_end(&mut config_bytes).await?;
+        serde_json::from_slice(&config_bytes)?
+      } else {
+        Default::default()
+      };
+
+      let source = Source::new(AsarSource(archive));
+      Ok((source, config))
+    }
+    (false, true) => {
+      let code = fs::read(lua_path).await?;
+      let source = Source::new(SingleSource::new(code));
+      Ok((source, Default::default()))
+    }
+    (true, true) => Err(From::from((
+      500,
+      "invalid service folder",
+      "both source.asar and source.lua found",
+    ))),
+    (false, false) => Err(From::from((
+      500,
+      "invalid service folder",
+      "neither source.asar nor source.lua found",
+    ))),
+  }
+}
+
 #[cfg(unix)]
 async fn shutdown_signal() {
   use tokio::select;
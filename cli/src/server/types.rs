@@ -67,11 +67,17 @@ impl<'a> ServiceWithStatus<'a> {
 pub struct ErrorPayload<'a> {
   pub start: Option<Cow<'a, str>>,
   pub stop: Option<Cow<'a, str>>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub warnings: Vec<Cow<'a, str>>,
 }
 
 impl ErrorPayload<'_> {
   pub fn is_empty(&self) -> bool {
-    self.start.is_none() && self.stop.is_none()
+    !self.has_errors() && self.warnings.is_empty()
+  }
+
+  pub fn has_errors(&self) -> bool {
+    self.start.is_some() || self.stop.is_some()
   }
 }
 
@@ -80,6 +86,7 @@ impl<'a> From<abel_core::service::ErrorPayload> for ErrorPayload<'a> {
     Self {
       start: payload.start.map(|x| x.to_string().into()),
       stop: payload.stop.map(|x| x.to_string().into()),
+      warnings: payload.warnings.into_iter().map(Into::into).collect(),
     }
   }
 }
@@ -1,4 +1,4 @@
-use super::metadata::Metadata;
+use super::metadata::{EnvOverrides, Metadata};
 use super::types::{HttpUploadResponse, ServiceWithStatus};
 use super::{json_response, Result, ServerState};
 use crate::source::{AsarSource, SingleSource};
@@ -71,7 +71,7 @@ pub async fn upload(
   req: Request<Body>,
 ) -> Result<Response<Body>> {
   let (parts, body) = req.into_parts();
-  let mut multipart = parse_multipart(&parts.headers, body)?;
+  let mut multipart = parse_multipart(&parts.headers, body, state.max_body_size)?;
 
   let UploadQuery { mode } = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
 
@@ -109,12 +109,19 @@ pub async fn upload_local(
   create_service(state, mode, name, config, source, kind, &temp_path).await
 }
 
-fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>> {
+fn parse_multipart(
+  headers: &HeaderMap,
+  body: Body,
+  max_body_size: Option<u64>,
+) -> Result<Multipart<'static>> {
   let allowed_fields = vec!["single", "multi", "config"];
-  let size_limit = SizeLimit::new()
+  let mut size_limit = SizeLimit::new()
     .for_field("single", 1024u64.pow(2) * 5)
     .for_field("multi", 1024u64.pow(2) * 100)
     .for_field("config", 1024u64.pow(2) * 5);
+  if let Some(max) = max_body_size {
+    size_limit = size_limit.whole_stream(max);
+  }
 
   let content_type = headers
     .get("content-type")
@@ -173,24 +180,29 @@ async fn create_service<'a>(
   state: &'a ServerState,
   mode: UploadMode,
   name: String,
-  config: Config,
+  mut config: Config,
   source: Source,
   source_kind: SourceKind,
   temp_path: &Path,
 ) -> Result<UploadResponse<'a>> {
+  // Keep environment overrides across updates
+  let env = if state.abel.get_service(&name).is_ok() {
+    let env_path = state.abel_path.join(format!("services/{name}/env.json"));
+    EnvOverrides::read(&env_path).await?
+  } else {
+    EnvOverrides::default()
+  };
+  env.apply(&mut config);
+
   let (new_service, replaced_service, errors) = match mode {
     UploadMode::Create if state.abel.get_service(&name).is_ok() => {
       return Err(ServiceExists { name: name.into() }.into())
     }
     UploadMode::Hot if state.abel.get_running_service(&name).is_ok() => {
-      let (service, replaced) = (state.abel)
+      let (service, replaced, error_payload) = (state.abel)
         .hot_update_service(name, None, source, config)
         .await?;
-      (
-        Service::Running(service),
-        Some(replaced),
-        Default::default(),
-      )
+      (Service::Running(service), Some(replaced), error_payload)
     }
     UploadMode::Hot | UploadMode::Cold | UploadMode::Create => {
       (state.abel)
@@ -217,6 +229,9 @@ async fn create_service<'a>(
     started: true,
   };
   metadata.write(&service_path.join("metadata.json")).await?;
+  if !env.0.is_empty() {
+    env.write(&service_path.join("env.json")).await?;
+  }
 
   match source_kind {
     SourceKind::Single => fs::rename(temp_path, service_path.join("source.lua")).await?,
@@ -272,3 +287,39 @@ async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
   };
   json_response(StatusCode::OK, body)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::{Config, ConfigArgs, ServerArgs};
+  use crate::server::init_state;
+  use clap::Parser;
+
+  #[tokio::test]
+  async fn test_upload_too_large() {
+    let abel_path = tempfile::tempdir().unwrap();
+    let args = ServerArgs {
+      config: ConfigArgs::parse_from(["abel", "--max-body-size", "1024"]),
+      abel_path: abel_path.path().into(),
+    };
+    let (_, _, state) = init_state(args, Config::default()).await.unwrap();
+
+    let code = format!(
+      "-- {}\nabel.listen(\"/\", function() end)\n",
+      "x".repeat(2048)
+    );
+    let body = format!(
+      "--boundary\r\nContent-Disposition: form-data; name=\"single\"\r\n\r\n{code}\r\n--boundary--\r\n"
+    );
+    let req = Request::put("/test")
+      .header("content-type", "multipart/form-data; boundary=boundary")
+      .body(Body::from(body))
+      .unwrap();
+
+    let error = upload(&state, "test".into(), req).await.unwrap_err();
+    let (status, body) = error.into_status_and_body();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body.error, "payload too large");
+    assert!(state.abel.get_service("test").is_err());
+  }
+}
@@ -1,8 +1,80 @@
-use serde::Deserialize;
+use crate::egress::EgressRule;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
   #[serde(rename = "name")]
   pub pkg_name: Option<String>,
   pub description: Option<String>,
+  #[serde(default)]
+  pub cpu_time: CpuTimeConfig,
+  /// Memory quota in bytes.
+  pub memory_limit: Option<usize>,
+  /// Environment variables readable by `os.getenv`.
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Maximum number of rows a SQL statement can read or write.
+  pub sql_max_rows: Option<usize>,
+  /// Maximum size of request body in bytes, for routes that do not specify
+  /// one in options of `abel.listen`.
+  pub max_body_size: Option<u64>,
+  /// Services allowed to call this one with `abel.call`; `"*"` allows all of
+  /// them.
+  #[serde(default)]
+  pub allow_callers: Vec<String>,
+  /// Hosts and networks the service sends requests to. If specified, any other
+  /// destination is denied, on top of the server's own egress policy. Networks
+  /// also match the addresses host names resolve to.
+  pub egress: Option<Vec<EgressRule>>,
+}
+
+/// CPU time budgets requested by a service, in milliseconds.
+///
+/// Budgets not specified fall back to the server's default, and all budgets
+/// are capped by the server's maximum.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct CpuTimeConfig {
+  /// Budget for running `main.lua`.
+  pub load: Option<u64>,
+  /// Budget for `abel.start` and `abel.stop`.
+  pub lifecycle: Option<u64>,
+  /// Budget for each request handler call.
+  pub request: Option<u64>,
+}
+
+impl CpuTimeConfig {
+  pub(crate) fn resolve(&self, default: Duration, max: Duration) -> CpuTimeLimits {
+    let resolve = |x: Option<u64>| x.map(Duration::from_millis).unwrap_or(default).min(max);
+    CpuTimeLimits {
+      load: resolve(self.load),
+      lifecycle: resolve(self.lifecycle),
+      request: resolve(self.request),
+    }
+  }
+}
+
+/// Resolved CPU time budgets of a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuTimeLimits {
+  #[serde(with = "millis")]
+  pub load: Duration,
+  #[serde(with = "millis")]
+  pub lifecycle: Duration,
+  #[serde(with = "millis")]
+  pub request: Duration,
+}
+
+mod millis {
+  use serde::{Deserialize, Deserializer, Serializer};
+  use std::time::Duration;
+
+  pub fn serialize<S: Serializer>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(x.as_millis().try_into().unwrap_or(u64::MAX))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+  }
 }
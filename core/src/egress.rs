@@ -0,0 +1,310 @@
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Networks not reachable from the public Internet, including loopback,
+/// private, shared, link-local, unique and site local, benchmarking, reserved,
+/// multicast and broadcast ones.
+static PRIVATE_NETWORKS: Lazy<Vec<IpNet>> = Lazy::new(|| {
+  [
+    "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12",
+    "192.0.0.0/24", "192.168.0.0/16", "198.18.0.0/15", "224.0.0.0/4", "240.0.0.0/4", "::/128",
+    "::1/128", "fc00::/7", "fe80::/10", "fec0::/10", "ff00::/8",
+  ]
+  .into_iter()
+  .map(|x| x.parse().unwrap())
+  .collect()
+});
+
+/// Well-known prefix of NAT64 addresses, which embed an IPv4 address in their
+/// last 32 bits.
+static NAT64_NETWORK: Lazy<IpNet> = Lazy::new(|| "64:ff9b::/96".parse().unwrap());
+
+/// Prefix of 6to4 addresses, which embed an IPv4 address in bits 16 to 48.
+static SIX_TO_FOUR_NETWORK: Lazy<IpNet> = Lazy::new(|| "2002::/16".parse().unwrap());
+
+/// Strips brackets around IPv6 addresses and lowercases host names.
+fn normalize_host(host: &str) -> String {
+  host
+    .trim_start_matches('[')
+    .trim_end_matches(']')
+    .to_ascii_lowercase()
+}
+
+/// Maps IPv4-mapped, NAT64 and 6to4 addresses to the IPv4 address they reach.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+  let v4 = |a: u16, b: u16| IpAddr::V4(Ipv4Addr::from((u32::from(a) << 16) | u32::from(b)));
+  match ip {
+    IpAddr::V6(v6) if NAT64_NETWORK.contains(&ip) => {
+      let [.., a, b] = v6.segments();
+      v4(a, b)
+    }
+    IpAddr::V6(v6) if SIX_TO_FOUR_NETWORK.contains(&ip) => {
+      let [_, a, b, ..] = v6.segments();
+      v4(a, b)
+    }
+    IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+    ip => ip,
+  }
+}
+
+/// Destination allowed by an egress policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum EgressRule {
+  /// Host name, or all of its subdomains if starting with `*.`.
+  Host(Box<str>),
+  /// IP network in CIDR notation, or a single IP address.
+  Network(IpNet),
+}
+
+impl EgressRule {
+  fn matches_host(&self, host: &str) -> bool {
+    match self {
+      Self::Host(pattern) => match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+          .strip_suffix(suffix)
+          .is_some_and(|x| x.len() > 1 && x.ends_with('.')),
+        None => host == &**pattern,
+      },
+      Self::Network(_) => false,
+    }
+  }
+
+  fn matches_ip(&self, ip: IpAddr) -> bool {
+    match self {
+      Self::Network(net) => net.contains(&ip),
+      Self::Host(_) => false,
+    }
+  }
+}
+
+impl FromStr for EgressRule {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Ok(net) = s.parse::<IpNet>() {
+      return Ok(Self::Network(net));
+    }
+    if let Ok(ip) = s.parse::<IpAddr>() {
+      return Ok(Self::Network(ip.into()));
+    }
+    let host = s.strip_prefix("*.").unwrap_or(s);
+    let valid = !host.is_empty()
+      && (host.split('.'))
+        .all(|x| !x.is_empty() && x.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'));
+    if valid {
+      Ok(Self::Host(s.to_ascii_lowercase().into()))
+    } else {
+      Err(format!("invalid egress rule '{s}'"))
+    }
+  }
+}
+
+impl TryFrom<String> for EgressRule {
+  type Error = String;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    s.parse()
+  }
+}
+
+impl Display for EgressRule {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Host(host) => f.write_str(host),
+      Self::Network(net) => write!(f, "{net}"),
+    }
+  }
+}
+
+impl From<EgressRule> for String {
+  fn from(rule: EgressRule) -> Self {
+    rule.to_string()
+  }
+}
+
+/// Server-wide restrictions on where services can send requests to.
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+  /// Destinations allowed; any if `None`.
+  pub allow: Option<Vec<EgressRule>>,
+  /// Whether to block addresses in non-public ranges, unless allowed by a
+  /// network in `allow`.
+  pub block_private: bool,
+}
+
+impl Default for EgressPolicy {
+  fn default() -> Self {
+    Self {
+      allow: None,
+      block_private: true,
+    }
+  }
+}
+
+impl EgressPolicy {
+  /// Whether `ip` is in a non-public range that the policy blocks.
+  fn blocks_private(&self, ip: IpAddr) -> bool {
+    let allowed = |rules: &[EgressRule]| rules.iter().any(|x| x.matches_ip(ip));
+    self.block_private
+      && PRIVATE_NETWORKS.iter().any(|x| x.contains(&ip))
+      && !self.allow.as_deref().is_some_and(allowed)
+  }
+}
+
+#[derive(Debug, Error)]
+#[error("egress to '{host}' is denied by {by} policy")]
+pub struct EgressDenied {
+  host: Box<str>,
+  by: &'static str,
+}
+
+impl EgressDenied {
+  fn new(host: impl ToString, by: &'static str) -> Self {
+    Self {
+      host: host.to_string().into(),
+      by,
+    }
+  }
+}
+
+/// Egress policy of a service, i.e. the server's narrowed down by rules the
+/// service declares in its config.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Egress {
+  pub(crate) server: Arc<EgressPolicy>,
+  pub(crate) service: Option<Arc<[EgressRule]>>,
+}
+
+impl Egress {
+  /// Checks the host of a URI before sending a request to it.
+  ///
+  /// If `resolved` is true, addresses that host names resolve to are checked
+  /// separately with [`check_addr`](Self::check_addr), so names that network
+  /// rules may allow are let through here. Otherwise, network rules only match
+  /// IP addresses in URIs.
+  pub(crate) fn check_host(&self, host: &str, resolved: bool) -> Result<(), EgressDenied> {
+    let host = normalize_host(host);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+      return self.check_addr(&host, ip);
+    }
+    let allowed = |rules: &[EgressRule]| {
+      (rules.iter()).any(|x| x.matches_host(&host) || resolved && matches!(x, EgressRule::Network(_)))
+    };
+    self.check_rules(&host, allowed)
+  }
+
+  /// Checks an address `host` resolves to, or is itself.
+  pub(crate) fn check_addr(&self, host: &str, ip: IpAddr) -> Result<(), EgressDenied> {
+    let host = normalize_host(host);
+    let ip = canonical_ip(ip);
+    if self.server.blocks_private(ip) {
+      return Err(EgressDenied::new(ip, "server"));
+    }
+    let allowed =
+      |rules: &[EgressRule]| (rules.iter()).any(|x| x.matches_host(&host) || x.matches_ip(ip));
+    self.check_rules(&host, allowed)
+  }
+
+  fn check_rules(
+    &self,
+    host: &str,
+    allowed: impl Fn(&[EgressRule]) -> bool,
+  ) -> Result<(), EgressDenied> {
+    if !self.server.allow.as_deref().is_none_or(&allowed) {
+      return Err(EgressDenied::new(host, "server"));
+    }
+    if !self.service.as_deref().is_none_or(&allowed) {
+      return Err(EgressDenied::new(host, "service"));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn rules(rules: &[&str]) -> Vec<EgressRule> {
+    rules.iter().map(|x| x.parse().unwrap()).collect()
+  }
+
+  fn egress(server: &[&str], service: Option<&[&str]>) -> Egress {
+    let server = EgressPolicy {
+      allow: (!server.is_empty()).then(|| rules(server)),
+      block_private: true,
+    };
+    Egress {
+      server: Arc::new(server),
+      service: service.map(|x| rules(x).into()),
+    }
+  }
+
+  #[test_case("example.com" => true)]
+  #[test_case("*.example.com" => true; "wildcard")]
+  #[test_case("10.0.0.0/8" => true)]
+  #[test_case("::1" => true)]
+  #[test_case("*" => false)]
+  #[test_case("foo..com" => false)]
+  #[test_case("foo/bar" => false)]
+  fn test_parse_rule(rule: &str) -> bool {
+    rule.parse::<EgressRule>().is_ok()
+  }
+
+  #[test_case(&[], None, "127.0.0.1" => false)]
+  #[test_case(&[], None, "[::ffff:169.254.169.254]" => false)]
+  #[test_case(&[], None, "example.com" => true)]
+  #[test_case(&["127.0.0.0/8"], None, "127.0.0.1" => true; "allowed private")]
+  #[test_case(&["*.example.com"], None, "api.example.com" => true)]
+  #[test_case(&["*.example.com"], None, "example.com" => false)]
+  #[test_case(&["*.example.com"], None, "evil-example.com" => false)]
+  #[test_case(&[], Some(&["API.example.com"]), "api.example.com" => true)]
+  #[test_case(&[], Some(&["api.example.com"]), "example.org" => false)]
+  #[test_case(&[], Some(&["127.0.0.1"]), "127.0.0.1" => false; "service cannot allow private")]
+  #[test_case(&["example.org"], Some(&["api.example.com"]), "api.example.com" => false)]
+  #[test_case(&["203.0.113.0/24"], None, "example.com" => true; "left to resolved addresses")]
+  #[test_case(&["example.org"], None, "example.com" => false; "no network rules")]
+  fn test_check_host(server: &[&str], service: Option<&[&str]>, host: &str) -> bool {
+    egress(server, service).check_host(host, true).is_ok()
+  }
+
+  #[test]
+  fn test_check_host_unresolved() {
+    let egress = egress(&["203.0.113.0/24"], None);
+    assert!(egress.check_host("example.com", false).is_err());
+    assert!(egress.check_host("203.0.113.1", false).is_ok());
+  }
+
+  #[test_case(&[], None, "example.com", "93.184.216.34" => true)]
+  #[test_case(&[], None, "example.com", "10.0.0.1" => false; "resolves to private")]
+  #[test_case(&[], None, "example.com", "224.0.0.251" => false; "multicast")]
+  #[test_case(&[], None, "example.com", "255.255.255.255" => false; "broadcast")]
+  #[test_case(&[], None, "example.com", "ff02::1" => false; "IPv6 multicast")]
+  #[test_case(&[], None, "example.com", "64:ff9b::a9fe:a9fe" => false; "NAT64 to private")]
+  #[test_case(&[], None, "example.com", "64:ff9b::5db8:d822" => true; "NAT64 to public")]
+  #[test_case(&[], None, "example.com", "2002:a00:1::" => false; "6to4 to private")]
+  #[test_case(&[], None, "example.com", "2002:5db8:d822::1" => true; "6to4 to public")]
+  #[test_case(&[], None, "example.com", "192.0.0.8" => false; "IETF protocol assignments")]
+  #[test_case(&[], None, "example.com", "198.18.0.1" => false; "benchmarking")]
+  #[test_case(&[], None, "example.com", "198.19.255.254" => false; "benchmarking upper half")]
+  #[test_case(&[], None, "example.com", "240.0.0.1" => false; "reserved")]
+  #[test_case(&[], None, "example.com", "fec0::1" => false; "IPv6 site local")]
+  #[test_case(&["example.com"], None, "example.com", "10.0.0.1" => false; "name does not unblock")]
+  #[test_case(&["10.0.0.0/8"], None, "example.com", "10.0.0.1" => true; "network unblocks")]
+  #[test_case(&["203.0.113.0/24"], None, "example.com", "203.0.113.1" => true)]
+  #[test_case(&["203.0.113.0/24"], None, "example.com", "198.51.100.1" => false)]
+  #[test_case(&[], Some(&["203.0.113.0/24"]), "example.com", "203.0.113.1" => true)]
+  #[test_case(&[], Some(&["203.0.113.0/24"]), "example.com", "198.51.100.1" => false)]
+  #[test_case(&[], Some(&["example.com"]), "example.com", "198.51.100.1" => true)]
+  fn test_check_addr(server: &[&str], service: Option<&[&str]>, host: &str, ip: &str) -> bool {
+    let ip = ip.parse().unwrap();
+    egress(server, service).check_addr(host, ip).is_ok()
+  }
+}
@@ -82,10 +82,51 @@ pub enum ErrorKind {
   #[strum(props(status = "409", error = "service is stopped"))]
   ServiceStopped { name: ServiceName },
 
+  #[error("service '{caller}' is not allowed to call service '{callee}'")]
+  #[strum(props(status = "403", error = "service call forbidden"))]
+  ServiceCallForbidden {
+    caller: ServiceName,
+    callee: ServiceName,
+  },
+
+  #[error("storage of service '{name}' is still in use")]
+  #[strum(props(status = "409", error = "service storage in use"))]
+  ServiceStorageInUse { name: ServiceName },
+
   #[error("service is dropped")]
   #[strum(props(status = "500", error = "service is dropped"))]
   ServiceDropped,
 
+  #[error("memory limit exceeded")]
+  #[strum(props(status = "500", error = "memory limit exceeded"))]
+  MemoryLimitExceeded,
+
+  #[error("method {got} not allowed; allowed methods: {allowed:?}")]
+  #[strum(props(status = "405", error = "method not allowed"))]
+  MethodNotAllowed {
+    got: Box<str>,
+    allowed: Vec<Box<str>>,
+  },
+
+  #[error("request body exceeds {limit} bytes")]
+  #[strum(props(status = "413", error = "payload too large"))]
+  PayloadTooLarge { limit: u64 },
+
+  #[error("invalid token: {reason}")]
+  #[strum(props(status = "401", error = "invalid token"))]
+  InvalidToken { reason: Box<str> },
+
+  #[error("invalid route '{route}': {reason}")]
+  #[strum(props(status = "400", error = "invalid route"))]
+  InvalidRoute { route: Box<str>, reason: Box<str> },
+
+  #[error("invalid schedule '{schedule}': {reason}")]
+  #[strum(props(status = "400", error = "invalid schedule"))]
+  InvalidSchedule {
+    schedule: Box<str>,
+    reason: Box<str>,
+  },
+
   #[error("entry '{entry}' not found")]
   #[strum(props(status = "404", error = "entry not found"))]
   EntryNotFound { entry: Box<str> },
@@ -0,0 +1,138 @@
+use crate::service::RunningService;
+use crate::task::Pool;
+use crate::ErrorKind::ServiceDropped;
+use dashmap::DashMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Number of events buffered for each topic before slow subscribers start
+/// missing them.
+const TOPIC_CAPACITY: usize = 256;
+
+pub(crate) type Event = Arc<serde_json::Value>;
+
+/// Topics published to and subscribed by services on this instance.
+///
+/// Events are delivered to every subscriber regardless of which worker it
+/// runs on.
+#[derive(Debug, Default)]
+pub(crate) struct EventBus(DashMap<Box<str>, broadcast::Sender<Event>>);
+
+impl EventBus {
+  /// Publishes an event, returning the number of subscribers it reached.
+  pub fn publish(&self, topic: &str, value: serde_json::Value) -> usize {
+    let sent = (self.0.get(topic)).and_then(|tx| tx.send(Arc::new(value)).ok());
+    match sent {
+      Some(count) => count,
+      None => {
+        // Every subscriber is gone; do not keep the topic around
+        self.0.remove_if(topic, |_, tx| tx.receiver_count() == 0);
+        0
+      }
+    }
+  }
+
+  fn subscribe(&self, topic: &str) -> broadcast::Receiver<Event> {
+    (self.0.entry(topic.into()))
+      .or_insert_with(|| broadcast::channel(TOPIC_CAPACITY).0)
+      .subscribe()
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+  topic: Box<str>,
+  /// Index of the handler in `internal.subscriptions`, starting from 1.
+  #[serde(skip)]
+  pub(crate) handler: usize,
+}
+
+impl Subscription {
+  pub fn new(topic: impl Into<Box<str>>) -> Self {
+    Self {
+      topic: topic.into(),
+      handler: 0,
+    }
+  }
+
+  pub fn topic(&self) -> &str {
+    &self.topic
+  }
+}
+
+/// Spawns event subscriptions of a running service onto the runtime pool.
+///
+/// Like jobs, subscriptions are torn down when the service's
+/// [`JobsHandle`](crate::schedule::JobsHandle) is cancelled.
+pub(crate) fn spawn_subscriptions(rt_pool: &Arc<Pool>, bus: &EventBus, service: RunningService) {
+  let (name, subscriptions, token) = match service.try_upgrade() {
+    Ok(guard) => (
+      guard.name.clone(),
+      guard.subscriptions.clone(),
+      guard.jobs_handle.token(),
+    ),
+    Err(_) => return,
+  };
+  for Subscription { topic, handler } in subscriptions {
+    let rt_pool = rt_pool.clone();
+    let service = service.clone();
+    let name = name.clone();
+    let token = token.clone();
+    let mut rx = bus.subscribe(&topic);
+
+    // Events are handled one at a time in the order they are published.
+    tokio::spawn(async move {
+      loop {
+        let event = tokio::select! {
+          _ = token.cancelled() => break,
+          x = rx.recv() => x,
+        };
+        let event = match event {
+          Ok(event) => event,
+          Err(RecvError::Lagged(n)) => {
+            warn!("service '{name}' missed {n} events on topic '{topic}'");
+            continue;
+          }
+          Err(RecvError::Closed) => break,
+        };
+        let service = service.clone();
+        let topic2 = topic.clone();
+        let result = rt_pool
+          .scope(move |rt| async move { rt.run_subscriber(service, handler, &topic2, event).await })
+          .await;
+        match result {
+          Ok(()) => {}
+          Err(error) if matches!(error.kind(), ServiceDropped) => break,
+          Err(error) => {
+            warn!("Lua error when delivering event on topic '{topic}' to service '{name}': {error}")
+          }
+        }
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[tokio::test]
+  async fn test_event_bus() {
+    let bus = EventBus::default();
+    assert_eq!(bus.publish("foo", json!(1)), 0);
+
+    let mut rx1 = bus.subscribe("foo");
+    let mut rx2 = bus.subscribe("foo");
+    assert_eq!(bus.publish("foo", json!({ "bar": true })), 2);
+    assert_eq!(bus.publish("baz", json!(1)), 0);
+    assert_eq!(*rx1.recv().await.unwrap(), json!({ "bar": true }));
+    assert_eq!(*rx2.recv().await.unwrap(), json!({ "bar": true }));
+
+    drop((rx1, rx2));
+    assert_eq!(bus.publish("foo", json!(1)), 0);
+    assert!(bus.0.is_empty());
+  }
+}
@@ -2,32 +2,51 @@ pub mod service;
 pub mod source;
 
 mod config;
+mod egress;
 mod error;
+mod event;
 mod lua;
 mod path;
+mod queue;
 mod runtime;
+mod schedule;
 mod task;
 
-pub use config::Config;
+pub use config::{Config, CpuTimeConfig, CpuTimeLimits};
+pub use egress::{EgressPolicy, EgressRule};
 pub use error::{Error, ErrorKind, Result};
+pub use event::Subscription;
+pub use lua::http::HttpClientOptions;
 pub use lua::require::{load_create_require, RemoteInterface};
 pub use mlua;
 pub use mlua::Error as LuaError;
 pub use path::normalize_path_str;
+pub use queue::DeadJob;
 pub use runtime::check_name;
-pub use service::{RunningService, RunningServiceGuard, ServiceImpl};
+pub use schedule::{Job, Schedule};
+pub use service::{
+  RunningService, RunningServiceGuard, ServiceImpl, DEFAULT_STORAGE_CLOSE_TIMEOUT,
+};
 
+use dashmap::DashMap;
+use event::EventBus;
 use hyper::{Body, Request, Response};
+use lua::http::HttpClient;
+use lua::kv::KvStore;
+use queue::JobQueue;
 use runtime::Runtime;
-use service::{ErrorPayload, Service, ServiceName, ServicePool, StoppedService};
+use service::{
+  get_job_queue, ErrorPayload, Service, ServiceName, ServicePool, Services, StoppedService,
+};
 use source::Source;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use task::Pool;
 use uuid::Uuid;
 
 pub struct Abel {
-  runtime_pool: Pool,
+  runtime_pool: Arc<Pool>,
   service_pool: ServicePool,
   state: Arc<AbelState>,
 }
@@ -36,12 +55,47 @@ pub struct Abel {
 pub struct AbelState {
   pub local_storage_path: PathBuf,
   pub remote: RemoteInterface,
+  pub default_cpu_time: Duration,
+  pub max_cpu_time: Duration,
+  pub default_memory_limit: usize,
+  pub max_memory_limit: usize,
+  pub default_sql_max_rows: usize,
+  pub max_sql_max_rows: usize,
+  pub max_body_size: Option<u64>,
+  pub storage_close_timeout: Duration,
+  pub(crate) kv_stores: DashMap<ServiceName, KvStore>,
+  pub(crate) job_queues: DashMap<ServiceName, JobQueue>,
+  pub(crate) services: Arc<Services>,
+  pub(crate) event_bus: EventBus,
+  pub(crate) http_client: HttpClient,
 }
 
 pub struct AbelOptions {
   pub runtime_pool_size: usize,
   pub local_storage_path: PathBuf,
   pub remote_cache_path: Option<PathBuf>,
+  /// CPU time budget for services that do not specify one.
+  pub default_cpu_time: Duration,
+  /// Upper bound of CPU time budget a service can request.
+  pub max_cpu_time: Duration,
+  /// Memory quota in bytes for services that do not specify one.
+  pub default_memory_limit: usize,
+  /// Upper bound of memory quota in bytes a service can request.
+  pub max_memory_limit: usize,
+  /// Maximum rows a SQL statement can read or write for services that do not
+  /// specify one.
+  pub default_sql_max_rows: usize,
+  /// Upper bound of `sql_max_rows` a service can request.
+  pub max_sql_max_rows: usize,
+  /// Upper bound of request body size in bytes, also applied to services and
+  /// routes that do not specify one.
+  pub max_body_size: Option<u64>,
+  /// Defaults of outbound HTTP requests made by services.
+  pub http_client: HttpClientOptions,
+  /// Where services are allowed to send requests to.
+  pub egress: EgressPolicy,
+  /// How long removing a service waits for its databases to be released.
+  pub storage_close_timeout: Duration,
 }
 
 impl Abel {
@@ -49,12 +103,25 @@ impl Abel {
     let state = Arc::new(AbelState {
       local_storage_path: options.local_storage_path,
       remote: RemoteInterface::new(options.remote_cache_path),
+      default_cpu_time: options.default_cpu_time.min(options.max_cpu_time),
+      max_cpu_time: options.max_cpu_time,
+      default_memory_limit: options.default_memory_limit.min(options.max_memory_limit),
+      max_memory_limit: options.max_memory_limit,
+      default_sql_max_rows: options.default_sql_max_rows.min(options.max_sql_max_rows),
+      max_sql_max_rows: options.max_sql_max_rows,
+      max_body_size: options.max_body_size,
+      storage_close_timeout: options.storage_close_timeout,
+      kv_stores: DashMap::new(),
+      job_queues: DashMap::new(),
+      services: Default::default(),
+      event_bus: Default::default(),
+      http_client: HttpClient::new(options.http_client, options.egress)?,
     });
     Ok(Self {
-      runtime_pool: Pool::new(options.runtime_pool_size, {
+      runtime_pool: Arc::new(Pool::new(options.runtime_pool_size, {
         let state = state.clone();
         move || Runtime::new(state.clone())
-      })?,
+      })?),
       service_pool: ServicePool::new(state.clone()),
       state,
     })
@@ -90,7 +157,7 @@ impl Abel {
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
-  ) -> Result<(RunningService, ServiceImpl)> {
+  ) -> Result<(RunningService, ServiceImpl, ErrorPayload)> {
     (self.service_pool)
       .hot_update(&self.runtime_pool, name.into(), uuid, source, config)
       .await
@@ -149,7 +216,22 @@ impl Abel {
     self.service_pool.start(&self.runtime_pool, name).await
   }
 
+  /// Lists jobs of a service that have run out of retries.
+  pub async fn list_dead_jobs(&self, name: &str) -> Result<Vec<DeadJob>> {
+    self.get_service(name)?;
+    get_job_queue(&self.state, name).dead_jobs().await
+  }
+
+  /// Removes jobs of a service that have run out of retries, returning how
+  /// many were removed.
+  pub async fn purge_dead_jobs(&self, name: &str) -> Result<usize> {
+    self.get_service(name)?;
+    get_job_queue(&self.state, name).purge_dead_jobs().await
+  }
+
   pub async fn remove_service(&self, name: &str) -> Result<ServiceImpl> {
-    self.service_pool.remove(&self.state, name).await
+    (self.service_pool)
+      .remove(&self.runtime_pool, &self.state, name)
+      .await
   }
 }
@@ -85,6 +85,28 @@ fn create_fn_handle_http_error(lua: &Lua) -> mlua::Result<Function> {
   })
 }
 
+/// Creates an HTTP error, as if `error` was called with a table of `status`,
+/// `error` and `detail`.
+pub fn http_error(
+  lua: &Lua,
+  status: StatusCode,
+  error: &str,
+  detail: serde_json::Value,
+) -> mlua::Result<mlua::Error> {
+  let source = lua.create_table_from([
+    ("status", lua.pack(status.as_u16())?),
+    ("error", lua.pack(error)?),
+    ("detail", lua.to_value(&detail)?),
+  ])?;
+  let result = CustomError {
+    status,
+    error: error.into(),
+    detail,
+    source: Some(lua.create_registry_value(source)?),
+  };
+  Ok(result.to_lua_err())
+}
+
 fn create_fn_pcall(lua: &Lua) -> mlua::Result<Function> {
   lua.create_async_function(|lua, args: MultiValue| async move {
     let (success, value): (bool, mlua::Value) = lua
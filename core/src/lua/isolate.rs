@@ -2,13 +2,16 @@ use super::require::{load_create_require, RemoteInterface};
 use super::LuaCacheExt;
 use crate::lua::LuaTableExt;
 use crate::source::{Source, SourceUserData};
-use mlua::{Function, Lua, RegistryKey, Table, TableExt};
+use crate::task::IsolateMemory;
+use mlua::{ChunkMode, Function, Lua, RegistryKey, Table, TableExt};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Isolate {
   pub(crate) source: Source,
   pub(crate) local_env: RegistryKey,
   pub(crate) internal: RegistryKey,
+  pub(crate) memory: Arc<IsolateMemory>,
 }
 
 pub struct IsolateBuilder<'lua> {
@@ -50,15 +53,22 @@ impl<'lua> IsolateBuilder<'lua> {
     Ok(self)
   }
 
+  /// Adds a library written in Lua, running in the isolate's local env.
+  ///
+  /// Only the compiled chunk is cached, as a function bound to one isolate's
+  /// env would keep that isolate alive.
   pub fn add_lua_lib(self, name: &str, code: &str) -> mlua::Result<Self> {
     let key = format!("abel:lua_preload_{name}");
-    let preload = self.lua.create_cached_value(&key, || {
-      (self.lua)
-        .load(code)
-        .set_name(&key)?
-        .set_environment(self.local_env.clone())?
-        .into_function()
+    let bytecode: mlua::String = self.lua.create_cached_value(&key, || {
+      let f = self.lua.load(code).set_name(&key)?.into_function()?;
+      self.lua.create_string(&f.dump(false))
     })?;
+    let preload = (self.lua)
+      .load(bytecode.as_bytes())
+      .set_name(&key)?
+      .set_mode(ChunkMode::Binary)
+      .set_environment(self.local_env.clone())?
+      .into_function()?;
     self.preload.raw_set(name, preload)?;
     Ok(self)
   }
@@ -78,6 +88,7 @@ impl<'lua> IsolateBuilder<'lua> {
       source: self.source,
       local_env,
       internal,
+      memory: Default::default(),
     })
   }
 }
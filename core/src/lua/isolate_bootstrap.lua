@@ -3,6 +3,10 @@ local source, remote, create_require = ...
 local local_env = {}
 local internal = {
   paths = {},
+  middlewares = {},
+  jobs = {},
+  subscriptions = {},
+  workers = {},
   sealed = false,
 }
 
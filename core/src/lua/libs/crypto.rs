@@ -1,26 +1,113 @@
-use crate::lua::error::{check_string, check_userdata_mut, rt_error, tag_handler};
+use crate::lua::error::{
+  arg_error, check_string, check_userdata, check_userdata_mut, rt_error, tag_handler,
+};
 use crate::lua::LuaCacheExt;
-use data_encoding::HEXLOWER;
-use digest::Digest;
-use mlua::{Function, Lua, MultiValue, UserData};
+use crate::task::{TaskContext, TimeoutError};
+use aes_gcm::aead::{Aead, Nonce, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params};
+use chacha20poly1305::ChaCha20Poly1305;
+use data_encoding::{BASE64, BASE64URL_NOPAD, HEXLOWER};
+use digest::core_api::BlockSizeUser;
+use digest::typenum::Unsigned;
+use digest::{Digest, FixedOutput, KeyInit, Update};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::SimpleHmac;
+use mlua::Value::Nil;
+use mlua::{AnyUserData, ExternalError, Function, Lua, MultiValue, UserData};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use sha2::{Sha224, Sha256, Sha384, Sha512, Sha512_224, Sha512_256};
+use std::sync::Arc;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
 
-pub fn create_preload_crypto(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_cached_function("abel:preload_crypto", |lua, ()| {
-    let crypto_table = lua.create_table()?;
-    crypto_table.raw_set("Sha224", create_digest_interface::<Sha224>(lua)?)?;
-    crypto_table.raw_set("Sha256", create_digest_interface::<Sha256>(lua)?)?;
-    crypto_table.raw_set("Sha384", create_digest_interface::<Sha384>(lua)?)?;
-    crypto_table.raw_set("Sha512", create_digest_interface::<Sha512>(lua)?)?;
-    crypto_table.raw_set("Sha512_224", create_digest_interface::<Sha512_224>(lua)?)?;
-    crypto_table.raw_set("Sha512_256", create_digest_interface::<Sha512_256>(lua)?)?;
-    Ok(crypto_table)
-  })
+pub fn create_preload_crypto(
+  argon2_permits: Argon2Permits,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    let preload =
+      lua.create_cached_function("abel:preload_crypto", |lua, permits: AnyUserData| {
+        let argon2_permits = permits.borrow::<Argon2Permits>()?.clone();
+        let crypto_table = lua.create_table()?;
+        crypto_table.raw_set("Sha224", create_digest_interface::<Sha224>(lua)?)?;
+        crypto_table.raw_set("Sha256", create_digest_interface::<Sha256>(lua)?)?;
+        crypto_table.raw_set("Sha384", create_digest_interface::<Sha384>(lua)?)?;
+        crypto_table.raw_set("Sha512", create_digest_interface::<Sha512>(lua)?)?;
+        crypto_table.raw_set("Sha512_224", create_digest_interface::<Sha512_224>(lua)?)?;
+        crypto_table.raw_set("Sha512_256", create_digest_interface::<Sha512_256>(lua)?)?;
+        crypto_table.raw_set("Hmac", create_fn_hmac(lua)?)?;
+        crypto_table.raw_set("constant_time_eq", create_fn_constant_time_eq(lua)?)?;
+        crypto_table.raw_set("Aes128Gcm", create_aead_interface::<Aes128Gcm>(lua)?)?;
+        crypto_table.raw_set("Aes256Gcm", create_aead_interface::<Aes256Gcm>(lua)?)?;
+        crypto_table.raw_set(
+          "ChaCha20Poly1305",
+          create_aead_interface::<ChaCha20Poly1305>(lua)?,
+        )?;
+        crypto_table.raw_set("ed25519_keygen", create_fn_ed25519_keygen(lua)?)?;
+        crypto_table.raw_set("ed25519_sign", create_fn_ed25519_sign(lua)?)?;
+        crypto_table.raw_set("ed25519_verify", create_fn_ed25519_verify(lua)?)?;
+        crypto_table.raw_set(
+          "hash_password",
+          create_fn_hash_password(lua, argon2_permits.clone())?,
+        )?;
+        crypto_table.raw_set(
+          "verify_password",
+          create_fn_verify_password(lua, argon2_permits)?,
+        )?;
+        Ok(crypto_table)
+      })?;
+    preload.bind(argon2_permits)
+  }
+}
+
+/// Encoding of hasher output, hex by default.
+#[derive(Clone, Copy)]
+enum OutputFormat {
+  Raw,
+  Hex,
+  Base64,
+  /// URL-safe Base64 without padding.
+  Base64Url,
+}
+
+impl OutputFormat {
+  fn check(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<Self> {
+    let format = match value {
+      None | Some(Nil) => return Ok(Self::Hex),
+      value => check_string(lua, value).map_err(tag_handler(lua, pos, 0))?,
+    };
+    match format.as_bytes() {
+      b"raw" => Ok(Self::Raw),
+      b"hex" => Ok(Self::Hex),
+      b"base64" => Ok(Self::Base64),
+      b"base64url" => Ok(Self::Base64Url),
+      _ => Err(arg_error(
+        lua,
+        pos,
+        "output format must be one of 'raw', 'hex', 'base64' or 'base64url'",
+        0,
+      )),
+    }
+  }
+
+  fn encode<'lua>(self, lua: &'lua Lua, bytes: &[u8]) -> mlua::Result<mlua::String<'lua>> {
+    match self {
+      Self::Raw => lua.create_string(bytes),
+      Self::Hex => lua.create_string(&HEXLOWER.encode(bytes)),
+      Self::Base64 => lua.create_string(&BASE64.encode(bytes)),
+      Self::Base64Url => lua.create_string(&BASE64URL_NOPAD.encode(bytes)),
+    }
+  }
 }
 
-struct LuaHasher<H: Digest + 'static>(Option<H>);
+struct LuaHasher<H: Update + FixedOutput + 'static>(Option<H>);
 
-impl<H: Digest + 'static> UserData for LuaHasher<H> {
+impl<H: Update + FixedOutput + 'static> UserData for LuaHasher<H> {
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
     methods.add_function("write", |lua, mut args: MultiValue| {
       let mut this =
@@ -34,13 +121,12 @@ impl<H: Digest + 'static> UserData for LuaHasher<H> {
       }
     });
 
-    // TODO: output format
     methods.add_function("finalize", |lua, mut args: MultiValue| {
       let mut this =
         check_userdata_mut::<Self>(args.pop_front(), "hasher").map_err(tag_handler(lua, 1, 0))?;
+      let format = OutputFormat::check(lua, args.pop_front(), 2)?;
       if let Some(inner) = this.with_borrowed_mut(|x| &mut x.0).take() {
-        let out = inner.finalize();
-        lua.create_string(&HEXLOWER.encode(&out))
+        format.encode(lua, &inner.finalize_fixed())
       } else {
         Err(rt_error("attempt to finalize a hasher after finalizing"))
       }
@@ -48,15 +134,303 @@ impl<H: Digest + 'static> UserData for LuaHasher<H> {
   }
 }
 
-fn create_digest_interface<H: Digest + 'static>(lua: &Lua) -> mlua::Result<Function> {
+/// Returns a hasher if no data is given, or the output of hashing the data
+/// at `pos` in the format after it.
+fn hash_or_hasher<'lua, H: Update + FixedOutput + 'static>(
+  lua: &'lua Lua,
+  mut hasher: H,
+  mut args: MultiValue<'lua>,
+  pos: usize,
+) -> mlua::Result<mlua::Value<'lua>> {
+  if args.is_empty() {
+    lua.pack(LuaHasher(Some(hasher)))
+  } else {
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, pos, 0))?;
+    let format = OutputFormat::check(lua, args.pop_front(), pos + 1)?;
+    hasher.update(data.as_bytes());
+    let out = format.encode(lua, &hasher.finalize_fixed())?;
+    Ok(mlua::Value::String(out))
+  }
+}
+
+fn create_digest_interface<H>(lua: &Lua) -> mlua::Result<Function>
+where
+  H: Digest + Update + FixedOutput + 'static,
+{
+  lua.create_function(|lua, args: MultiValue| hash_or_hasher(lua, <H as Digest>::new(), args, 1))
+}
+
+/// `crypto.Hmac(algo, key[, data[, format]])`
+fn create_fn_hmac(lua: &Lua) -> mlua::Result<Function> {
+  fn hmac<'lua, H: Digest + BlockSizeUser + 'static>(
+    lua: &'lua Lua,
+    key: &[u8],
+    args: MultiValue<'lua>,
+  ) -> mlua::Result<mlua::Value<'lua>> {
+    let mac = SimpleHmac::<H>::new_from_slice(key).expect("HMAC takes keys of any length");
+    hash_or_hasher(lua, mac, args, 3)
+  }
+
+  lua.create_function(|lua, mut args: MultiValue| {
+    let algo = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let key = key.as_bytes();
+    match algo.as_bytes() {
+      b"sha224" => hmac::<Sha224>(lua, key, args),
+      b"sha256" => hmac::<Sha256>(lua, key, args),
+      b"sha384" => hmac::<Sha384>(lua, key, args),
+      b"sha512" => hmac::<Sha512>(lua, key, args),
+      b"sha512_224" => hmac::<Sha512_224>(lua, key, args),
+      b"sha512_256" => hmac::<Sha512_256>(lua, key, args),
+      _ => Err(arg_error(
+        lua,
+        1,
+        &format!("unknown hash algorithm '{}'", algo.to_string_lossy()),
+        0,
+      )),
+    }
+  })
+}
+
+/// Compares two strings in time independent of their contents, e.g. for
+/// checking signatures. Only their lengths are not kept secret.
+fn create_fn_constant_time_eq(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let a = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let b = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    Ok(bool::from(a.as_bytes().ct_eq(b.as_bytes())))
+  })
+}
+
+/// AEAD cipher with a fixed key.
+///
+/// `seal` prepends a random nonce to the ciphertext, which `open` then takes
+/// from the start of its input.
+struct LuaAead<C>(C);
+
+impl<C: Aead + 'static> UserData for LuaAead<C> {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_function("seal", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "cipher").map_err(tag_handler(lua, 1, 0))?;
+      let msg = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+      let aad = check_aad(lua, args.pop_front(), 3)?;
+      let mut nonce = Nonce::<C>::default();
+      OsRng.fill_bytes(&mut nonce);
+      let payload = Payload {
+        msg: msg.as_bytes(),
+        aad: aad.as_ref().map_or(b"", |x| x.as_bytes()),
+      };
+      let ciphertext = (this.with_borrowed(|x| x.0.encrypt(&nonce, payload)))
+        .map_err(|_| rt_error("encryption failed"))?;
+      lua.create_string(&[&nonce[..], &ciphertext].concat())
+    });
+
+    methods.add_function("open", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "cipher").map_err(tag_handler(lua, 1, 0))?;
+      let sealed = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+      let aad = check_aad(lua, args.pop_front(), 3)?;
+      let sealed = sealed.as_bytes();
+      let nonce_size = C::NonceSize::USIZE;
+      if sealed.len() < nonce_size {
+        return lua.pack_multi((Nil, "authentication failed"));
+      }
+      let payload = Payload {
+        msg: &sealed[nonce_size..],
+        aad: aad.as_ref().map_or(b"", |x| x.as_bytes()),
+      };
+      let nonce = Nonce::<C>::from_slice(&sealed[..nonce_size]);
+      match this.with_borrowed(|x| x.0.decrypt(nonce, payload)) {
+        Ok(plaintext) => lua.pack_multi(lua.create_string(&plaintext)?),
+        Err(_) => lua.pack_multi((Nil, "authentication failed")),
+      }
+    });
+  }
+}
+
+fn check_aad<'lua>(
+  lua: &'lua Lua,
+  value: Option<mlua::Value<'lua>>,
+  pos: usize,
+) -> mlua::Result<Option<mlua::String<'lua>>> {
+  match value {
+    None | Some(Nil) => Ok(None),
+    value => Ok(Some(
+      check_string(lua, value).map_err(tag_handler(lua, pos, 0))?,
+    )),
+  }
+}
+
+/// `crypto.Aes256Gcm(key)` etc.
+fn create_aead_interface<C: Aead + KeyInit + 'static>(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let cipher = C::new_from_slice(key.as_bytes())
+      .map_err(|_| arg_error(lua, 1, &format!("key must be {} bytes", C::key_size()), 0))?;
+    Ok(LuaAead(cipher))
+  })
+}
+
+/// Returns a random secret key and its public key, both 32 bytes.
+fn create_fn_ed25519_keygen(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, ()| {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    let key = SigningKey::from_bytes(&secret);
+    let public = key.verifying_key();
+    Ok((
+      lua.create_string(&secret)?,
+      lua.create_string(public.as_bytes())?,
+    ))
+  })
+}
+
+fn check_key<'lua, const N: usize>(
+  lua: &'lua Lua,
+  value: Option<mlua::Value<'lua>>,
+  pos: usize,
+  name: &str,
+) -> mlua::Result<[u8; N]> {
+  let bytes = check_string(lua, value).map_err(tag_handler(lua, pos, 0))?;
+  (bytes.as_bytes().try_into())
+    .map_err(|_| arg_error(lua, pos, &format!("{name} must be {N} bytes"), 0))
+}
+
+fn create_fn_ed25519_sign(lua: &Lua) -> mlua::Result<Function> {
   lua.create_function(|lua, mut args: MultiValue| {
-    if args.is_empty() {
-      lua.pack(LuaHasher(Some(H::new())))
-    } else {
-      let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
-      let out = H::digest(data);
-      let out = lua.create_string(&HEXLOWER.encode(&out))?;
-      Ok(mlua::Value::String(out))
+    let secret = check_key::<32>(lua, args.pop_front(), 1, "secret key")?;
+    let msg = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let signature = SigningKey::from_bytes(&secret).sign(msg.as_bytes());
+    lua.create_string(&signature.to_bytes())
+  })
+}
+
+fn create_fn_ed25519_verify(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let public = check_key::<32>(lua, args.pop_front(), 1, "public key")?;
+    let msg = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let signature = check_key::<64>(lua, args.pop_front(), 3, "signature")?;
+    let public =
+      VerifyingKey::from_bytes(&public).map_err(|_| arg_error(lua, 1, "invalid public key", 0))?;
+    let signature = Signature::from_bytes(&signature);
+    Ok(public.verify_strict(msg.as_bytes(), &signature).is_ok())
+  })
+}
+
+/// Number of Argon2 hashes computed at once across all services.
+///
+/// Hashes never cost more than the default parameters, with which each of them
+/// takes 19 MiB of memory outside of the service's Lua memory quota, and tens
+/// of milliseconds of CPU time.
+const MAX_CONCURRENT_ARGON2: usize = 4;
+
+/// Number of Argon2 hashes computed at once by a single service, so that it
+/// cannot take every permit of [`ARGON2_PERMITS`] from the others.
+const MAX_CONCURRENT_ARGON2_PER_SERVICE: usize = 1;
+
+// Tokio's semaphores are fair, so services waiting for a permit get it in turn.
+static ARGON2_PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(MAX_CONCURRENT_ARGON2));
+
+/// A service's share of [`ARGON2_PERMITS`].
+#[derive(Debug, Clone)]
+pub struct Argon2Permits(Arc<Semaphore>);
+
+impl Default for Argon2Permits {
+  fn default() -> Self {
+    Self(Arc::new(Semaphore::new(MAX_CONCURRENT_ARGON2_PER_SERVICE)))
+  }
+}
+
+impl Argon2Permits {
+  #[cfg(test)]
+  pub(crate) fn available(&self) -> usize {
+    self.0.available_permits()
+  }
+}
+
+impl UserData for Argon2Permits {}
+
+/// Runs Argon2 on the blocking thread pool, as it is deliberately slow.
+///
+/// The time it takes is charged to the current task's CPU time budget, which
+/// would otherwise not see it. Permits and the charge go with the blocking job,
+/// which keeps running if the task is cancelled.
+async fn run_argon2<T: Send + 'static>(
+  lua: &Lua,
+  permits: &Argon2Permits,
+  f: impl FnOnce() -> T + Send + 'static,
+) -> mlua::Result<T> {
+  let service_permit = permits.0.clone().acquire_owned().await.map_err(rt_error)?;
+  let permit = ARGON2_PERMITS.acquire().await.map_err(rt_error)?;
+  let cpu_time = TaskContext::get_current(lua).map(|x| x.cpu_time.clone());
+  let result = spawn_blocking({
+    let cpu_time = cpu_time.clone();
+    move || {
+      let _permits = (service_permit, permit);
+      let t = Instant::now();
+      let result = f();
+      if let Some(cpu_time) = cpu_time {
+        cpu_time.lock().used += t.elapsed();
+      }
+      result
+    }
+  })
+  .await
+  .map_err(rt_error)?;
+  if cpu_time.is_some_and(|x| x.lock().is_exceeded()) {
+    return Err(TimeoutError(()).to_lua_err());
+  }
+  Ok(result)
+}
+
+/// Hashes a password with Argon2id, returning the hash in PHC string format.
+///
+/// See [`run_argon2`] for its cost.
+fn create_fn_hash_password(lua: &Lua, permits: Argon2Permits) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let permits = permits.clone();
+    async move {
+      let password = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      let password = password.as_bytes().to_vec();
+      let hash = run_argon2(lua, &permits, move || {
+        let salt = SaltString::generate(&mut OsRng);
+        (Argon2::default().hash_password(&password, &salt)).map(|x| x.to_string())
+      })
+      .await?
+      .map_err(rt_error)?;
+      Ok(hash)
+    }
+  })
+}
+
+/// Checks a password against a hash from `crypto.hash_password`.
+///
+/// Hashes costing more than the default parameters are rejected, as their
+/// cost is chosen by whoever made them.
+fn create_fn_verify_password(lua: &Lua, permits: Argon2Permits) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let permits = permits.clone();
+    async move {
+      let password = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      let hash = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+      let password = password.as_bytes().to_vec();
+      let hash = hash.to_str().ok().and_then(|x| PasswordHash::new(x).ok());
+      let params = (hash.as_ref())
+        .and_then(|x| Params::try_from(x).ok())
+        .ok_or_else(|| arg_error(lua, 2, "invalid password hash", 0))?;
+      if params.m_cost() > Params::DEFAULT_M_COST
+        || params.t_cost() > Params::DEFAULT_T_COST
+        || params.p_cost() > Params::DEFAULT_P_COST
+      {
+        return Err(arg_error(lua, 2, "password hash too costly", 0));
+      }
+      let hash = hash.unwrap().to_string();
+      run_argon2(lua, &permits, move || {
+        let hash = PasswordHash::new(&hash).unwrap();
+        Argon2::default().verify_password(&password, &hash).is_ok()
+      })
+      .await
     }
   })
 }
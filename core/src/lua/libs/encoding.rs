@@ -0,0 +1,154 @@
+use crate::lua::error::{check_string, check_userdata_mut, rt_error, rt_error_fmt, tag_handler};
+use crate::lua::LuaCacheExt;
+use data_encoding::{
+  Encoding, BASE32, BASE32_NOPAD, BASE64, BASE64URL, BASE64URL_NOPAD, BASE64_NOPAD,
+  HEXLOWER_PERMISSIVE,
+};
+use mlua::{Function, Lua, MultiValue, Table, UserData};
+use percent_encoding::{percent_decode, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::borrow::Cow;
+
+/// Characters not percent-encoded, i.e. unreserved ones in RFC 3986.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~');
+
+pub fn create_preload_encoding(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_encoding", |lua, ()| {
+    let encoding_table = lua.create_table()?;
+    for codec in [
+      Codec::Data("base64", BASE64),
+      Codec::Data("base64_nopad", BASE64_NOPAD),
+      Codec::Data("base64url", BASE64URL),
+      Codec::Data("base64url_nopad", BASE64URL_NOPAD),
+      Codec::Data("base32", BASE32),
+      Codec::Data("base32_nopad", BASE32_NOPAD),
+      Codec::Data("hex", HEXLOWER_PERMISSIVE),
+      Codec::Percent,
+    ] {
+      encoding_table.raw_set(codec.name(), create_codec_table(lua, codec)?)?;
+    }
+    Ok(encoding_table)
+  })
+}
+
+#[derive(Clone)]
+enum Codec {
+  Data(&'static str, Encoding),
+  Percent,
+}
+
+impl Codec {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Data(name, _) => name,
+      Self::Percent => "percent",
+    }
+  }
+
+  /// Number of input bytes encoded independently of those around them.
+  fn block_size(&self) -> usize {
+    match self {
+      // lcm(8, bits) / 8
+      Self::Data(_, encoding) => encoding.bit_width() / gcd(8, encoding.bit_width()),
+      Self::Percent => 1,
+    }
+  }
+
+  fn encode(&self, input: &[u8]) -> String {
+    match self {
+      Self::Data(_, encoding) => encoding.encode(input),
+      Self::Percent => percent_encode(input, UNRESERVED).to_string(),
+    }
+  }
+
+  fn decode<'a>(&self, input: &'a [u8]) -> mlua::Result<Cow<'a, [u8]>> {
+    match self {
+      Self::Data(name, encoding) => (encoding.decode(input))
+        .map(Cow::Owned)
+        .map_err(|error| rt_error_fmt!("invalid {name}: {error}")),
+      Self::Percent => Ok(percent_decode(input).into()),
+    }
+  }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+  if b == 0 {
+    a
+  } else {
+    gcd(b, a % b)
+  }
+}
+
+/// `encoding.<codec>.encode`, `encoding.<codec>.decode` and
+/// `encoding.<codec>.Encoder`
+fn create_codec_table(lua: &Lua, codec: Codec) -> mlua::Result<Table> {
+  let encode = lua.create_function({
+    let codec = codec.clone();
+    move |lua, mut args: MultiValue| {
+      let input = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      Ok(codec.encode(input.as_bytes()))
+    }
+  })?;
+  let decode = lua.create_function({
+    let codec = codec.clone();
+    move |lua, mut args: MultiValue| {
+      let input = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      lua.create_string(&codec.decode(input.as_bytes())?)
+    }
+  })?;
+  let encoder = lua.create_function(move |_lua, ()| {
+    Ok(LuaEncoder {
+      codec: codec.clone(),
+      buf: Vec::new(),
+      finished: false,
+    })
+  })?;
+  lua.create_table_from([("encode", encode), ("decode", decode), ("Encoder", encoder)])
+}
+
+/// Stream transform encoding bytes as they come.
+///
+/// Input that does not fill a whole block is kept until the next item, or
+/// until `finish` is called (e.g. by `stream.pipe_through`) to encode the rest
+/// with padding.
+struct LuaEncoder {
+  codec: Codec,
+  buf: Vec<u8>,
+  finished: bool,
+}
+
+impl UserData for LuaEncoder {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_function("transform", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "encoder").map_err(tag_handler(lua, 1, 0))?;
+      let item = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+      this.with_borrowed_mut(|this| {
+        if this.finished {
+          return Err(rt_error("attempt to write to a finished encoder"));
+        }
+        this.buf.extend_from_slice(item.as_bytes());
+        let len = this.buf.len() / this.codec.block_size() * this.codec.block_size();
+        let output = this.codec.encode(&this.buf[..len]);
+        this.buf.drain(..len);
+        Ok(output)
+      })
+    });
+
+    methods.add_function("finish", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "encoder").map_err(tag_handler(lua, 1, 0))?;
+      this.with_borrowed_mut(|this| {
+        if this.finished {
+          return Err(rt_error("attempt to finish a finished encoder"));
+        }
+        this.finished = true;
+        let rest = std::mem::take(&mut this.buf);
+        Ok(this.codec.encode(&rest))
+      })
+    });
+  }
+}
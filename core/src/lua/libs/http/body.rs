@@ -33,6 +33,7 @@ impl LuaBody {
       status,
       headers: Rc::new(RefCell::new(headers)),
       body: Some(self),
+      ..Default::default()
     }
   }
 
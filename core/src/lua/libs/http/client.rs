@@ -0,0 +1,799 @@
+use super::body::LuaBody;
+use super::{LuaRequest, LuaResponse};
+use crate::egress::{Egress, EgressPolicy, EgressRule};
+use crate::lua::error::{rt_error, rt_error_fmt, TableCheckExt};
+use data_encoding::BASE64;
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, StreamExt};
+use hyper::body::Bytes;
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::header::{
+  HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION,
+  TRANSFER_ENCODING,
+};
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use hyper_tls::MaybeHttpsStream;
+use mlua::{Lua, Table};
+use std::cell::RefCell;
+use std::error::Error as StdError;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{timeout_at, Instant};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Number of redirects followed before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// Upper bound of a proxy's response to `CONNECT`.
+const MAX_TUNNEL_RESPONSE: usize = 8192;
+
+/// Server-wide defaults of outbound HTTP requests made by services.
+#[derive(Debug, Clone)]
+pub struct HttpClientOptions {
+  /// Time limit of a request, including reading its response body.
+  pub timeout: Option<Duration>,
+  pub follow_redirects: bool,
+  /// Maximum size of response body in bytes.
+  pub max_body_size: Option<usize>,
+  /// HTTP proxy through which every request is sent, optionally with
+  /// credentials in its user info.
+  ///
+  /// Since hosts are then resolved by the proxy, only their names are checked
+  /// against the egress policy. Private addresses cannot be blocked this way,
+  /// so a proxy is rejected unless `block_private` of the policy is off.
+  pub proxy: Option<Uri>,
+}
+
+impl Default for HttpClientOptions {
+  fn default() -> Self {
+    Self {
+      timeout: Some(Duration::from_secs(30)),
+      follow_redirects: true,
+      max_body_size: None,
+      proxy: None,
+    }
+  }
+}
+
+/// Options of a single request, overriding those of the client.
+#[derive(Debug, Default)]
+pub(crate) struct RequestOptions {
+  timeout: Option<Duration>,
+  follow_redirects: Option<bool>,
+  max_body_size: Option<usize>,
+}
+
+impl RequestOptions {
+  pub(crate) fn from_table<'lua>(lua: &'lua Lua, table: &Table<'lua>) -> mlua::Result<Self> {
+    Ok(Self {
+      timeout: (table.check_raw_get::<Option<u64>>(lua, "timeout", "integer")?)
+        .map(Duration::from_millis),
+      follow_redirects: table.check_raw_get(lua, "follow_redirects", "boolean")?,
+      max_body_size: table.check_raw_get(lua, "max_body_size", "integer")?,
+    })
+  }
+}
+
+/// Client used by `http.request`.
+///
+/// Clones share the same connection pool.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+  client: Client<Connector>,
+  connector: Connector,
+  options: Arc<HttpClientOptions>,
+  proxy: Option<Arc<Proxy>>,
+}
+
+impl HttpClient {
+  pub fn new(options: HttpClientOptions, egress: EgressPolicy) -> io::Result<Self> {
+    let proxy = (options.proxy.as_ref())
+      .map(|uri| {
+        if uri.scheme() != Some(&Scheme::HTTP) || uri.host().is_none() {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid proxy '{uri}': only HTTP proxies are supported"),
+          ));
+        }
+        if egress.block_private {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "private addresses cannot be blocked behind a proxy; disable blocking them to use one",
+          ));
+        }
+        let auth = (uri.authority())
+          .and_then(|x| x.as_str().rsplit_once('@'))
+          .map(|(user_info, _)| -> io::Result<_> {
+            let mut value =
+              HeaderValue::try_from(format!("Basic {}", BASE64.encode(user_info.as_bytes())))
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+            value.set_sensitive(true);
+            Ok(value)
+          })
+          .transpose()?;
+        Ok(Proxy {
+          uri: uri.clone(),
+          auth,
+        })
+      })
+      .transpose()?;
+
+    let proxy = proxy.map(Arc::new);
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let tls = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+    let connector = Connector {
+      http,
+      tls: tls.into(),
+      proxy: proxy.clone(),
+      egress: Egress {
+        server: Arc::new(egress),
+        service: None,
+      },
+    };
+    Ok(Self {
+      client: Client::builder().build(connector.clone()),
+      connector,
+      options: Arc::new(options),
+      proxy,
+    })
+  }
+
+  /// Creates a client for a service that further restricts egress to `rules`.
+  ///
+  /// Connections are checked against the rules when opened, so the client has
+  /// its own connection pool if there are any. Otherwise it shares that of the
+  /// original client.
+  pub(crate) fn with_egress_rules(&self, rules: Option<&[EgressRule]>) -> Self {
+    let Some(rules) = rules else {
+      return self.clone();
+    };
+    let mut connector = self.connector.clone();
+    connector.egress.service = Some(rules.into());
+    Self {
+      client: Client::builder().build(connector.clone()),
+      connector,
+      ..self.clone()
+    }
+  }
+
+  pub(crate) async fn request(
+    &self,
+    req: LuaRequest,
+    options: RequestOptions,
+  ) -> mlua::Result<LuaResponse> {
+    let timeout = options.timeout.or(self.options.timeout);
+    let deadline = timeout.map(|x| Instant::now() + x);
+    let follow_redirects = (options.follow_redirects).unwrap_or(self.options.follow_redirects);
+    let max_body_size = options.max_body_size.or(self.options.max_body_size);
+    let timed_out = || rt_error_fmt!("request timed out after {}ms", timeout.unwrap().as_millis());
+
+    let send = self.send(req, follow_redirects);
+    let (uri, resp) = match deadline {
+      Some(deadline) => timeout_at(deadline, send)
+        .await
+        .map_err(|_| timed_out())??,
+      None => send.await?,
+    };
+
+    if let Some(max) = max_body_size {
+      let len =
+        (resp.headers().get(CONTENT_LENGTH)).and_then(|x| x.to_str().ok()?.parse::<u64>().ok());
+      if len.is_some_and(|len| len > max as u64) {
+        return Err(rt_error_fmt!("response body exceeds {max} bytes"));
+      }
+    }
+    let resp = if deadline.is_some() || max_body_size.is_some() {
+      let timeout = timeout.map(|x| x.as_millis());
+      resp.map(|body| limit_body(body, deadline.zip(timeout), max_body_size))
+    } else {
+      resp
+    };
+
+    let mut resp = LuaResponse::from_hyper(resp);
+    resp.uri = Some(uri);
+    Ok(resp)
+  }
+
+  /// Checks whether the egress policy allows sending requests to `uri`.
+  pub(crate) fn check_egress(&self, uri: &Uri) -> mlua::Result<()> {
+    let host = uri.host().ok_or_else(|| rt_error("missing host in URI"))?;
+    // Hosts are resolved by the proxy if there is one
+    (self.connector.egress)
+      .check_host(host, self.proxy.is_none())
+      .map_err(rt_error)
+  }
+
+  /// Sends a `GET` request with the client's default options.
+  pub(crate) async fn get(&self, uri: Uri) -> mlua::Result<Response<Body>> {
+    let req = LuaRequest {
+      uri,
+      ..Default::default()
+    };
+    let resp = self.request(req, RequestOptions::default()).await?;
+    Ok(resp.into())
+  }
+
+  /// Sends the request, following redirects if asked to.
+  ///
+  /// Returns the response along with its URI.
+  async fn send(
+    &self,
+    req: LuaRequest,
+    follow_redirects: bool,
+  ) -> mlua::Result<(Uri, Response<Body>)> {
+    let LuaRequest {
+      mut method,
+      mut uri,
+      headers,
+      body,
+      ..
+    } = req;
+    let mut headers = Rc::try_unwrap(headers)
+      .map(RefCell::into_inner)
+      .unwrap_or_else(|x| x.borrow().clone());
+    // Streamed bodies can only be sent once, so redirects that need to resend
+    // them are not followed.
+    let (mut replay, mut stream) = match body.unwrap_or(LuaBody::Empty) {
+      LuaBody::Stream(x) => (None, Some(x)),
+      x => (
+        Some(
+          hyper::body::to_bytes(Body::from(x))
+            .await
+            .map_err(rt_error)?,
+        ),
+        None,
+      ),
+    };
+
+    let mut redirects = 0;
+    loop {
+      // Checked on every hop, so that redirects cannot bypass the policy
+      self.check_egress(&uri)?;
+      let body = match (&replay, stream.take()) {
+        (Some(bytes), _) => Body::from(bytes.clone()),
+        (None, Some(stream)) => stream,
+        (None, None) => Body::empty(),
+      };
+      let resp = self
+        .client
+        .request(self.build_request(&method, &uri, &headers, body))
+        .await
+        .map_err(rt_error)?;
+
+      let status = resp.status();
+      let location = match resp.headers().get(LOCATION) {
+        Some(location) if follow_redirects && status.is_redirection() => location.clone(),
+        _ => return Ok((uri, resp)),
+      };
+      match status {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER
+          if method != Method::HEAD
+            && (status == StatusCode::SEE_OTHER || method == Method::POST) =>
+        {
+          method = Method::GET;
+          replay = Some(Bytes::new());
+          headers.remove(CONTENT_TYPE);
+          headers.remove(CONTENT_LENGTH);
+          headers.remove(TRANSFER_ENCODING);
+        }
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT
+          if replay.is_some() => {}
+        _ => return Ok((uri, resp)),
+      }
+      if redirects == MAX_REDIRECTS {
+        return Err(rt_error_fmt!(
+          "too many redirects (more than {MAX_REDIRECTS})"
+        ));
+      }
+      let next = resolve_location(&uri, location.as_bytes())?;
+      if next.authority() != uri.authority() {
+        // Do not leak credentials to other hosts
+        headers.remove(AUTHORIZATION);
+        headers.remove(COOKIE);
+      }
+      uri = next;
+      redirects += 1;
+    }
+  }
+
+  fn build_request(
+    &self,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Body,
+  ) -> Request<Body> {
+    let mut builder = Request::builder().method(method.clone()).uri(uri.clone());
+    let headers_mut = builder.headers_mut().unwrap();
+    *headers_mut = headers.clone();
+    // Plain HTTP requests are forwarded by the proxy instead of tunnelled
+    if let Some(auth) = (self.proxy.as_ref()).and_then(|x| x.auth.as_ref()) {
+      if uri.scheme() == Some(&Scheme::HTTP) {
+        headers_mut.insert(PROXY_AUTHORIZATION, auth.clone());
+      }
+    }
+    builder.body(body).unwrap()
+  }
+}
+
+/// Resolves a redirect's `Location` against the URI it comes from.
+fn resolve_location(base: &Uri, location: &[u8]) -> mlua::Result<Uri> {
+  let location =
+    std::str::from_utf8(location).map_err(|_| rt_error("invalid redirect location (not UTF-8)"))?;
+  if let Ok(uri) = Uri::try_from(location) {
+    if uri.scheme().is_some() {
+      return Ok(uri);
+    }
+  }
+  let scheme = base.scheme_str().unwrap_or("http");
+  let authority = base.authority().map(|x| x.as_str()).unwrap_or_default();
+  let path = base.path();
+  let target = if let Some(rest) = location.strip_prefix("//") {
+    format!("{scheme}://{rest}")
+  } else if location.starts_with('/') {
+    format!("{scheme}://{authority}{location}")
+  } else if location.starts_with('?') {
+    format!("{scheme}://{authority}{path}{location}")
+  } else {
+    let dir = &path[..path.rfind('/').map(|x| x + 1).unwrap_or(0)];
+    format!("{scheme}://{authority}{dir}{location}")
+  };
+  Uri::try_from(&*target)
+    .map_err(|error| rt_error_fmt!("invalid redirect location '{location}' ({error})"))
+}
+
+/// Wraps a response body so that reading it fails after the deadline or when
+/// it grows over `max_size`.
+fn limit_body(body: Body, deadline: Option<(Instant, u128)>, max_size: Option<usize>) -> Body {
+  let stream = stream::unfold(Some((body, 0)), move |state| async move {
+    let (mut body, size) = state?;
+    let next = match deadline {
+      Some((deadline, ms)) => match timeout_at(deadline, body.next()).await {
+        Ok(next) => next,
+        Err(_) => {
+          let error: BoxError = format!("request timed out after {ms}ms").into();
+          return Some((Err(error), None));
+        }
+      },
+      None => body.next().await,
+    };
+    match next? {
+      Ok(chunk) => {
+        let size = size + chunk.len();
+        match max_size {
+          Some(max) if size > max => {
+            let error: BoxError = format!("response body exceeds {max} bytes").into();
+            Some((Err(error), None))
+          }
+          _ => Some((Ok(chunk), Some((body, size)))),
+        }
+      }
+      Err(error) => Some((Err(error.into()), None)),
+    }
+  });
+  Body::wrap_stream(stream)
+}
+
+#[derive(Debug)]
+struct Proxy {
+  uri: Uri,
+  /// `Proxy-Authorization` header value from the proxy URI's user info.
+  auth: Option<HeaderValue>,
+}
+
+/// HTTPS connector that optionally goes through an HTTP proxy.
+///
+/// HTTPS requests are tunnelled through the proxy with `CONNECT`, while plain
+/// HTTP ones are sent to the proxy in absolute form.
+#[derive(Debug, Clone)]
+struct Connector {
+  http: HttpConnector,
+  tls: TlsConnector,
+  proxy: Option<Arc<Proxy>>,
+  egress: Egress,
+}
+
+impl Connector {
+  /// Connects directly to `dst`, skipping addresses denied by the egress
+  /// policy.
+  ///
+  /// Hosts are resolved here rather than by `HttpConnector`, so that names
+  /// pointing at private addresses are caught as well.
+  async fn connect_direct(&mut self, dst: &Uri, host: &str) -> Result<TcpStream, BoxError> {
+    let port = (dst.port_u16()).unwrap_or(if dst.scheme() == Some(&Scheme::HTTPS) {
+      443
+    } else {
+      80
+    });
+    let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+      Ok(ip) => vec![SocketAddr::new(ip, port)],
+      Err(_) => lookup_host((host, port)).await?.collect(),
+    };
+    let mut error: Option<BoxError> = None;
+    for addr in addrs {
+      if let Err(denied) = self.egress.check_addr(host, addr.ip()) {
+        error.get_or_insert_with(|| denied.into());
+        continue;
+      }
+      let uri = match addr.ip() {
+        IpAddr::V4(ip) => format!("http://{ip}:{port}"),
+        IpAddr::V6(ip) => format!("http://[{ip}]:{port}"),
+      };
+      match self.http.call(Uri::try_from(uri)?).await {
+        Ok(tcp) => return Ok(tcp),
+        Err(e) => error = Some(e.into()),
+      }
+    }
+    Err(error.unwrap_or_else(|| format!("no address found for '{host}'").into()))
+  }
+}
+
+impl Service<Uri> for Connector {
+  type Response = ProxyStream;
+  type Error = BoxError;
+  type Future = BoxFuture<'static, Result<ProxyStream, BoxError>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+    self.http.poll_ready(cx).map_err(Into::into)
+  }
+
+  fn call(&mut self, dst: Uri) -> Self::Future {
+    let mut this = self.clone();
+    async move {
+      let https = dst.scheme() == Some(&Scheme::HTTPS);
+      let host = dst.host().ok_or("missing host in URI")?;
+      let (tcp, proxied) = match &this.proxy {
+        Some(proxy) => {
+          let mut tcp = this.http.call(proxy.uri.clone()).await?;
+          if https {
+            let port = dst.port_u16().unwrap_or(443);
+            tunnel(&mut tcp, host, port, proxy.auth.as_ref()).await?;
+          }
+          (tcp, !https)
+        }
+        None => (this.connect_direct(&dst, host).await?, false),
+      };
+      let inner = if https {
+        let domain = host.trim_start_matches('[').trim_end_matches(']');
+        MaybeHttpsStream::from(this.tls.connect(domain, tcp).await?)
+      } else {
+        MaybeHttpsStream::from(tcp)
+      };
+      Ok(ProxyStream { inner, proxied })
+    }
+    .boxed()
+  }
+}
+
+/// Opens a tunnel to `host:port` through a proxy.
+async fn tunnel(
+  tcp: &mut TcpStream,
+  host: &str,
+  port: u16,
+  auth: Option<&HeaderValue>,
+) -> Result<(), BoxError> {
+  let mut req = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n").into_bytes();
+  if let Some(auth) = auth {
+    req.extend_from_slice(b"Proxy-Authorization: ");
+    req.extend_from_slice(auth.as_bytes());
+    req.extend_from_slice(b"\r\n");
+  }
+  req.extend_from_slice(b"\r\n");
+  tcp.write_all(&req).await?;
+
+  let mut resp = Vec::new();
+  let mut buf = [0; 1024];
+  while !resp.ends_with(b"\r\n\r\n") {
+    let n = tcp.read(&mut buf).await?;
+    if n == 0 {
+      return Err("proxy closed connection during tunnel setup".into());
+    }
+    resp.extend_from_slice(&buf[..n]);
+    if resp.len() > MAX_TUNNEL_RESPONSE {
+      return Err("proxy response too large".into());
+    }
+  }
+  let status_line = resp.split(|&x| x == b'\r').next().unwrap_or_default();
+  if status_line.split(|&x| x == b' ').nth(1) != Some(b"200") {
+    let status_line = String::from_utf8_lossy(status_line);
+    return Err(format!("proxy refused to tunnel: {status_line}").into());
+  }
+  Ok(())
+}
+
+struct ProxyStream {
+  inner: MaybeHttpsStream<TcpStream>,
+  /// Whether requests on this connection are sent to a proxy in absolute form.
+  proxied: bool,
+}
+
+impl Connection for ProxyStream {
+  fn connected(&self) -> Connected {
+    self.inner.connected().proxy(self.proxied)
+  }
+}
+
+impl AsyncRead for ProxyStream {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for ProxyStream {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_write_vectored(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    bufs: &[io::IoSlice<'_>],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    self.inner.is_write_vectored()
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::service::{make_service_fn, service_fn};
+  use hyper::Server;
+  use std::convert::Infallible;
+  use test_case::test_case;
+
+  /// Serves on a random local port:
+  ///
+  /// - `/echo`: method, `Authorization` header and body of the request;
+  /// - `/redirect/<status>?<location>`: redirects to the location;
+  /// - `/loop`: redirects to itself;
+  /// - `/slow`: responds after 1 second;
+  /// - `/slow_body`: sends a chunk of body, then stalls for 1 second;
+  /// - `/big`: 1000 bytes of body;
+  /// - `/big_chunked`: 1000 bytes of body without `Content-Length`.
+  async fn serve() -> SocketAddr {
+    async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+      let path = req.uri().path().to_string();
+      let redirect = |status: u16, location: &str| {
+        Response::builder()
+          .status(status)
+          .header(LOCATION, location)
+          .body(Body::empty())
+          .unwrap()
+      };
+      let resp = match &*path {
+        "/echo" => {
+          let method = req.method().to_string();
+          let auth = (req.headers().get(AUTHORIZATION))
+            .map(|x| x.to_str().unwrap().to_string())
+            .unwrap_or_default();
+          let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+          let body = String::from_utf8_lossy(&body);
+          Response::new(Body::from(format!("{method} {auth} {body}")))
+        }
+        "/loop" => redirect(302, "/loop"),
+        "/slow" => {
+          tokio::time::sleep(Duration::from_secs(1)).await;
+          Response::new(Body::empty())
+        }
+        "/slow_body" => {
+          let (mut tx, body) = Body::channel();
+          tokio::spawn(async move {
+            tx.send_data("a".into()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let _ = tx.send_data("b".into()).await;
+          });
+          Response::new(body)
+        }
+        "/big" => Response::new(Body::from(vec![b'a'; 1000])),
+        "/big_chunked" => {
+          let chunks = (0..10).map(|_| Ok::<_, Infallible>(vec![b'a'; 100]));
+          Response::new(Body::wrap_stream(stream::iter(chunks)))
+        }
+        path => match path.strip_prefix("/redirect/") {
+          Some(status) => redirect(status.parse().unwrap(), req.uri().query().unwrap()),
+          None => Response::builder().status(404).body(Body::empty()).unwrap(),
+        },
+      };
+      Ok(resp)
+    }
+
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+  }
+
+  fn client() -> HttpClient {
+    let egress = EgressPolicy {
+      allow: None,
+      block_private: false,
+    };
+    HttpClient::new(Default::default(), egress).unwrap()
+  }
+
+  fn request(method: Method, uri: String, auth: Option<&str>, body: &str) -> LuaRequest {
+    let mut headers = HeaderMap::new();
+    if let Some(auth) = auth {
+      headers.insert(AUTHORIZATION, auth.parse().unwrap());
+    }
+    LuaRequest {
+      method,
+      uri: uri.parse().unwrap(),
+      headers: Rc::new(RefCell::new(headers)),
+      body: Some(LuaBody::Bytes(body.into())),
+      ..Default::default()
+    }
+  }
+
+  /// Sends a request, returning the response's status, URI and body.
+  async fn send(
+    client: &HttpClient,
+    req: LuaRequest,
+    options: RequestOptions,
+  ) -> mlua::Result<(StatusCode, String, String)> {
+    let resp = client.request(req, options).await?;
+    let uri = resp.uri.as_ref().unwrap().to_string();
+    let resp = Response::from(resp);
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body())
+      .await
+      .map_err(rt_error)?;
+    Ok((status, uri, String::from_utf8_lossy(&body).into()))
+  }
+
+  #[test]
+  fn test_proxy_requires_private_unblocked() {
+    let options = || HttpClientOptions {
+      proxy: Some("http://127.0.0.1:3128".parse().unwrap()),
+      ..Default::default()
+    };
+    assert!(HttpClient::new(options(), EgressPolicy::default()).is_err());
+    let egress = EgressPolicy {
+      allow: None,
+      block_private: false,
+    };
+    assert!(HttpClient::new(options(), egress).is_ok());
+  }
+
+  #[test_case(Method::POST, 301 => "GET  "; "301 rewrites POST")]
+  #[test_case(Method::POST, 302 => "GET  "; "302 rewrites POST")]
+  #[test_case(Method::PUT, 302 => "PUT  body"; "302 keeps PUT")]
+  #[test_case(Method::PUT, 303 => "GET  "; "303 rewrites PUT")]
+  #[test_case(Method::POST, 307 => "POST  body"; "307 keeps POST")]
+  #[test_case(Method::POST, 308 => "POST  body"; "308 keeps POST")]
+  #[tokio::test]
+  async fn test_redirect_method(method: Method, status: u16) -> String {
+    let addr = serve().await;
+    let uri = format!("http://{addr}/redirect/{status}?/echo");
+    let req = request(method, uri, None, "body");
+    let (_, final_uri, body) = send(&client(), req, Default::default()).await.unwrap();
+    assert_eq!(final_uri, format!("http://{addr}/echo"));
+    body
+  }
+
+  #[tokio::test]
+  async fn test_redirect_credentials() {
+    let addr = serve().await;
+    let client = client();
+    let port = addr.port();
+
+    let uri = format!("http://127.0.0.1:{port}/redirect/302?/echo");
+    let req = request(Method::GET, uri, Some("Bearer x"), "");
+    let (_, _, body) = send(&client, req, Default::default()).await.unwrap();
+    assert_eq!(body, "GET Bearer x ");
+
+    let location = format!("http://localhost:{port}/echo");
+    let uri = format!("http://127.0.0.1:{port}/redirect/302?{location}");
+    let req = request(Method::GET, uri, Some("Bearer x"), "");
+    let (_, final_uri, body) = send(&client, req, Default::default()).await.unwrap();
+    assert_eq!((&*final_uri, &*body), (&*location, "GET  "));
+  }
+
+  #[tokio::test]
+  async fn test_redirect_limit() {
+    let addr = serve().await;
+    let client = client();
+
+    let req = request(Method::GET, format!("http://{addr}/loop"), None, "");
+    let error = send(&client, req, Default::default()).await.unwrap_err();
+    assert!(error.to_string().contains("too many redirects"), "{error}");
+
+    let req = request(Method::GET, format!("http://{addr}/loop"), None, "");
+    let options = RequestOptions {
+      follow_redirects: Some(false),
+      ..Default::default()
+    };
+    let (status, uri, _) = send(&client, req, options).await.unwrap();
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(uri, format!("http://{addr}/loop"));
+  }
+
+  #[test_case("/slow"; "response")]
+  #[test_case("/slow_body"; "body")]
+  #[tokio::test]
+  async fn test_timeout(path: &str) {
+    let addr = serve().await;
+    let req = request(Method::GET, format!("http://{addr}{path}"), None, "");
+    let options = RequestOptions {
+      timeout: Some(Duration::from_millis(100)),
+      ..Default::default()
+    };
+    let error = send(&client(), req, options).await.unwrap_err();
+    assert!(
+      error.to_string().contains("timed out after 100ms"),
+      "{error}"
+    );
+  }
+
+  #[test_case("/big", 1000 => true; "exact")]
+  #[test_case("/big", 999 => false)]
+  #[test_case("/big_chunked", 1000 => true; "chunked exact")]
+  #[test_case("/big_chunked", 999 => false; "chunked")]
+  #[tokio::test]
+  async fn test_max_body_size(path: &str, max: usize) -> bool {
+    let addr = serve().await;
+    let req = request(Method::GET, format!("http://{addr}{path}"), None, "");
+    let options = RequestOptions {
+      max_body_size: Some(max),
+      ..Default::default()
+    };
+    match send(&client(), req, options).await {
+      Ok((_, _, body)) => body.len() == 1000,
+      Err(error) => {
+        assert!(error.to_string().contains("exceeds 999 bytes"), "{error}");
+        false
+      }
+    }
+  }
+
+  #[test_case("https://example.com/a/b", "http://other.org/x" => "http://other.org/x")]
+  #[test_case("https://example.com/a/b", "//other.org/x" => "https://other.org/x")]
+  #[test_case("https://example.com/a/b", "/c?d=1" => "https://example.com/c?d=1")]
+  #[test_case("https://example.com/a/b", "c" => "https://example.com/a/c")]
+  #[test_case("https://example.com/a/b", "?q" => "https://example.com/a/b?q")]
+  #[test_case("http://example.com:8080", "c" => "http://example.com:8080/c")]
+  fn test_resolve_location(base: &str, location: &str) -> String {
+    let base = Uri::try_from(base).unwrap();
+    resolve_location(&base, location.as_bytes())
+      .unwrap()
+      .to_string()
+  }
+}
@@ -0,0 +1,200 @@
+use super::{LuaRequest, LuaResponse};
+use crate::lua::error::{
+  bad_field, check_userdata, check_value, rt_error, rt_error_fmt, tag_error, tag_handler,
+  TableCheckExt,
+};
+use crate::lua::LuaCacheExt;
+use crate::task::TaskContext;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, HeaderMap};
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, MultiValue, Table, UserData, UserDataFields};
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+
+/// Default interval of heartbeat comments in milliseconds.
+const DEFAULT_HEARTBEAT: u64 = 15000;
+
+/// Server-Sent Events stream, returned by the handler as the response.
+///
+/// - Sink: `es:write(event: { event: string?, data: any, id: string?, retry: integer? } | string)`
+pub struct LuaEventStream {
+  tx: Option<mpsc::Sender<Bytes>>,
+  body: Option<Body>,
+  last_event_id: Option<String>,
+  heartbeat: Option<JoinHandle<()>>,
+}
+
+impl LuaEventStream {
+  fn new(last_event_id: Option<String>, heartbeat: Option<Duration>) -> Self {
+    let (tx, rx) = mpsc::channel(16);
+    let heartbeat = heartbeat.map(|period| {
+      let mut tx = tx.clone();
+      tokio::spawn(async move {
+        let mut interval = interval_at(Instant::now() + period, period);
+        loop {
+          interval.tick().await;
+          if let Err(error) = tx.try_send(Bytes::from_static(b":\n\n")) {
+            if error.is_disconnected() {
+              break;
+            }
+          }
+        }
+      })
+    });
+    Self {
+      tx: Some(tx),
+      body: Some(Body::wrap_stream(rx.map(Ok::<_, Infallible>))),
+      last_event_id,
+      heartbeat,
+    }
+  }
+
+  pub(crate) fn take_response(&mut self) -> mlua::Result<LuaResponse> {
+    let body = (self.body.take()).ok_or_else(|| rt_error("event stream already responded"))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      header::CONTENT_TYPE,
+      HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(LuaResponse {
+      headers: Rc::new(RefCell::new(headers)),
+      body: Some(body.into()),
+      ..Default::default()
+    })
+  }
+
+  fn close(&mut self) {
+    self.tx = None;
+    if let Some(heartbeat) = self.heartbeat.take() {
+      heartbeat.abort();
+    }
+  }
+}
+
+impl Drop for LuaEventStream {
+  fn drop(&mut self) {
+    self.close()
+  }
+}
+
+fn encode_event(lua: &Lua, event: mlua::Value) -> mlua::Result<Bytes> {
+  fn check_line(field: &str, x: &str) -> mlua::Result<()> {
+    if x.contains(['\r', '\n']) {
+      Err(bad_field(field, "must not contain line breaks"))
+    } else {
+      Ok(())
+    }
+  }
+
+  fn data_string(lua: &Lua, data: mlua::Value) -> mlua::Result<String> {
+    match data {
+      x @ mlua::Value::Table(_) => serde_json::to_string(&x).map_err(rt_error),
+      x => {
+        let type_name = x.type_name();
+        let x = (lua.coerce_string(x)?)
+          .ok_or_else(|| rt_error_fmt!("string or JSON table expected as data, got {type_name}"))?;
+        Ok(x.to_str()?.into())
+      }
+    }
+  }
+
+  let mut buf = String::new();
+  let data = match event {
+    mlua::Value::Table(t) => {
+      if let Some(event) = t.check_raw_get::<Option<String>>(lua, "event", "string")? {
+        check_line("event", &event)?;
+        buf += &format!("event: {event}\n");
+      }
+      if let Some(id) = t.check_raw_get::<Option<String>>(lua, "id", "string")? {
+        check_line("id", &id)?;
+        buf += &format!("id: {id}\n");
+      }
+      if let Some(retry) = t.check_raw_get::<Option<u64>>(lua, "retry", "integer")? {
+        buf += &format!("retry: {retry}\n");
+      }
+      data_string(lua, t.raw_get("data")?).map_err(|error| bad_field("data", error))?
+    }
+    x => data_string(lua, x)?,
+  };
+  // Empty data is still sent as an event. Clients break lines at CRLF, and at
+  // lone CR or LF as well.
+  for line in data.split("\r\n").flat_map(|x| x.split(['\r', '\n'])) {
+    buf += &format!("data: {line}\n");
+  }
+  buf.push('\n');
+  Ok(buf.into())
+}
+
+impl UserData for LuaEventStream {
+  fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+    fields.add_field_method_get("last_event_id", |lua, this| {
+      lua.pack(this.last_event_id.as_deref())
+    });
+  }
+
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn close(_lua: &Lua, this: AnyUserData) -> mlua::Result<()> {
+      this.borrow_mut::<LuaEventStream>()?.close();
+      Ok(())
+    }
+
+    methods.add_meta_function("__close", close);
+    methods.add_function("close", close);
+
+    methods.add_async_function("write", |lua, mut args: MultiValue| async move {
+      let tx = check_userdata::<Self>(args.pop_front(), "event stream")
+        .map_err(tag_handler(lua, 1, 1))?
+        .with_borrowed(|x| x.tx.clone());
+      let event = args
+        .pop_front()
+        .ok_or_else(|| tag_error(lua, 2, "table or string", "no value", 1))?;
+      let bytes = encode_event(lua, event)?;
+      let mut tx = tx.ok_or_else(|| rt_error("event stream closed"))?;
+      tx.send(bytes)
+        .await
+        .map_err(|_| rt_error("event stream closed"))
+    });
+  }
+}
+
+pub fn create_fn_http_create_event_stream(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.EventStream", |lua, mut args: MultiValue| {
+    let req = Some(args.pop_front().unwrap_or(Nil));
+    let req =
+      check_value::<Option<AnyUserData>>(lua, req, "request").map_err(tag_handler(lua, 1, 0))?;
+    let last_event_id = if let Some(req) = req {
+      let req = req
+        .borrow::<LuaRequest>()
+        .map_err(|_| tag_error(lua, 1, "request", "other userdata", 0))?;
+      let headers = req.headers.borrow();
+      (headers.get("last-event-id"))
+        .and_then(|x| x.to_str().ok())
+        .map(String::from)
+    } else {
+      None
+    };
+
+    let options = Some(args.pop_front().unwrap_or(Nil));
+    let options =
+      check_value::<Option<Table>>(lua, options, "table").map_err(tag_handler(lua, 2, 0))?;
+    let heartbeat = options
+      .map(|x| x.check_raw_get::<Option<u64>>(lua, "heartbeat", "integer"))
+      .transpose()?
+      .flatten()
+      .unwrap_or(DEFAULT_HEARTBEAT);
+    let heartbeat = (heartbeat > 0).then(|| Duration::from_millis(heartbeat));
+
+    let stream = lua.create_userdata(LuaEventStream::new(last_event_id, heartbeat))?;
+    TaskContext::register(lua, stream.clone())?;
+    Ok(stream)
+  })
+}
@@ -1,65 +1,100 @@
 mod body;
+mod client;
+mod event_stream;
 mod header_map;
 mod request;
 mod response;
 mod uri;
+mod websocket;
 
+pub use client::{HttpClient, HttpClientOptions};
 pub use request::LuaRequest;
+pub(crate) use request::{resolve_body_error, BodyTooLarge};
 pub use response::LuaResponse;
 pub(crate) use uri::LuaUri;
 
 use crate::lua::error::{arg_error, check_value, rt_error, rt_error_fmt, tag_error, tag_handler};
-use crate::lua::{LuaCacheExt, LuaEither, LUA_HTTP_CLIENT};
+use crate::lua::{LuaCacheExt, LuaEither};
 use bstr::ByteSlice;
+use client::RequestOptions;
+use event_stream::create_fn_http_create_event_stream;
 use hyper::header::{HeaderName, HeaderValue};
 use hyper::HeaderMap;
-use mlua::{AnyUserData, Function, Lua, MultiValue, Table};
+use mlua::{AnyUserData, Function, Lua, MultiValue, Table, UserData};
 use response::create_fn_http_create_response;
 use uri::create_fn_http_create_uri;
 
-pub fn create_preload_http(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_cached_function("abel:preload_http", move |lua, ()| {
-    let http = lua.create_table()?;
-    http.raw_set("request", create_fn_http_request(lua)?)?;
-    http.raw_set("Response", create_fn_http_create_response(lua)?)?;
-    http.raw_set("Uri", create_fn_http_create_uri(lua)?)?;
-    Ok(http)
-  })
+/// Client of a service, bound to the cached loader of the `http` library.
+struct LuaHttpClient(HttpClient);
+
+impl UserData for LuaHttpClient {}
+
+pub fn create_preload_http(client: HttpClient) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    let preload =
+      lua.create_cached_function("abel:preload_http", |lua, mut args: MultiValue| {
+        let client = args.pop_front();
+        let http = lua.create_table()?;
+        http.raw_set("request", create_fn_http_request(lua)?.bind(client)?)?;
+        http.raw_set("Response", create_fn_http_create_response(lua)?)?;
+        http.raw_set("Uri", create_fn_http_create_uri(lua)?)?;
+        http.raw_set("EventStream", create_fn_http_create_event_stream(lua)?)?;
+        Ok(http)
+      })?;
+    preload.bind(LuaHttpClient(client))
+  }
 }
 
-pub fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
-  fn check_request_first_arg(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<LuaRequest> {
-    use LuaEither::*;
-    type RequestMeta<'a> = LuaEither<LuaEither<mlua::String<'a>, Table<'a>>, AnyUserData<'a>>;
-    const EXPECTED: &str = "URI or request";
+/// Checks a URI or request argument, as accepted by `http.request`.
+pub(crate) fn check_request(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+) -> mlua::Result<LuaRequest> {
+  use LuaEither::*;
+  type RequestMeta<'a> = LuaEither<LuaEither<mlua::String<'a>, Table<'a>>, AnyUserData<'a>>;
+  const EXPECTED: &str = "URI or request";
 
-    let either =
-      check_value::<RequestMeta>(lua, value, EXPECTED).map_err(tag_handler(lua, 1, 1))?;
-    match either {
-      Left(Left(uri)) => Ok(LuaRequest {
-        uri: hyper::Uri::try_from(uri.as_bytes())
-          .map_err(|error| arg_error(lua, 1, &error.to_string(), 1))?,
-        ..Default::default()
-      }),
-      Left(Right(table)) => LuaRequest::from_table(lua, table),
-      Right(u) if u.is::<LuaRequest>() => LuaRequest::from_userdata(lua, u),
-      Right(u) if u.is::<LuaUri>() => Ok(LuaRequest {
-        uri: u.borrow::<LuaUri>()?.0.clone(),
-        ..Default::default()
-      }),
-      Right(_) => Err(tag_error(lua, 1, EXPECTED, "other userdata", 1)),
-    }
+  let either =
+    check_value::<RequestMeta>(lua, value, EXPECTED).map_err(tag_handler(lua, pos, 1))?;
+  match either {
+    Left(Left(uri)) => Ok(LuaRequest {
+      uri: hyper::Uri::try_from(uri.as_bytes())
+        .map_err(|error| arg_error(lua, pos, &error.to_string(), 1))?,
+      ..Default::default()
+    }),
+    Left(Right(table)) => LuaRequest::from_table(lua, table),
+    Right(u) if u.is::<LuaRequest>() => LuaRequest::from_userdata(lua, u),
+    Right(u) if u.is::<LuaUri>() => Ok(LuaRequest {
+      uri: u.borrow::<LuaUri>()?.0.clone(),
+      ..Default::default()
+    }),
+    Right(_) => Err(tag_error(lua, pos, EXPECTED, "other userdata", 1)),
   }
+}
 
+/// Sends a request, returning its response.
+///
+/// Request tables may also contain `timeout` in milliseconds,
+/// `follow_redirects` and `max_body_size` in bytes, overriding the server's
+/// defaults.
+///
+/// The service's client is bound as the first argument.
+fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function(
     "abel:http.request",
-    move |lua, mut args: MultiValue| async move {
-      let req = check_request_first_arg(lua, args.pop_front())?;
-      LUA_HTTP_CLIENT
-        .request(req.into())
-        .await
-        .map(LuaResponse::from_hyper)
-        .map_err(rt_error)
+    |lua, mut args: MultiValue| async move {
+      let client = match args.pop_front() {
+        Some(mlua::Value::UserData(client)) => client.borrow::<LuaHttpClient>()?.0.clone(),
+        _ => return Err(rt_error("HTTP client not bound")),
+      };
+      let req = args.pop_front();
+      let options = match &req {
+        Some(mlua::Value::Table(table)) => RequestOptions::from_table(lua, table)?,
+        _ => Default::default(),
+      };
+      let req = check_request(lua, req, 1)?;
+      client.request(req, options).await
     },
   )
 }
@@ -1,15 +1,23 @@
 use super::body::LuaBody;
 use super::header_map::LuaHeaderMap;
 use super::uri::LuaUri;
-use crate::lua::error::{bad_field, rt_error_fmt, TableCheckExt};
+use super::websocket::LuaWebSocket;
+use crate::lua::error::{bad_field, http_error, rt_error, rt_error_fmt, TableCheckExt};
 use crate::lua::http::check_headers;
-use crate::path::Params;
-use crate::task::close_value;
+use crate::path::{ParamValue, Params};
+use crate::task::{close_value, TaskContext};
+use crate::ErrorKind::PayloadTooLarge;
+use crate::Result;
+use futures::{stream, StreamExt};
+use hyper::header;
 use hyper::http::request::Parts;
+use hyper::upgrade::OnUpgrade;
 use hyper::{Body, HeaderMap, Method, Request, Uri};
-use mlua::{AnyUserData, Lua, Table, UserData};
+use mlua::{AnyUserData, Lua, Table, ToLua, UserData};
 use std::cell::RefCell;
 use std::rc::Rc;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
 
 pub struct LuaRequest {
   pub(crate) method: Method,
@@ -19,16 +27,72 @@ pub struct LuaRequest {
   pub(crate) body: Option<LuaBody>,
   /// Only used in Abel core
   pub(crate) params: Option<Params>,
+  /// Only present in requests received by Abel that can be upgraded
+  pub(crate) upgrade: Option<OnUpgrade>,
 }
 
 impl LuaRequest {
   #[rustfmt::skip]
   pub fn new(req: Request<Body>, params: Params) -> Self {
-    let (Parts { method, uri, headers, .. }, body) = req.into_parts();
+    let (Parts { method, uri, headers, mut extensions, .. }, body) = req.into_parts();
     let headers = Rc::new(RefCell::new(headers));
     let body = Some(body.into());
     let params = Some(params);
-    Self { method, uri, headers, body, params }
+    let upgrade = extensions.remove::<OnUpgrade>();
+    Self { method, uri, headers, body, params, upgrade }
+  }
+
+  /// Limits the size of the request body to `max` bytes.
+  ///
+  /// Requests whose `Content-Length` is over the limit are rejected at once.
+  /// Otherwise, reading the body fails as soon as it grows over the limit.
+  pub(crate) fn limit_body(&mut self, max: u64) -> Result<()> {
+    let len = (self.headers.borrow().get(header::CONTENT_LENGTH))
+      .and_then(|x| x.to_str().ok()?.parse::<u64>().ok());
+    if len.is_some_and(|len| len > max) {
+      return Err(PayloadTooLarge { limit: max }.into());
+    }
+    if let Some(LuaBody::Stream(body)) = self.body.take() {
+      let stream = stream::unfold(Some((body, 0)), move |state| async move {
+        let (mut body, size) = state?;
+        match body.next().await? {
+          Ok(chunk) if size + chunk.len() as u64 > max => {
+            Some((Err(BodyTooLarge { limit: max }.into()), None))
+          }
+          Ok(chunk) => {
+            let size = size + chunk.len() as u64;
+            Some((Ok(chunk), Some((body, size))))
+          }
+          Err(error) => Some((Err(BoxError::from(error)), None)),
+        }
+      });
+      self.body = Some(LuaBody::Stream(Body::wrap_stream(stream)));
+    }
+    Ok(())
+  }
+
+  /// Checks WebSocket handshake headers and returns the accept key.
+  fn websocket_accept_key(&self) -> mlua::Result<String> {
+    let headers = self.headers.borrow();
+    let has_token = |name, token: &str| {
+      (headers.get_all(name).iter())
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case(token))
+    };
+    if self.method != Method::GET {
+      return Err(rt_error("WebSocket handshake requires GET method"));
+    }
+    if !has_token(header::CONNECTION, "upgrade") || !has_token(header::UPGRADE, "websocket") {
+      return Err(rt_error("not a WebSocket handshake request"));
+    }
+    let version = headers.get(header::SEC_WEBSOCKET_VERSION);
+    if version.map(|x| x.as_bytes()) != Some(b"13") {
+      return Err(rt_error("unsupported WebSocket version"));
+    }
+    let key =
+      (headers.get(header::SEC_WEBSOCKET_KEY)).ok_or_else(|| rt_error("missing WebSocket key"))?;
+    Ok(derive_accept_key(key.as_bytes()))
   }
 
   pub fn from_table<'lua>(lua: &'lua Lua, table: Table<'lua>) -> mlua::Result<LuaRequest> {
@@ -78,6 +142,31 @@ impl LuaRequest {
   }
 }
 
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Error of reading a request body over its size limit.
+#[derive(Debug, Clone, Copy, Error)]
+#[error("request body exceeds {limit} bytes")]
+pub(crate) struct BodyTooLarge {
+  limit: u64,
+}
+
+/// Turns [`BodyTooLarge`] into an HTTP error with the same status and detail
+/// as [`PayloadTooLarge`], so that it survives `pcall` and can be rethrown.
+pub(crate) fn resolve_body_error(lua: &Lua, error: mlua::Error) -> mlua::Error {
+  let limit = match &error {
+    mlua::Error::ExternalError(x) => x.downcast_ref::<BodyTooLarge>().map(|x| x.limit),
+    _ => None,
+  };
+  match limit {
+    Some(limit) => {
+      let kind = PayloadTooLarge { limit };
+      http_error(lua, kind.status(), kind.error(), kind.detail()).unwrap_or_else(|x| x)
+    }
+    None => error,
+  }
+}
+
 impl Default for LuaRequest {
   fn default() -> Self {
     Self {
@@ -86,6 +175,7 @@ impl Default for LuaRequest {
       headers: Default::default(),
       body: Some(LuaBody::Empty),
       params: None,
+      upgrade: None,
     }
   }
 }
@@ -101,9 +191,7 @@ impl UserData for LuaRequest {
             .params
             .take()
             .map(|x| {
-              let iter = x
-                .into_iter()
-                .map(|(k, v)| (k.into_string(), v.into_string()));
+              let iter = x.into_iter().map(|(k, v)| (k.into_string(), v));
               lua.create_table_from(iter)
             })
             .unwrap_or_else(|| lua.create_table())?;
@@ -138,6 +226,20 @@ impl UserData for LuaRequest {
       let _ = this.take::<Self>();
       Ok(())
     });
+
+    // The socket must be returned by the handler as the response.
+    //
+    // It is closed when the request's task and those spawned by it end.
+    methods.add_function("upgrade_websocket", |lua, this: AnyUserData| {
+      let mut this = this.borrow_mut::<Self>()?;
+      let accept_key = this.websocket_accept_key()?;
+      let upgrade =
+        (this.upgrade.take()).ok_or_else(|| rt_error("connection cannot be upgraded"))?;
+
+      let socket = lua.create_userdata(LuaWebSocket::new(upgrade, accept_key))?;
+      TaskContext::register(lua, socket.clone())?;
+      Ok(socket)
+    });
   }
 }
 
@@ -152,3 +254,55 @@ impl From<LuaRequest> for Request<Body> {
     builder.body(x.body.unwrap().into()).unwrap()
   }
 }
+
+impl<'lua> ToLua<'lua> for ParamValue {
+  fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+    match self {
+      Self::Str(x) => lua.pack(&*x),
+      Self::Int(x) => lua.pack(x),
+      Self::Float(x) => lua.pack(x),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ErrorKind;
+  use std::error::Error as _;
+
+  fn request(body: Body, content_length: Option<u64>) -> LuaRequest {
+    let mut builder = Request::builder();
+    if let Some(len) = content_length {
+      builder = builder.header(header::CONTENT_LENGTH, len);
+    }
+    LuaRequest::new(builder.body(body).unwrap(), Params::new())
+  }
+
+  #[test]
+  fn test_limit_body_content_length() {
+    let mut req = request(Body::from("0123456789"), Some(10));
+    let error = req.limit_body(5).unwrap_err();
+    assert!(matches!(
+      error.kind(),
+      ErrorKind::PayloadTooLarge { limit: 5 }
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_limit_body_stream() {
+    let chunks = || stream::iter(["0123", "4567", "89"].map(Ok::<_, std::io::Error>));
+    let mut req = request(Body::wrap_stream(chunks()), None);
+    req.limit_body(8).unwrap();
+    let body = match req.body.take() {
+      Some(LuaBody::Stream(body)) => body,
+      _ => unreachable!(),
+    };
+    let error = hyper::body::to_bytes(body).await.unwrap_err();
+    assert!(error.source().unwrap().is::<BodyTooLarge>());
+
+    let mut req = request(Body::wrap_stream(chunks()), None);
+    req.limit_body(10).unwrap();
+    assert!(matches!(req.body, Some(LuaBody::Stream(_))));
+  }
+}
@@ -1,10 +1,13 @@
 use super::body::LuaBody;
 use super::check_headers;
+use super::event_stream::LuaEventStream;
 use super::header_map::LuaHeaderMap;
+use super::uri::LuaUri;
+use super::websocket::LuaWebSocket;
 use crate::lua::error::{bad_field, check_value, rt_error_fmt, tag_handler, TableCheckExt};
 use crate::lua::LuaCacheExt;
 use hyper::http::{HeaderMap, StatusCode};
-use hyper::{Body, Response};
+use hyper::{Body, Response, Uri};
 use mlua::{FromLua, Function, Lua, MultiValue, Table, UserData, UserDataFields};
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -14,6 +17,8 @@ pub struct LuaResponse {
   pub status: StatusCode,
   pub headers: Rc<RefCell<HeaderMap>>,
   pub body: Option<LuaBody>,
+  /// Final URI of a response from `http.request`, after redirects.
+  pub uri: Option<Uri>,
 }
 
 impl LuaResponse {
@@ -23,6 +28,7 @@ impl LuaResponse {
       status: parts.status,
       headers: Rc::new(RefCell::new(parts.headers)),
       body: Some(body.into()),
+      uri: None,
     }
   }
 }
@@ -30,6 +36,7 @@ impl LuaResponse {
 impl UserData for LuaResponse {
   fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
     fields.add_field_method_get("status", |_lua, this| Ok(this.status.as_u16()));
+    fields.add_field_method_get("uri", |_lua, this| Ok(this.uri.clone().map(LuaUri)));
     fields.add_field_function_get("body", |lua, this| {
       let mut this_ = this.borrow_mut::<Self>()?;
       let body = this_.body.take();
@@ -65,6 +72,10 @@ impl<'lua> FromLua<'lua> for LuaResponse {
             u.body = Some(body);
           }
           Ok(u)
+        } else if x.is::<LuaEventStream>() {
+          x.borrow_mut::<LuaEventStream>()?.take_response()
+        } else if x.is::<LuaWebSocket>() {
+          Ok(x.borrow::<LuaWebSocket>()?.handshake_response())
         } else {
           Ok(
             LuaBody::from_lua_with_error_msg(lua, UserData(x))?
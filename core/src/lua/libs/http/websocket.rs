@@ -0,0 +1,172 @@
+use super::body::LuaBody;
+use super::LuaResponse;
+use crate::lua::error::{
+  arg_error, check_integer, check_string, check_userdata, rt_error, tag_handler,
+};
+use crate::lua::stream::create_table_stream;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use hyper::header::{self, HeaderValue};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{HeaderMap, StatusCode};
+use mlua::{AnyUserData, Lua, MultiValue, UserData, UserDataFields, UserDataMethods};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::sync::{Mutex, OnceCell};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+type Socket = WebSocketStream<Upgraded>;
+type Halves = (
+  Mutex<SplitSink<Socket, Message>>,
+  Mutex<SplitStream<Socket>>,
+);
+
+/// Server side of a WebSocket connection upgraded from a request.
+///
+/// - Stream: `ws:read() -> (string, "text" | "binary")?`
+/// - Sink: `ws:write(data: string, kind: ("text" | "binary")?)`
+///
+/// The handler returns the socket itself as the `101 Switching Protocols`
+/// response. The connection is established on first read or write after
+/// that.
+pub struct LuaWebSocket(Rc<Inner>);
+
+struct Inner {
+  accept_key: HeaderValue,
+  upgrade: RefCell<Option<OnUpgrade>>,
+  socket: OnceCell<Halves>,
+}
+
+impl LuaWebSocket {
+  pub(crate) fn new(upgrade: OnUpgrade, accept_key: String) -> Self {
+    Self(Rc::new(Inner {
+      // Base64 is always a valid header value
+      accept_key: HeaderValue::from_str(&accept_key).unwrap(),
+      upgrade: RefCell::new(Some(upgrade)),
+      socket: OnceCell::new(),
+    }))
+  }
+
+  pub(crate) fn handshake_response(&self) -> LuaResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, self.0.accept_key.clone());
+    LuaResponse {
+      status: StatusCode::SWITCHING_PROTOCOLS,
+      headers: Rc::new(RefCell::new(headers)),
+      body: Some(LuaBody::Empty),
+      ..Default::default()
+    }
+  }
+}
+
+impl Inner {
+  async fn socket(&self) -> mlua::Result<&Halves> {
+    (self.socket)
+      .get_or_try_init(|| async {
+        let upgrade = (self.upgrade.borrow_mut().take())
+          .ok_or_else(|| rt_error("WebSocket connection failed"))?;
+        let upgraded = upgrade.await.map_err(rt_error)?;
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let (sink, stream) = socket.split();
+        Ok((Mutex::new(sink), Mutex::new(stream)))
+      })
+      .await
+  }
+}
+
+fn is_closed(error: &WsError) -> bool {
+  matches!(error, WsError::ConnectionClosed | WsError::AlreadyClosed)
+}
+
+fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<Rc<Inner>> {
+  check_userdata::<LuaWebSocket>(value, "WebSocket")
+    .map(|x| x.with_borrowed(|x| x.0.clone()))
+    .map_err(tag_handler(lua, 1, 1))
+}
+
+impl UserData for LuaWebSocket {
+  fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+    fields.add_meta_field_with("__index", create_table_stream);
+  }
+
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      drop(this.take::<Self>());
+      Ok(())
+    });
+
+    methods.add_async_function("read", |lua, mut args: MultiValue| async move {
+      let this = check_self(lua, args.pop_front())?;
+      let mut stream = this.socket().await?.1.lock().await;
+      let (data, kind) = loop {
+        match stream.next().await {
+          Some(Ok(Message::Text(x))) => break (lua.create_string(&x)?, "text"),
+          Some(Ok(Message::Binary(x))) => break (lua.create_string(&x)?, "binary"),
+          // Pings are answered by the socket itself
+          Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+          Some(Ok(Message::Close(_))) | None => return Ok(MultiValue::new()),
+          Some(Err(error)) if is_closed(&error) => return Ok(MultiValue::new()),
+          Some(Err(error)) => return Err(rt_error(error)),
+        }
+      };
+      lua.pack_multi((data, kind))
+    });
+
+    methods.add_async_function("write", |lua, mut args: MultiValue| async move {
+      let this = check_self(lua, args.pop_front())?;
+      let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let kind = (args.pop_front())
+        .map(|x| check_string(lua, Some(x)))
+        .transpose()
+        .map_err(tag_handler(lua, 3, 1))?;
+      let message = match kind.as_ref().map(|x| x.as_bytes()) {
+        // Defaults to text if possible, so that byte streams can be piped in
+        None => match data.to_str() {
+          Ok(x) => Message::Text(x.into()),
+          Err(_) => Message::Binary(data.as_bytes().into()),
+        },
+        Some(b"text") => Message::Text(
+          (data.to_str())
+            .map_err(|_| arg_error(lua, 2, "text must be valid UTF-8", 1))?
+            .into(),
+        ),
+        Some(b"binary") => Message::Binary(data.as_bytes().into()),
+        Some(_) => return Err(arg_error(lua, 3, "expected 'text' or 'binary'", 1)),
+      };
+      let mut sink = this.socket().await?.0.lock().await;
+      sink.send(message).await.map_err(rt_error)
+    });
+
+    methods.add_async_function("close", |lua, mut args: MultiValue| async move {
+      let this = check_self(lua, args.pop_front())?;
+      let code = (args.pop_front())
+        .map(|x| check_integer(Some(x)))
+        .transpose()
+        .map_err(tag_handler(lua, 2, 1))?
+        .map(|x| u16::try_from(x).map_err(|_| arg_error(lua, 2, "invalid close code", 1)))
+        .transpose()?;
+      let reason = (args.pop_front())
+        .map(|x| check_string(lua, Some(x)))
+        .transpose()
+        .map_err(tag_handler(lua, 3, 1))?;
+      let frame = code.map(|code| CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason
+          .map(|x| Cow::Owned(x.to_string_lossy().into_owned()))
+          .unwrap_or_default(),
+      });
+      let mut sink = this.socket().await?.0.lock().await;
+      match sink.send(Message::Close(frame)).await {
+        Ok(()) => Ok(()),
+        Err(error) if is_closed(&error) => Ok(()),
+        Err(error) => Err(rt_error(error)),
+      }
+    });
+  }
+}
@@ -0,0 +1,241 @@
+use crate::lua::error::{
+  arg_error, bad_field, check_string, check_value, http_error, tag_handler, TableCheckExt,
+};
+use crate::lua::LuaCacheExt;
+use crate::ErrorKind::InvalidToken;
+use jsonwebtoken::errors::ErrorKind as JwtErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use mlua::Value::Nil;
+use mlua::{Function, Lua, LuaSerdeExt, MultiValue, Table};
+use serde::Deserialize;
+
+pub fn create_preload_jwt(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_jwt", |lua, ()| {
+    let jwt_table = lua.create_table()?;
+    jwt_table.raw_set("encode", create_fn_jwt_encode(lua)?)?;
+    jwt_table.raw_set("verify", create_fn_jwt_verify(lua)?)?;
+    jwt_table.raw_set("decode", create_fn_jwt_decode(lua)?)?;
+    Ok(jwt_table)
+  })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+  One(String),
+  Many(Vec<String>),
+}
+
+impl OneOrMany {
+  fn into_vec(self) -> Vec<String> {
+    match self {
+      Self::One(x) => vec![x],
+      Self::Many(x) => x,
+    }
+  }
+}
+
+fn check_options<'lua>(
+  lua: &'lua Lua,
+  value: Option<mlua::Value<'lua>>,
+  pos: usize,
+) -> mlua::Result<Option<Table<'lua>>> {
+  let value = Some(value.unwrap_or(Nil));
+  check_value::<Option<Table>>(lua, value, "table").map_err(tag_handler(lua, pos, 0))
+}
+
+fn check_algorithm(lua: &Lua, options: Option<&Table>) -> mlua::Result<Option<Algorithm>> {
+  let algorithm = match options {
+    Some(options) => options.check_raw_get::<Option<mlua::String>>(lua, "algorithm", "string")?,
+    None => None,
+  };
+  (algorithm.map(|algorithm| {
+    (algorithm.to_str().ok())
+      .and_then(|x| x.parse().ok())
+      .ok_or_else(|| {
+        let msg = format!("unknown algorithm '{}'", algorithm.to_string_lossy());
+        bad_field("algorithm", msg)
+      })
+  }))
+  .transpose()
+}
+
+/// Gets a list of strings from a field that may also be a single string,
+/// e.g. `aud`.
+fn check_one_or_many(lua: &Lua, options: &Table, field: &str) -> mlua::Result<Option<Vec<String>>> {
+  let value: mlua::Value = options.raw_get(field)?;
+  (lua.from_value::<Option<OneOrMany>>(value))
+    .map(|x| x.map(OneOrMany::into_vec))
+    .map_err(|_| bad_field(field, "string or array of strings expected"))
+}
+
+/// Ed25519 secret keys are accepted either in PEM or raw, the latter as
+/// returned by `crypto.ed25519_keygen`.
+fn encoding_key(
+  lua: &Lua,
+  algorithm: Algorithm,
+  key: &[u8],
+  pos: usize,
+) -> mlua::Result<EncodingKey> {
+  use Algorithm::*;
+  let result = match algorithm {
+    HS256 | HS384 | HS512 => Ok(EncodingKey::from_secret(key)),
+    RS256 | RS384 | RS512 | PS256 | PS384 | PS512 => EncodingKey::from_rsa_pem(key),
+    ES256 | ES384 => EncodingKey::from_ec_pem(key),
+    EdDSA if key.len() == 32 => {
+      // PKCS#8 v1 wrapping of the raw key
+      let prefix = b"\x30\x2e\x02\x01\x00\x30\x05\x06\x03\x2b\x65\x70\x04\x22\x04\x20";
+      Ok(EncodingKey::from_ed_der(&[&prefix[..], key].concat()))
+    }
+    EdDSA => EncodingKey::from_ed_pem(key),
+  };
+  result.map_err(|error| key_error(lua, pos, &error))
+}
+
+/// Public keys in PEM are refused as HMAC secrets, so that tokens cannot be
+/// signed with a key that is not secret.
+fn decoding_key(
+  lua: &Lua,
+  algorithm: Algorithm,
+  key: &[u8],
+  pos: usize,
+) -> mlua::Result<DecodingKey> {
+  use Algorithm::*;
+  let result = match algorithm {
+    HS256 | HS384 | HS512 if key.trim_ascii_start().starts_with(b"-----BEGIN") => {
+      return Err(arg_error(lua, pos, "PEM key used as HMAC secret", 0));
+    }
+    HS256 | HS384 | HS512 => Ok(DecodingKey::from_secret(key)),
+    RS256 | RS384 | RS512 | PS256 | PS384 | PS512 => DecodingKey::from_rsa_pem(key),
+    ES256 | ES384 => DecodingKey::from_ec_pem(key),
+    EdDSA if key.len() == 32 => Ok(DecodingKey::from_ed_der(key)),
+    EdDSA => DecodingKey::from_ed_pem(key),
+  };
+  result.map_err(|error| key_error(lua, pos, &error))
+}
+
+fn key_error(lua: &Lua, pos: usize, error: &jsonwebtoken::errors::Error) -> mlua::Error {
+  arg_error(lua, pos, &format!("invalid key ({error})"), 0)
+}
+
+/// Turns errors of verifying a token into `401 invalid token`, except for
+/// those caused by the key, which are argument errors.
+fn token_error(lua: &Lua, error: jsonwebtoken::errors::Error, key_pos: usize) -> mlua::Error {
+  let reason = match error.kind() {
+    JwtErrorKind::InvalidKeyFormat
+    | JwtErrorKind::InvalidRsaKey(_)
+    | JwtErrorKind::InvalidEcdsaKey
+    | JwtErrorKind::Crypto(_) => return key_error(lua, key_pos, &error),
+    JwtErrorKind::InvalidSignature => "invalid signature".into(),
+    JwtErrorKind::ExpiredSignature => "token has expired".into(),
+    JwtErrorKind::ImmatureSignature => "token is not valid yet".into(),
+    JwtErrorKind::InvalidAudience => "invalid audience".into(),
+    JwtErrorKind::InvalidIssuer => "invalid issuer".into(),
+    JwtErrorKind::InvalidSubject => "invalid subject".into(),
+    JwtErrorKind::InvalidAlgorithm => "algorithm not allowed".into(),
+    JwtErrorKind::MissingRequiredClaim(claim) => format!("missing required claim '{claim}'"),
+    _ => "malformed token".into(),
+  };
+  let kind = InvalidToken {
+    reason: reason.into(),
+  };
+  http_error(lua, kind.status(), kind.error(), kind.detail()).unwrap_or_else(|x| x)
+}
+
+fn push_token_data<'lua>(
+  lua: &'lua Lua,
+  data: TokenData<serde_json::Value>,
+) -> mlua::Result<(mlua::Value<'lua>, mlua::Value<'lua>)> {
+  Ok((lua.to_value(&data.claims)?, lua.to_value(&data.header)?))
+}
+
+/// `jwt.encode(claims, key[, options])`
+///
+/// Options are `algorithm` (`HS256` by default) and `kid`.
+fn create_fn_jwt_encode(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let claims: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let options = check_options(lua, args.pop_front(), 3)?;
+
+    let algorithm = check_algorithm(lua, options.as_ref())?.unwrap_or(Algorithm::HS256);
+    let mut header = Header::new(algorithm);
+    if let Some(options) = &options {
+      header.kid = options.check_raw_get(lua, "kid", "string")?;
+    }
+    let claims =
+      serde_json::to_value(&claims).map_err(|error| arg_error(lua, 1, &error.to_string(), 0))?;
+    let key = encoding_key(lua, algorithm, key.as_bytes(), 2)?;
+    jsonwebtoken::encode(&header, &claims, &key).map_err(|error| key_error(lua, 2, &error))
+  })
+}
+
+/// `jwt.verify(token, key[, options])`
+///
+/// Checks the signature and `exp`, `nbf`, `aud` and `iss` claims, returning
+/// the claims and the header. Options are:
+///
+/// - `algorithm`: required, as the key alone does not tell which one it is for;
+/// - `audience`, `issuer`: string or array of strings accepted;
+/// - `required`: array of claims that must be present, `{ "exp" }` by default;
+/// - `leeway`: seconds of clock skew allowed, 60 by default.
+fn create_fn_jwt_verify(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let token = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let options = check_options(lua, args.pop_front(), 3)?;
+
+    let algorithm = check_algorithm(lua, options.as_ref())?
+      .ok_or_else(|| arg_error(lua, 3, "missing option 'algorithm'", 0))?;
+    let mut validation = Validation::new(algorithm);
+    validation.validate_nbf = true;
+    if let Some(options) = &options {
+      if let Some(audience) = check_one_or_many(lua, options, "audience")? {
+        validation.set_audience(&audience);
+      }
+      if let Some(issuer) = check_one_or_many(lua, options, "issuer")? {
+        validation.set_issuer(&issuer);
+      }
+      if let Some(required) =
+        options.check_raw_get::<Option<Vec<String>>>(lua, "required", "table")?
+      {
+        validation.set_required_spec_claims(&required);
+      }
+      if let Some(leeway) = options.check_raw_get(lua, "leeway", "integer")? {
+        validation.leeway = leeway;
+      }
+    }
+
+    let key = decoding_key(lua, algorithm, key.as_bytes(), 2)?;
+    let token = token
+      .to_str()
+      .map_err(|_| token_error(lua, JwtErrorKind::InvalidToken.into(), 2))?;
+    jsonwebtoken::decode(token, &key, &validation)
+      .map_err(|error| token_error(lua, error, 2))
+      .and_then(|data| push_token_data(lua, data))
+  })
+}
+
+/// `jwt.decode(token)`
+///
+/// Returns the claims and the header of a token **without** verifying it,
+/// e.g. for looking up its key by `kid` beforehand.
+fn create_fn_jwt_decode(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let token = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let token = token
+      .to_str()
+      .map_err(|_| token_error(lua, JwtErrorKind::InvalidToken.into(), 1))?;
+    let header = jsonwebtoken::decode_header(token).map_err(|error| token_error(lua, error, 1))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+    jsonwebtoken::decode(token, &DecodingKey::from_secret(&[]), &validation)
+      .map_err(|error| token_error(lua, error, 1))
+      .and_then(|data| push_token_data(lua, data))
+  })
+}
@@ -0,0 +1,261 @@
+use crate::lua::error::{
+  bad_field, check_string, check_value, rt_error, rt_error_fmt, tag_handler, TableCheckExt,
+};
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+use once_cell::sync::OnceCell;
+use sled::{Db, IVec};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use tokio::time::{timeout_at, Instant};
+
+/// Key-value store of a service, opened on first use.
+///
+/// Clones share the same underlying database.
+#[derive(Debug, Clone)]
+pub struct KvStore(Arc<KvStoreInner>);
+
+#[derive(Debug)]
+struct KvStoreInner {
+  path: PathBuf,
+  db: OnceCell<Db>,
+  /// Dropped along with the database, notifying those waiting for it.
+  closed: watch::Sender<()>,
+}
+
+impl KvStore {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self(Arc::new(KvStoreInner {
+      path: path.into(),
+      db: OnceCell::new(),
+      closed: watch::channel(()).0,
+    }))
+  }
+
+  /// Drops this handle and waits for the others to be dropped, so that the
+  /// database is closed.
+  ///
+  /// Gives a handle back if the store is still open at `deadline`.
+  pub(crate) async fn close(self, deadline: Instant) -> Result<(), Self> {
+    let inner = Arc::downgrade(&self.0);
+    let mut closed = self.0.closed.subscribe();
+    drop(self);
+    match timeout_at(deadline, closed.changed()).await {
+      Ok(_) => Ok(()),
+      Err(_) => inner.upgrade().map_or(Ok(()), |x| Err(Self(x))),
+    }
+  }
+
+  /// Runs a blocking operation on the database in background.
+  async fn run<T, F>(&self, f: F) -> mlua::Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Db) -> sled::Result<T> + Send + 'static,
+  {
+    let this = self.0.clone();
+    spawn_blocking(move || f(this.db.get_or_try_init(|| sled::open(&this.path))?))
+      .await
+      .map_err(|x| rt_error_fmt!("background task failed: {x}"))?
+      .map_err(rt_error)
+  }
+}
+
+// Entries are stored as 8 bytes of big-endian expiration time in
+// milliseconds since Unix epoch (0 for never), followed by the value.
+
+fn now_millis() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+fn encode_entry(value: &[u8], ttl: Option<u64>) -> Vec<u8> {
+  let expires_at = ttl.map(|x| now_millis().saturating_add(x)).unwrap_or(0);
+  let mut buf = Vec::with_capacity(8 + value.len());
+  buf.extend_from_slice(&expires_at.to_be_bytes());
+  buf.extend_from_slice(value);
+  buf
+}
+
+/// Returns the value of an entry, or `None` if it has expired.
+fn decode_entry(raw: &[u8]) -> Option<&[u8]> {
+  let (expires_at, value) = raw.split_at(8.min(raw.len()));
+  let expires_at = u64::from_be_bytes(expires_at.try_into().ok()?);
+  (expires_at == 0 || expires_at > now_millis()).then_some(value)
+}
+
+/// Gets the live value of `key`, removing it if expired.
+fn get_live(db: &Db, key: &[u8]) -> sled::Result<(Option<IVec>, Option<IVec>)> {
+  let raw = db.get(key)?;
+  match raw.as_deref().map(decode_entry) {
+    Some(Some(value)) => {
+      let value = IVec::from(value);
+      Ok((raw, Some(value)))
+    }
+    Some(None) => {
+      // Fine if someone else has changed it in the meantime
+      let _ = db.compare_and_swap(key, raw.as_ref(), None as Option<&[u8]>)?;
+      Ok((None, None))
+    }
+    None => Ok((None, None)),
+  }
+}
+
+pub fn create_preload_kv(store: KvStore) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let kv = lua.create_table()?;
+      kv.raw_set("get", create_fn_kv_get(lua, store.clone())?)?;
+      kv.raw_set("set", create_fn_kv_set(lua, store.clone())?)?;
+      kv.raw_set("delete", create_fn_kv_delete(lua, store.clone())?)?;
+      kv.raw_set("scan", create_fn_kv_scan(lua, store.clone())?)?;
+      kv.raw_set("cas", create_fn_kv_cas(lua, store.clone())?)?;
+      Ok(kv)
+    })
+  }
+}
+
+fn check_ttl(lua: &Lua, options: Option<mlua::Value>, pos: usize) -> mlua::Result<Option<u64>> {
+  let options = Some(options.unwrap_or(Nil));
+  let options =
+    check_value::<Option<Table>>(lua, options, "table").map_err(tag_handler(lua, pos, 1))?;
+  let ttl = options
+    .map(|x| x.check_raw_get::<Option<u64>>(lua, "ttl", "integer"))
+    .transpose()?
+    .flatten();
+  match ttl {
+    Some(0) => Err(bad_field("ttl", "must be positive")),
+    ttl => Ok(ttl),
+  }
+}
+
+fn create_fn_kv_get(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let key = key.as_bytes().to_vec();
+      let (_, value) = store.run(move |db| get_live(db, &key)).await?;
+      value.map(|x| lua.create_string(&x)).transpose()
+    }
+  })
+}
+
+fn create_fn_kv_set(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let value = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let ttl = check_ttl(lua, args.pop_front(), 3)?;
+      let key = key.as_bytes().to_vec();
+      let entry = encode_entry(value.as_bytes(), ttl);
+      store.run(move |db| db.insert(key, entry).map(drop)).await
+    }
+  })
+}
+
+fn create_fn_kv_delete(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let key = key.as_bytes().to_vec();
+      let old = (store.run(move |db| db.remove(key)).await?)
+        .and_then(|x| decode_entry(&x).map(|x| lua.create_string(x)))
+        .transpose()?;
+      Ok(old)
+    }
+  })
+}
+
+fn create_fn_kv_scan(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let prefix = (args.pop_front())
+        .map(|x| check_string(lua, Some(x)))
+        .transpose()
+        .map_err(tag_handler(lua, 1, 1))?
+        .map(|x| x.as_bytes().to_vec())
+        .unwrap_or_default();
+      let iter = store.run(move |db| Ok(db.scan_prefix(prefix))).await?;
+      let iter = Rc::new(RefCell::new(Some(iter)));
+
+      lua.create_async_function(move |lua, ()| {
+        let iter = iter.clone();
+        async move {
+          let mut inner = match iter.borrow_mut().take() {
+            Some(inner) => inner,
+            None => return lua.pack_multi(()),
+          };
+          let (inner, next) = spawn_blocking(move || {
+            let next = loop {
+              match inner.next() {
+                Some(Ok((k, raw))) => match decode_entry(&raw) {
+                  Some(value) => break Ok(Some((k, IVec::from(value)))),
+                  None => continue,
+                },
+                Some(Err(error)) => break Err(error),
+                None => break Ok(None),
+              }
+            };
+            (inner, next)
+          })
+          .await
+          .map_err(|x| rt_error_fmt!("background task failed: {x}"))?;
+          match next.map_err(rt_error)? {
+            Some((k, v)) => {
+              *iter.borrow_mut() = Some(inner);
+              lua.pack_multi((lua.create_string(&k)?, lua.create_string(&v)?))
+            }
+            None => lua.pack_multi(()),
+          }
+        }
+      })
+    }
+  })
+}
+
+fn create_fn_kv_cas(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let old = check_value::<Option<mlua::String>>(
+        lua,
+        Some(args.pop_front().unwrap_or(Nil)),
+        "string or nil",
+      )
+      .map_err(tag_handler(lua, 2, 1))?;
+      let new = check_value::<Option<mlua::String>>(
+        lua,
+        Some(args.pop_front().unwrap_or(Nil)),
+        "string or nil",
+      )
+      .map_err(tag_handler(lua, 3, 1))?;
+      let ttl = check_ttl(lua, args.pop_front(), 4)?;
+
+      let key = key.as_bytes().to_vec();
+      let old = old.map(|x| x.as_bytes().to_vec());
+      let new = new.map(|x| encode_entry(x.as_bytes(), ttl));
+      store
+        .run(move |db| loop {
+          let (raw, current) = get_live(db, &key)?;
+          if current.as_deref() != old.as_deref() {
+            break Ok(false);
+          }
+          // Retry if the entry is changed between reading and swapping
+          if db.compare_and_swap(&key, raw, new.clone())?.is_ok() {
+            break Ok(true);
+          }
+        })
+        .await
+    }
+  })
+}
@@ -1,6 +1,8 @@
-use mlua::Value::Nil;
+use crate::lua::error::{check_string, tag_handler};
 use mlua::{Function, Lua, MultiValue, Table};
 use paste::paste;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 fn apply_whitelist<'lua>(
   from: Table<'lua>,
@@ -70,20 +72,24 @@ create_whitelist_preloads! {
   ];
 }
 
-pub fn create_preload_os(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_function(move |lua, ()| {
-    let os = lua.create_table()?;
-    apply_whitelist(lua.globals().raw_get("os")?, os.clone(), [
-      "clock", "difftime", "time",
-    ])?;
-    os.raw_set("getenv", create_fn_os_getenv(lua)?)?;
-    Ok(os)
-  })
+pub fn create_preload_os(
+  env: Arc<HashMap<String, String>>,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let os = lua.create_table()?;
+      apply_whitelist(lua.globals().raw_get("os")?, os.clone(), [
+        "clock", "difftime", "time",
+      ])?;
+      os.raw_set("getenv", create_fn_os_getenv(lua, env.clone())?)?;
+      Ok(os)
+    })
+  }
 }
 
-fn create_fn_os_getenv(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_function(|_lua, _args: MultiValue| {
-    // TODO: read env from config file
-    Ok(Nil)
+fn create_fn_os_getenv(lua: &Lua, env: Arc<HashMap<String, String>>) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(name.to_str().ok().and_then(|x| env.get(x)).cloned())
   })
 }
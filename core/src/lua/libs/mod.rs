@@ -1,7 +1,12 @@
 pub mod crypto;
+pub mod encoding;
 pub mod fs;
 pub mod http;
 pub mod json;
+pub mod jwt;
+pub mod kv;
 pub mod lua_std;
+pub mod queue;
 pub mod rand;
+pub mod sql;
 pub mod stream;
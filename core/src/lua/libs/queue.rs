@@ -0,0 +1,54 @@
+use crate::lua::error::{
+  arg_error, check_string, check_value, rt_error, tag_handler, TableCheckExt,
+};
+use crate::queue::{JobQueue, DEFAULT_MAX_RETRIES};
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+
+pub fn create_preload_queue(queue: JobQueue) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let queue_table = lua.create_table()?;
+      queue_table.raw_set("push", create_fn_queue_push(lua, queue.clone())?)?;
+      Ok(queue_table)
+    })
+  }
+}
+
+/// Pushes a job to the service's persistent queue, to be run by the worker of
+/// the given name registered with `abel.worker`.
+///
+/// Returns the job's ID.
+fn create_fn_queue_push(lua: &Lua, queue: JobQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let worker = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let payload = args.pop_front().unwrap_or(Nil);
+      let payload =
+        serde_json::to_value(&payload).map_err(|error| arg_error(lua, 2, &error.to_string(), 1))?;
+
+      let options = Some(args.pop_front().unwrap_or(Nil));
+      let options =
+        check_value::<Option<Table>>(lua, options, "table").map_err(tag_handler(lua, 3, 1))?;
+      let (delay, max_retries) = match options {
+        Some(options) => (
+          options.check_raw_get::<Option<u64>>(lua, "delay", "integer")?,
+          options.check_raw_get::<Option<u32>>(lua, "max_retries", "integer")?,
+        ),
+        None => (None, None),
+      };
+
+      let id = queue
+        .push(
+          worker.to_str()?,
+          payload,
+          delay.unwrap_or(0),
+          max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+        )
+        .await
+        .map_err(rt_error)?;
+      Ok(id)
+    }
+  })
+}
@@ -1,7 +1,14 @@
-use crate::lua::error::{arg_error, check_integer, check_userdata_mut, tag_handler};
+use crate::lua::error::{arg_error, check_integer, check_userdata_mut, check_value, tag_handler};
 use crate::lua::LuaCacheExt;
-use mlua::{Function, Lua, MultiValue, UserData};
-use rand::{thread_rng, Rng, RngCore};
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table, UserData};
+use rand::rngs::{OsRng, StdRng};
+use rand::{thread_rng, Rng, RngCore, SeedableRng};
+use uuid::Uuid;
+
+/// Upper bound of `rng:bytes(n)`, as the buffer is allocated outside of the
+/// service's Lua memory quota.
+const MAX_BYTES_LEN: usize = 4 * 1024 * 1024;
 
 struct LuaRng(Box<dyn RngCore>);
 
@@ -25,6 +32,49 @@ impl UserData for LuaRng {
         this.with_borrowed_mut(|r| Ok(r.0.gen_range(low..=high)))
       }
     });
+
+    methods.add_function("bytes", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "RNG").map_err(tag_handler(lua, 1, 0))?;
+      let len = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+      let len = (usize::try_from(len).ok())
+        .filter(|x| *x <= MAX_BYTES_LEN)
+        .ok_or_else(|| {
+          let msg = format!("length must be between 0 and {MAX_BYTES_LEN}");
+          arg_error(lua, 2, &msg, 0)
+        })?;
+      let mut bytes = vec![0; len];
+      this.with_borrowed_mut(|r| r.0.fill_bytes(&mut bytes));
+      lua.create_string(&bytes)
+    });
+
+    // Shuffles the sequence part of the table in place.
+    methods.add_function("shuffle", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "RNG").map_err(tag_handler(lua, 1, 0))?;
+      let table: Table =
+        check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 2, 0))?;
+      for i in (2..=table.raw_len()).rev() {
+        let j = this.with_borrowed_mut(|r| r.0.gen_range(1..=i));
+        let (a, b): (mlua::Value, mlua::Value) = (table.raw_get(i)?, table.raw_get(j)?);
+        table.raw_set(i, b)?;
+        table.raw_set(j, a)?;
+      }
+      Ok(())
+    });
+
+    // Returns a random item of the sequence part of the table, or nil if it
+    // is empty.
+    methods.add_function("choose", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "RNG").map_err(tag_handler(lua, 1, 0))?;
+      let table: Table =
+        check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 2, 0))?;
+      match table.raw_len() {
+        0 => Ok(Nil),
+        len => table.raw_get(this.with_borrowed_mut(|r| r.0.gen_range(1..=len))),
+      }
+    });
   }
 }
 
@@ -37,6 +87,35 @@ pub fn create_preload_rand(lua: &Lua) -> mlua::Result<Function> {
         lua.create_userdata(LuaRng(Box::new(thread_rng())))
       })?,
     )?;
+    rand_table.raw_set(
+      "OsRng",
+      lua.create_cached_value("abel:rand.OsRng", || {
+        lua.create_userdata(LuaRng(Box::new(OsRng)))
+      })?,
+    )?;
+    rand_table.raw_set("StdRng", create_fn_std_rng(lua)?)?;
+    rand_table.raw_set("uuid_v4", create_fn_uuid_v4(lua)?)?;
     Ok(rand_table)
   })
 }
+
+/// `rand.StdRng([seed])`
+///
+/// Generates the same sequence for the same seed, and is seeded from the OS
+/// if no seed is given.
+fn create_fn_std_rng(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let rng = match args.pop_front() {
+      None | Some(Nil) => StdRng::from_entropy(),
+      seed => {
+        let seed = check_integer(seed).map_err(tag_handler(lua, 1, 0))?;
+        StdRng::seed_from_u64(seed as u64)
+      }
+    };
+    Ok(LuaRng(Box::new(rng)))
+  })
+}
+
+fn create_fn_uuid_v4(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|_lua, ()| Ok(Uuid::new_v4().to_string()))
+}
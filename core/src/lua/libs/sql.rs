@@ -0,0 +1,712 @@
+use super::stream::create_table_stream;
+use crate::lua::error::{
+  check_string, check_userdata, check_userdata_mut, rt_error, rt_error_fmt, tag_error, tag_handler,
+};
+use crate::task::{CpuTime, TaskContext, TimeoutError};
+use mlua::Value::Nil;
+use mlua::{
+  AnyUserData, ExternalError, Function, Lua, MultiValue, UserData, UserDataFields, UserDataMethods,
+};
+use ouroboros::self_referencing;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
+use rusqlite::types::{ToSql, Value};
+use rusqlite::{params_from_iter, Connection, Rows, Statement};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::spawn_blocking;
+
+/// Database file in the service's local storage.
+const DB_FILE: &str = "database.sqlite";
+
+#[derive(Debug, Clone)]
+struct SqlDatabase {
+  path: Arc<Path>,
+  max_rows: usize,
+}
+
+impl SqlDatabase {
+  /// Opens a connection confined to the service's own database.
+  ///
+  /// `ATTACH` and `DETACH` would let a service reach any SQLite file the
+  /// server can, so they are denied, and attaching is disabled altogether.
+  /// `VACUUM`, including `VACUUM INTO`, is therefore unavailable as well, as
+  /// it attaches its target internally.
+  fn open(&self) -> mlua::Result<Connection> {
+    let conn = Connection::open(&self.path).map_err(rt_error)?;
+    conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+    conn.authorizer(Some(authorize));
+    conn
+      .busy_timeout(Duration::from_secs(5))
+      .map_err(rt_error)?;
+    conn
+      .pragma_update(None, "journal_mode", "WAL")
+      .map_err(rt_error)?;
+    Ok(conn)
+  }
+}
+
+fn authorize(ctx: AuthContext) -> Authorization {
+  match ctx.action {
+    AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+    _ => Authorization::Allow,
+  }
+}
+
+/// Number of SQLite VM instructions between checks of the CPU time budget.
+const PROGRESS_OPS: c_int = 1000;
+
+/// Maximum number of rows read from the database at a time.
+const BATCH_ROWS: usize = 64;
+
+/// Point in time when running statements are interrupted.
+#[derive(Debug, Clone, Copy)]
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+  fn apply(self, conn: &Connection) {
+    conn.progress_handler(PROGRESS_OPS, self.0.map(|x| move || Instant::now() >= x));
+  }
+}
+
+/// Runs `f` in background, charging the time it takes to the current task's
+/// CPU time budget.
+///
+/// Statements are interrupted once the budget is used up, so that runaway
+/// queries neither escape it nor hold on to a thread of the blocking pool.
+async fn run_blocking<T: Send + 'static>(
+  lua: &Lua,
+  f: impl FnOnce(Deadline) -> T + Send + 'static,
+) -> mlua::Result<T> {
+  let cpu_time = TaskContext::get_current(lua).map(|x| x.cpu_time.clone());
+  spawn_blocking(move || {
+    let t = Instant::now();
+    let deadline = (cpu_time.as_ref()).map(|x| {
+      let CpuTime { used, limit } = *x.lock();
+      t + limit.saturating_sub(used)
+    });
+    let result = f(Deadline(deadline));
+    if let Some(cpu_time) = cpu_time {
+      cpu_time.lock().used += t.elapsed();
+    }
+    result
+  })
+  .await
+  .map_err(|x| rt_error_fmt!("background task failed: {x}"))
+}
+
+// Note that "lsp" stands for "local storage path".
+pub fn create_preload_sql(
+  lsp: Arc<Path>,
+  max_rows: usize,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  move |lua| {
+    let db = SqlDatabase {
+      path: lsp.join(DB_FILE).into(),
+      max_rows,
+    };
+    lua.create_function(move |lua, ()| {
+      let sql = lua.create_table()?;
+      sql.raw_set("execute", create_fn_sql_execute(lua, db.clone())?)?;
+      sql.raw_set("query", create_fn_sql_query(lua, db.clone())?)?;
+      sql.raw_set("begin", create_fn_sql_begin(lua, db.clone())?)?;
+      Ok(sql)
+    })
+  }
+}
+
+enum Params {
+  Positional(Vec<Value>),
+  Named(Vec<(String, Value)>),
+}
+
+impl Params {
+  /// Parses either a single table of named parameters, or positional ones.
+  fn from_lua(lua: &Lua, args: MultiValue, pos: usize) -> mlua::Result<Self> {
+    let args = args.into_vec();
+    if let [mlua::Value::Table(t)] = &*args {
+      let mut named = Vec::new();
+      for kv in t.clone().pairs::<mlua::Value, mlua::Value>() {
+        let (k, v) = kv?;
+        let k = match k {
+          mlua::Value::String(k) => k.to_str()?.to_string(),
+          k => {
+            let msg = format!("parameter names must be strings, got {}", k.type_name());
+            return Err(rt_error(msg));
+          }
+        };
+        let k = if k.starts_with([':', '@', '$']) {
+          k
+        } else {
+          format!(":{k}")
+        };
+        named.push((k, to_sql_value(lua, v, pos)?));
+      }
+      Ok(Self::Named(named))
+    } else {
+      let positional = (args.into_iter().enumerate())
+        .map(|(i, x)| to_sql_value(lua, x, pos + i))
+        .collect::<mlua::Result<_>>()?;
+      Ok(Self::Positional(positional))
+    }
+  }
+
+  fn execute(&self, stmt: &mut Statement) -> rusqlite::Result<usize> {
+    match self {
+      Self::Positional(x) => stmt.execute(params_from_iter(x)),
+      Self::Named(x) => stmt.execute(&*named_refs(x)),
+    }
+  }
+
+  fn query<'a>(&self, stmt: &'a mut Statement) -> rusqlite::Result<rusqlite::Rows<'a>> {
+    match self {
+      Self::Positional(x) => stmt.query(params_from_iter(x)),
+      Self::Named(x) => stmt.query(&*named_refs(x)),
+    }
+  }
+}
+
+fn named_refs(params: &[(String, Value)]) -> Vec<(&str, &dyn ToSql)> {
+  (params.iter())
+    .map(|(k, v)| (&**k, v as &dyn ToSql))
+    .collect()
+}
+
+fn to_sql_value(lua: &Lua, value: mlua::Value, pos: usize) -> mlua::Result<Value> {
+  let value = match value {
+    Nil => Value::Null,
+    mlua::Value::Boolean(x) => Value::Integer(x as _),
+    mlua::Value::Integer(x) => Value::Integer(x),
+    mlua::Value::Number(x) => Value::Real(x),
+    mlua::Value::String(x) => match x.to_str() {
+      Ok(x) => Value::Text(x.into()),
+      Err(_) => Value::Blob(x.as_bytes().into()),
+    },
+    x => {
+      let expected = "nil, boolean, number or string";
+      return Err(tag_error(lua, pos, expected, x.type_name(), 1));
+    }
+  };
+  Ok(value)
+}
+
+fn from_sql_value(lua: &Lua, value: Value) -> mlua::Result<mlua::Value> {
+  let value = match value {
+    Value::Null => Nil,
+    Value::Integer(x) => mlua::Value::Integer(x),
+    Value::Real(x) => mlua::Value::Number(x),
+    Value::Text(x) => mlua::Value::String(lua.create_string(&x)?),
+    Value::Blob(x) => mlua::Value::String(lua.create_string(&x)?),
+  };
+  Ok(value)
+}
+
+/// Runs a statement that does not return rows.
+///
+/// The statement is rolled back if it changes more rows than allowed.
+fn execute(conn: &Connection, sql: &str, params: &Params, max_rows: usize) -> mlua::Result<usize> {
+  conn.execute_batch("SAVEPOINT abel_sql").map_err(rt_error)?;
+  let result = conn
+    .prepare(sql)
+    .and_then(|mut stmt| params.execute(&mut stmt))
+    .map_err(rt_error)
+    .and_then(|changes| {
+      if changes > max_rows {
+        Err(rt_error_fmt!(
+          "statement changed {changes} rows, exceeding the limit of {max_rows}"
+        ))
+      } else {
+        Ok(changes)
+      }
+    });
+  let finish = if result.is_ok() {
+    "RELEASE abel_sql"
+  } else {
+    "ROLLBACK TO abel_sql; RELEASE abel_sql"
+  };
+  conn.execute_batch(finish).map_err(rt_error)?;
+  result
+}
+
+/// A query being stepped through, along with the connection it runs on.
+#[self_referencing]
+struct Cursor {
+  conn: Connection,
+  #[borrows(conn)]
+  #[not_covariant]
+  query: Query<'this>,
+}
+
+#[self_referencing]
+struct Query<'conn> {
+  stmt: Statement<'conn>,
+  #[borrows(mut stmt)]
+  #[not_covariant]
+  rows: Rows<'this>,
+}
+
+// SAFETY: the statement and its rows only borrow the connection they are moved
+// along with, and `Connection` itself is `Send`.
+unsafe impl Send for Cursor {}
+
+impl Cursor {
+  fn columns(&self) -> Vec<String> {
+    self.with_query(|query| {
+      query.with_rows(|rows| {
+        (rows.as_ref().into_iter())
+          .flat_map(|x| x.column_names())
+          .map(String::from)
+          .collect()
+      })
+    })
+  }
+
+  /// Reads at most `max` rows.
+  fn read(&mut self, max: usize) -> mlua::Result<Vec<Vec<Value>>> {
+    self.with_query_mut(|query| {
+      query.with_rows_mut(|rows| {
+        let mut batch = Vec::new();
+        while batch.len() < max {
+          let Some(row) = rows.next().map_err(rt_error)? else {
+            break;
+          };
+          let row = (0..row.as_ref().column_count())
+            .map(|i| row.get::<_, Value>(i))
+            .collect::<rusqlite::Result<_>>()
+            .map_err(rt_error)?;
+          batch.push(row);
+        }
+        Ok(batch)
+      })
+    })
+  }
+}
+
+/// Connection of a statement or transaction, shared with rows of its queries.
+enum ConnState {
+  Unopened(SqlDatabase),
+  Idle(Connection),
+  /// Reading rows of a query, numbered to tell it from later ones.
+  Reading(u64, Cursor),
+  /// Taken by a statement running in background.
+  Busy,
+  Finished,
+}
+
+impl ConnState {
+  /// Gets the connection, opening it first or closing rows still being read.
+  fn into_conn(self) -> mlua::Result<Connection> {
+    match self {
+      Self::Unopened(db) => db.open(),
+      Self::Idle(conn) => Ok(conn),
+      Self::Reading(_, cursor) => Ok(cursor.into_heads().conn),
+      Self::Busy | Self::Finished => Err(rt_error("transaction is finished or in use")),
+    }
+  }
+
+  /// Runs `f` with the connection, keeping it afterwards.
+  fn with_conn<T>(
+    self,
+    deadline: Deadline,
+    f: impl FnOnce(&Connection) -> mlua::Result<T>,
+  ) -> (Self, mlua::Result<T>) {
+    match self.into_conn() {
+      Ok(conn) => {
+        deadline.apply(&conn);
+        let result = f(&conn);
+        (Self::Idle(conn), result)
+      }
+      Err(error) => (Self::Finished, Err(error)),
+    }
+  }
+}
+
+struct SharedConn {
+  state: RefCell<ConnState>,
+  queries: Cell<u64>,
+}
+
+impl SharedConn {
+  fn new(state: ConnState) -> Rc<Self> {
+    Rc::new(Self {
+      state: RefCell::new(state),
+      queries: Cell::new(0),
+    })
+  }
+
+  /// Runs `f` in background with the connection, putting back what it returns
+  /// unless it is closed in the meantime.
+  async fn run<T: Send + 'static>(
+    &self,
+    lua: &Lua,
+    f: impl FnOnce(ConnState, Deadline) -> (ConnState, mlua::Result<T>) + Send + 'static,
+  ) -> mlua::Result<T> {
+    let state = match self.state.replace(ConnState::Busy) {
+      state @ (ConnState::Busy | ConnState::Finished) => {
+        self.state.replace(state);
+        return Err(rt_error("transaction is finished or in use"));
+      }
+      state => state,
+    };
+    let (state, result) = run_blocking(lua, move |deadline| f(state, deadline)).await?;
+    let busy = matches!(*self.state.borrow(), ConnState::Busy);
+    if busy {
+      self.state.replace(state);
+    }
+    if (TaskContext::get_current(lua)).is_some_and(|x| x.cpu_time.lock().is_exceeded()) {
+      return Err(TimeoutError(()).to_lua_err());
+    }
+    result
+  }
+
+  fn close(&self) {
+    self.state.replace(ConnState::Finished);
+  }
+}
+
+/// Rows of a query, read from the database in batches as they are consumed.
+///
+/// Reading more rows than the service's `sql_max_rows` raises an error. Rows
+/// of a transaction's query are closed when it runs another statement.
+///
+/// - Stream: `rows:read() -> { [column: string]: any }?`
+/// - `rows:close()` stops reading rows; also called when closed as a
+///   to-be-closed variable.
+pub struct LuaRows {
+  columns: Rc<[String]>,
+  batch: VecDeque<Vec<Value>>,
+  conn: Rc<SharedConn>,
+  /// Number of the query in its connection, or `None` if no more rows are
+  /// read from the database.
+  query: Option<u64>,
+  /// Whether the connection belongs to a transaction, and is kept after rows
+  /// are read.
+  in_transaction: bool,
+  read: usize,
+  max_rows: usize,
+}
+
+impl LuaRows {
+  /// Runs a query, reading its first batch of rows.
+  async fn query(
+    lua: &Lua,
+    conn: Rc<SharedConn>,
+    in_transaction: bool,
+    sql: String,
+    params: Params,
+    max_rows: usize,
+  ) -> mlua::Result<Self> {
+    let query = conn.queries.get() + 1;
+    conn.queries.set(query);
+    let (columns, batch, reading) = conn
+      .run(lua, move |state, deadline| {
+        let conn = match state.into_conn() {
+          Ok(conn) => conn,
+          Err(error) => return (ConnState::Finished, Err(error)),
+        };
+        deadline.apply(&conn);
+        let cursor = CursorTryBuilder {
+          conn,
+          query_builder: |conn| {
+            QueryTryBuilder {
+              stmt: conn.prepare(&sql)?,
+              rows_builder: |stmt| params.query(stmt),
+            }
+            .try_build()
+          },
+        }
+        .try_build_or_recover();
+        let cursor = match cursor {
+          Ok(cursor) => cursor,
+          Err((error, heads)) => {
+            let state = Self::finish(ConnState::Idle(heads.conn), in_transaction);
+            return (state, Err(rt_error(error)));
+          }
+        };
+        let columns = cursor.columns();
+        let (state, result) = Self::read_batch(query, cursor, 0, max_rows, in_transaction);
+        let reading = matches!(state, ConnState::Reading(..));
+        (state, result.map(|batch| (columns, batch, reading)))
+      })
+      .await?;
+    Ok(Self {
+      columns: columns.into(),
+      read: batch.len(),
+      batch: batch.into(),
+      conn,
+      query: reading.then_some(query),
+      in_transaction,
+      max_rows,
+    })
+  }
+
+  /// Reads the next batch of rows, closing the cursor once all rows are read,
+  /// or more than allowed.
+  fn read_batch(
+    query: u64,
+    mut cursor: Cursor,
+    read: usize,
+    max_rows: usize,
+    in_transaction: bool,
+  ) -> (ConnState, mlua::Result<Vec<Vec<Value>>>) {
+    let max = BATCH_ROWS.min(max_rows.saturating_sub(read).saturating_add(1));
+    let result = cursor.read(max);
+    let state = match &result {
+      Ok(batch) if batch.len() == max && read + batch.len() <= max_rows => {
+        return (ConnState::Reading(query, cursor), result);
+      }
+      _ => ConnState::Idle(cursor.into_heads().conn),
+    };
+    let result = result.and_then(|batch| {
+      if read + batch.len() > max_rows {
+        Err(rt_error_fmt!("query returned more than {max_rows} rows"))
+      } else {
+        Ok(batch)
+      }
+    });
+    (Self::finish(state, in_transaction), result)
+  }
+
+  /// Closes the connection once rows are read, unless it is a transaction's.
+  fn finish(state: ConnState, in_transaction: bool) -> ConnState {
+    if in_transaction {
+      state
+    } else {
+      ConnState::Finished
+    }
+  }
+
+  async fn next(this: &AnyUserData<'_>, lua: &Lua) -> mlua::Result<Option<Vec<Value>>> {
+    let (conn, read, max_rows, in_transaction) = {
+      let mut this = this.borrow_mut::<Self>()?;
+      if let Some(query) = this.query {
+        if !matches!(*this.conn.state.borrow(), ConnState::Reading(x, _) if x == query) {
+          this.close();
+          return Err(rt_error("rows are closed"));
+        }
+      }
+      if let Some(row) = this.batch.pop_front() {
+        return Ok(Some(row));
+      }
+      if this.query.is_none() {
+        return Ok(None);
+      }
+      let this = &*this;
+      (
+        this.conn.clone(),
+        this.read,
+        this.max_rows,
+        this.in_transaction,
+      )
+    };
+    let result = conn
+      .run(lua, move |state, deadline| match state {
+        ConnState::Reading(query, cursor) => {
+          deadline.apply(cursor.borrow_conn());
+          let (state, result) = Self::read_batch(query, cursor, read, max_rows, in_transaction);
+          let reading = matches!(state, ConnState::Reading(..));
+          (state, result.map(|batch| (batch, reading)))
+        }
+        state => (state, Err(rt_error("rows are closed"))),
+      })
+      .await;
+    let mut this = this.borrow_mut::<Self>()?;
+    match result {
+      Ok((batch, reading)) => {
+        this.read += batch.len();
+        this.batch = batch.into();
+        if !reading {
+          this.query = None;
+        }
+        Ok(this.batch.pop_front())
+      }
+      Err(error) => {
+        this.query = None;
+        Err(error)
+      }
+    }
+  }
+
+  fn close(&mut self) {
+    if let Some(query) = self.query.take() {
+      let mut state = self.conn.state.borrow_mut();
+      if matches!(*state, ConnState::Reading(x, _) if x == query) {
+        let old = std::mem::replace(&mut *state, ConnState::Busy);
+        *state = match old {
+          ConnState::Reading(_, cursor) if self.in_transaction => {
+            ConnState::Idle(cursor.into_heads().conn)
+          }
+          _ => ConnState::Finished,
+        };
+      }
+    }
+    self.batch.clear();
+  }
+}
+
+impl UserData for LuaRows {
+  fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+    fields.add_meta_field_with("__index", create_table_stream);
+  }
+
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      this.borrow_mut::<Self>()?.close();
+      Ok(())
+    });
+
+    methods.add_async_function("read", |lua, mut args: MultiValue| async move {
+      let this = check_userdata::<Self>(args.pop_front(), "rows")
+        .map_err(tag_handler(lua, 1, 0))?
+        .into_any();
+      let columns = this.borrow::<Self>()?.columns.clone();
+      match Self::next(&this, lua).await? {
+        Some(row) => {
+          let table = lua.create_table_with_capacity(0, row.len() as _)?;
+          for (column, value) in columns.iter().zip(row) {
+            table.raw_set(&**column, from_sql_value(lua, value)?)?;
+          }
+          Ok(mlua::Value::Table(table))
+        }
+        None => Ok(Nil),
+      }
+    });
+
+    methods.add_function("close", |lua, mut args: MultiValue| {
+      check_userdata_mut::<Self>(args.pop_front(), "rows")
+        .map_err(tag_handler(lua, 1, 0))?
+        .with_borrowed_mut(|x| x.close());
+      Ok(())
+    });
+  }
+}
+
+fn check_sql_args<'lua>(
+  lua: &'lua Lua,
+  mut args: MultiValue<'lua>,
+  pos: usize,
+) -> mlua::Result<(String, Params)> {
+  let sql = check_string(lua, args.pop_front()).map_err(tag_handler(lua, pos, 1))?;
+  let sql = sql.to_str()?.to_string();
+  let params = Params::from_lua(lua, args, pos + 1)?;
+  Ok((sql, params))
+}
+
+fn create_fn_sql_execute(lua: &Lua, db: SqlDatabase) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, args: MultiValue| {
+    let db = db.clone();
+    async move {
+      let (sql, params) = check_sql_args(lua, args, 1)?;
+      let max_rows = db.max_rows;
+      let conn = SharedConn::new(ConnState::Unopened(db));
+      conn
+        .run(lua, move |state, deadline| {
+          let (_, result) =
+            state.with_conn(deadline, |conn| execute(conn, &sql, &params, max_rows));
+          (ConnState::Finished, result)
+        })
+        .await
+    }
+  })
+}
+
+fn create_fn_sql_query(lua: &Lua, db: SqlDatabase) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, args: MultiValue| {
+    let db = db.clone();
+    async move {
+      let (sql, params) = check_sql_args(lua, args, 1)?;
+      let max_rows = db.max_rows;
+      let conn = SharedConn::new(ConnState::Unopened(db));
+      LuaRows::query(lua, conn, false, sql, params, max_rows).await
+    }
+  })
+}
+
+fn create_fn_sql_begin(lua: &Lua, db: SqlDatabase) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, ()| {
+    let db = db.clone();
+    async move {
+      let max_rows = db.max_rows;
+      let conn = SharedConn::new(ConnState::Unopened(db));
+      conn
+        .run(lua, |state, deadline| {
+          state.with_conn(deadline, |conn| {
+            conn.execute_batch("BEGIN IMMEDIATE").map_err(rt_error)
+          })
+        })
+        .await?;
+      let tx = lua.create_userdata(LuaTransaction { conn, max_rows })?;
+      TaskContext::register(lua, tx.clone())?;
+      Ok(tx)
+    }
+  })
+}
+
+/// A transaction holding the database's write lock until it finishes.
+///
+/// It is rolled back if not committed when closed.
+pub struct LuaTransaction {
+  conn: Rc<SharedConn>,
+  max_rows: usize,
+}
+
+impl UserData for LuaTransaction {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<(Rc<SharedConn>, usize)> {
+      check_userdata::<LuaTransaction>(value, "transaction")
+        .map(|x| x.with_borrowed(|x| (x.conn.clone(), x.max_rows)))
+        .map_err(tag_handler(lua, 1, 1))
+    }
+
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      // SQLite rolls back unfinished transactions when the connection closes
+      this.borrow::<Self>()?.conn.close();
+      Ok(())
+    });
+
+    methods.add_async_function("execute", |lua, mut args: MultiValue| async move {
+      let (conn, max_rows) = check_self(lua, args.pop_front())?;
+      let (sql, params) = check_sql_args(lua, args, 2)?;
+      conn
+        .run(lua, move |state, deadline| {
+          state.with_conn(deadline, |conn| execute(conn, &sql, &params, max_rows))
+        })
+        .await
+    });
+
+    methods.add_async_function("query", |lua, mut args: MultiValue| async move {
+      let (conn, max_rows) = check_self(lua, args.pop_front())?;
+      let (sql, params) = check_sql_args(lua, args, 2)?;
+      LuaRows::query(lua, conn, true, sql, params, max_rows).await
+    });
+
+    methods.add_async_function("commit", |lua, mut args: MultiValue| async move {
+      let (conn, _) = check_self(lua, args.pop_front())?;
+      conn
+        .run(lua, |state, deadline| {
+          let (_, result) = state.with_conn(deadline, |conn| {
+            conn.execute_batch("COMMIT").map_err(rt_error)
+          });
+          (ConnState::Finished, result)
+        })
+        .await
+    });
+
+    methods.add_async_function("rollback", |lua, mut args: MultiValue| async move {
+      let (conn, _) = check_self(lua, args.pop_front())?;
+      conn
+        .run(lua, |state, deadline| {
+          let (_, result) = state.with_conn(deadline, |conn| {
+            conn.execute_batch("ROLLBACK").map_err(rt_error)
+          });
+          (ConnState::Finished, result)
+        })
+        .await
+    });
+  }
+}
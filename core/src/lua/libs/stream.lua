@@ -69,11 +69,15 @@ end
 function stream.pipe_through(st, tr)
   check_stream(st)
   check_transform(tr)
+  local finished = false
   return setmetatable({
     read = function(_)
       local item = st:read()
       if item then
         return tr:transform(item)
+      elseif not finished and tr.finish then
+        finished = true
+        return tr:finish()
       end
     end
   }, { __index = st })
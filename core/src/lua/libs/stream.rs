@@ -1,3 +1,4 @@
+use super::http::{resolve_body_error, BodyTooLarge};
 use super::json::create_fn_json_parse;
 use crate::lua::error::{check_userdata_mut, rt_error, tag_handler};
 use crate::lua::LuaCacheExt;
@@ -12,7 +13,8 @@ use tokio_util::io::ReaderStream;
 
 /// - Stream: `stream<T>:read() -> T?`
 /// - Sink: `sink<T>:write(item: T)`
-/// - Transform: `transform<T, U>:transform(item: T) -> U`
+/// - Transform: `transform<T, U>:transform(item: T) -> U`, and optionally
+///   `transform<T, U>:finish() -> U?` for output left when the stream ends
 pub fn create_preload_stream(lua: &Lua) -> mlua::Result<mlua::Function> {
   lua.create_cached_function("abel:preload_stream", |lua, ()| create_table_stream(lua))
 }
@@ -53,7 +55,16 @@ impl ByteStream {
 
 impl From<Body> for ByteStream {
   fn from(body: Body) -> Self {
-    Self(body.map_err(rt_error).boxed())
+    Self(body.map_err(body_error).boxed())
+  }
+}
+
+/// Keeps errors of exceeding body size limit apart from other ones.
+fn body_error(error: hyper::Error) -> mlua::Error {
+  let source = std::error::Error::source(&error);
+  match source.and_then(|x| x.downcast_ref::<BodyTooLarge>()) {
+    Some(x) => mlua::Error::external(*x),
+    None => rt_error(error),
   }
 }
 
@@ -72,7 +83,8 @@ impl UserData for ByteStream {
     methods.add_async_function("read", |lua, mut args: MultiValue| async move {
       let mut this = check_userdata_mut::<Self>(args.pop_front(), "byte stream")
         .map_err(tag_handler(lua, 1, 1))?;
-      let value = match this.with_borrowed_mut(|x| x.0.try_next()).await? {
+      let next = this.with_borrowed_mut(|x| x.0.try_next()).await;
+      let value = match next.map_err(|error| resolve_body_error(lua, error))? {
         Some(bytes) => mlua::Value::String(lua.create_string(&bytes)?),
         None => Nil,
       };
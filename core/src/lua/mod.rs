@@ -8,7 +8,7 @@ mod libs;
 #[cfg(test)]
 mod tests;
 
-pub use libs::{fs, http, json, lua_std, rand, stream};
+pub use libs::{crypto, fs, http, json, kv, lua_std, queue, rand, sql, stream};
 
 use crate::{Error, ErrorKind};
 use error::{resolve_callback_error, CustomError};
@@ -154,16 +154,21 @@ pub fn sanitize_error(error: mlua::Error) -> Error {
   match error {
     mlua::Error::CallbackError { traceback, cause } => {
       let cause = resolve_callback_error(&cause);
-      if let mlua::Error::ExternalError(error) = cause {
-        if let Some(error) = extract_custom_error(error) {
-          return error;
+      match cause {
+        mlua::Error::ExternalError(error) => {
+          if let Some(error) = extract_custom_error(error) {
+            return error;
+          }
         }
+        mlua::Error::MemoryError(_) => return ErrorKind::MemoryLimitExceeded.into(),
+        _ => {}
       }
       format!("{cause}\n{traceback}").to_lua_err().into()
     }
     mlua::Error::ExternalError(error) => {
       extract_custom_error(&error).unwrap_or_else(|| mlua::Error::ExternalError(error).into())
     }
+    mlua::Error::MemoryError(_) => ErrorKind::MemoryLimitExceeded.into(),
     _ => error.into(),
   }
 }
@@ -1,4 +1,4 @@
-use super::http::LuaUri;
+use super::http::{HttpClient, LuaUri};
 use super::{LuaCacheExt, LUA_HTTP_CLIENT};
 use crate::rt_error_fmt;
 use anyhow::{anyhow, bail, Context};
@@ -22,12 +22,22 @@ use tokio::io::AsyncReadExt;
 #[derive(Debug, Clone, Default)]
 pub struct RemoteInterface {
   cache_path: Option<Arc<Path>>,
+  /// Client that applies the service's egress policy, if any.
+  http_client: Option<HttpClient>,
 }
 
 impl RemoteInterface {
   pub fn new(cache_path: Option<PathBuf>) -> Self {
     Self {
       cache_path: cache_path.map(From::from),
+      http_client: None,
+    }
+  }
+
+  pub(crate) fn with_http_client(&self, http_client: HttpClient) -> Self {
+    Self {
+      cache_path: self.cache_path.clone(),
+      http_client: Some(http_client),
     }
   }
 
@@ -69,7 +79,11 @@ impl RemoteInterface {
     } else {
       debug!("Loading '{path} @{uri}'");
     }
-    let resps = join(request_ok(init_uri.clone()), request_ok(file_uri.clone())).await;
+    let resps = join(
+      self.request_ok(init_uri.clone()),
+      self.request_ok(file_uri.clone()),
+    )
+    .await;
 
     match resps {
       (Ok((uri, mut resp)), Err(_)) | (Err(_), Ok((uri, mut resp))) => {
@@ -87,6 +101,10 @@ impl RemoteInterface {
   }
 
   async fn get_cached(&self, path: &str, uri: Uri) -> anyhow::Result<(Bytes, Uri)> {
+    // Cached modules must not bypass the egress policy either
+    if let Some(client) = &self.http_client {
+      client.check_egress(&uri)?;
+    }
     match self.cache_path.as_deref() {
       Some(cache_path) => {
         let hash = Sha256::new()
@@ -125,10 +143,17 @@ impl RemoteInterface {
       None => self.get(path, uri).await,
     }
   }
+
+  async fn request_ok(&self, uri: Uri) -> anyhow::Result<(Uri, Response<Body>)> {
+    let resp = match &self.http_client {
+      Some(client) => client.get(uri.clone()).await?,
+      None => LUA_HTTP_CLIENT.get(uri.clone()).await?,
+    };
+    check_response(uri, resp)
+  }
 }
 
-async fn request_ok(uri: Uri) -> anyhow::Result<(Uri, Response<Body>)> {
-  let resp = LUA_HTTP_CLIENT.get(uri.clone()).await?;
+fn check_response(uri: Uri, resp: Response<Body>) -> anyhow::Result<(Uri, Response<Body>)> {
   if resp.status() != 200 {
     bail!("server responded with status code {}", resp.status())
   }
@@ -1,23 +1,41 @@
 use super::fs::create_preload_fs;
 use super::global_env::modify_global_env;
-use super::http::create_preload_http;
+use super::http::{create_preload_http, HttpClient};
 use super::isolate::{Isolate, IsolateBuilder};
 use super::json::create_preload_json;
-use super::libs::crypto::create_preload_crypto;
+use super::kv::{create_preload_kv, KvStore};
+use super::libs::crypto::{create_preload_crypto, Argon2Permits};
+use super::libs::encoding::create_preload_encoding;
+use super::libs::jwt::create_preload_jwt;
 use super::lua_std::{
   create_preload_coroutine, create_preload_math, create_preload_os, create_preload_string,
   create_preload_table, create_preload_utf8, side_effect_global_whitelist,
 };
+use super::queue::create_preload_queue;
 use super::rand::create_preload_rand;
 use super::require::RemoteInterface;
 use super::sanitize_error;
+use super::sql::create_preload_sql;
 use super::stream::create_preload_stream;
+use crate::queue::JobQueue;
 use crate::source::Source;
+use crate::task::GC_MINOR_MULTIPLIER;
 use crate::Result;
 use mlua::{FromLuaMulti, Lua, Table, ToLuaMulti};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// Per-service handles the standard library works with.
+pub struct ServiceHandles {
+  pub env: Arc<HashMap<String, String>>,
+  pub kv: KvStore,
+  pub sql_max_rows: usize,
+  pub queue: JobQueue,
+  pub http_client: HttpClient,
+  pub argon2_permits: Argon2Permits,
+}
+
 pub struct Sandbox {
   lua: Lua,
   remote: RemoteInterface,
@@ -26,6 +44,8 @@ pub struct Sandbox {
 impl Sandbox {
   pub fn new(remote: RemoteInterface) -> mlua::Result<Self> {
     let lua = Lua::new();
+    // Isolates' memory accounting relies on minor collections.
+    lua.gc_gen(GC_MINOR_MULTIPLIER, 0);
     modify_global_env(&lua)?;
     Ok(Self { lua, remote })
   }
@@ -42,24 +62,38 @@ impl Sandbox {
     &self,
     source: Source,
     lsp: impl Into<PathBuf>,
+    handles: ServiceHandles,
   ) -> mlua::Result<IsolateBuilder> {
+    let ServiceHandles {
+      env,
+      kv,
+      sql_max_rows,
+      queue,
+      http_client,
+      argon2_permits,
+    } = handles;
     let lsp: Arc<Path> = lsp.into().into();
-    self
-      .isolate_builder(source.clone())?
+    let remote = self.remote.with_http_client(http_client.clone());
+    IsolateBuilder::new(&self.lua, source.clone(), remote)?
       .add_side_effect(side_effect_global_whitelist)?
       // Lua std, modified
       .add_lib("math", create_preload_math)?
       .add_lib("string", create_preload_string)?
       .add_lib("table", create_preload_table)?
       .add_lib("coroutine", create_preload_coroutine)?
-      .add_lib("os", create_preload_os)?
+      .add_lib("os", create_preload_os(env))?
       .add_lib("utf8", create_preload_utf8)?
       // Abel std (?)
-      .add_lib("fs", create_preload_fs(source, lsp))?
-      .add_lib("http", create_preload_http)?
+      .add_lib("fs", create_preload_fs(source, lsp.clone()))?
+      .add_lib("http", create_preload_http(http_client))?
       .add_lib("json", create_preload_json)?
+      .add_lib("kv", create_preload_kv(kv))?
+      .add_lib("sql", create_preload_sql(lsp, sql_max_rows))?
+      .add_lib("queue", create_preload_queue(queue))?
       .add_lib("rand", create_preload_rand)?
-      .add_lib("crypto", create_preload_crypto)?
+      .add_lib("crypto", create_preload_crypto(argon2_permits))?
+      .add_lib("jwt", create_preload_jwt)?
+      .add_lib("encoding", create_preload_encoding)?
       .add_lib("stream", create_preload_stream)?
       .add_lua_lib("testing", include_str!("libs/testing.lua"))?
       // ...and load some of then into local env
@@ -1,9 +1,13 @@
 use super::error::resolve_callback_error;
+use super::http::{HttpClient, HttpClientOptions};
+use super::kv::KvStore;
 use super::require::RemoteInterface;
-use super::sandbox::Sandbox;
+use super::sandbox::{Sandbox, ServiceHandles};
+use crate::queue::JobQueue;
 use crate::source::{Metadata, Source, SourceVfs};
 use async_trait::async_trait;
 use std::io::Cursor;
+use std::sync::Arc;
 use tempfile::TempDir;
 use tokio::io;
 
@@ -42,7 +46,18 @@ macro_rules! run_lua_test {
       let sandbox = Sandbox::new(RemoteInterface::new(None))?;
       let local_storage = TempDir::new()?;
       let isolate = sandbox
-        .isolate_builder_with_stdlib(Source::new(EmptySource), local_storage.path())?
+        .isolate_builder_with_stdlib(
+          Source::new(EmptySource),
+          local_storage.path(),
+          ServiceHandles {
+            env: Arc::new([("ABEL_TEST".into(), "foo".into())].into()),
+            kv: KvStore::new(local_storage.path().join(".kv")),
+            sql_max_rows: 10000,
+            queue: JobQueue::new(local_storage.path().join(".queue")),
+            http_client: HttpClient::new(HttpClientOptions::default(), Default::default())?,
+            argon2_permits: Default::default(),
+          },
+        )?
         .build()?;
       sandbox
         .run_isolate_ext::<_, _, ()>(&isolate, $code, $test_name, ())
@@ -118,6 +133,151 @@ lua_tests! {
     t.assert_eq(query.baz, " ")
   "#
 
+  test_crypto r#"
+    local crypto = require "crypto"
+    local t = require "testing"
+
+    local abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    t.assert_eq(crypto.Sha256 "abc", abc)
+    t.assert_eq(crypto.Sha256("abc", "base64"), "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=")
+    t.assert_eq(#crypto.Sha256("abc", "raw"), 32)
+    t.assert_false(pcall(crypto.Sha256, "abc", "base32"))
+
+    -- RFC 4231, test case 2
+    local key, data = "Jefe", "what do ya want for nothing?"
+    local expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    t.assert_eq(crypto.Hmac("sha256", key, data), expected)
+    t.assert_eq(
+      crypto.Hmac("sha256", key, data, "base64url"),
+      "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM"
+    )
+    local mac = crypto.Hmac("sha256", key)
+    mac:write "what do ya want "
+    mac:write "for nothing?"
+    t.assert_eq(mac:finalize(), expected)
+    t.assert_false(pcall(mac.finalize, mac))
+    t.assert_false(pcall(crypto.Hmac, "md5", key))
+
+    t.assert(crypto.constant_time_eq(expected, crypto.Hmac("sha256", key, data)))
+    t.assert_false(crypto.constant_time_eq(expected, abc))
+    t.assert_false(crypto.constant_time_eq(expected, ""))
+  "#
+
+  test_crypto_cipher r#"
+    local crypto = require "crypto"
+    local t = require "testing"
+
+    for _, cipher in ipairs { crypto.Aes256Gcm(("k"):rep(32)), crypto.ChaCha20Poly1305(("k"):rep(32)) } do
+      local sealed = cipher:seal("secret\0message", "header")
+      t.assert_eq(#sealed, 12 + 14 + 16)
+      t.assert_eq(cipher:open(sealed, "header"), "secret\0message")
+      t.assert_eq(cipher:open(sealed), nil)
+      local tampered = sealed:sub(1, -2) .. string.char(sealed:byte(-1) ~ 1)
+      t.assert_eq(cipher:open(tampered, "header"), nil)
+      t.assert_eq(select(2, cipher:open "short"), "authentication failed")
+      t.assert(cipher:seal "" ~= cipher:seal "")
+    end
+    t.assert_false(pcall(crypto.Aes128Gcm, ("k"):rep(32)))
+
+    local secret, public = crypto.ed25519_keygen()
+    t.assert_eq(#secret, 32)
+    local sig = crypto.ed25519_sign(secret, "hello")
+    t.assert_eq(#sig, 64)
+    t.assert(crypto.ed25519_verify(public, "hello", sig))
+    t.assert_false(crypto.ed25519_verify(public, "hellO", sig))
+    t.assert_false(pcall(crypto.ed25519_verify, public, "hello", "short"))
+
+    local hash = crypto.hash_password "hunter2"
+    t.assert(hash:find "^%$argon2id%$")
+    t.assert(crypto.verify_password("hunter2", hash))
+    t.assert_false(crypto.verify_password("hunter3", hash))
+    t.assert_false(pcall(crypto.verify_password, "hunter2", "not a hash"))
+    local costly = hash:gsub("m=%d+,t=%d+,p=%d+", "m=4194304,t=100000,p=1")
+    local ok, err = pcall(crypto.verify_password, "hunter2", costly)
+    t.assert(not ok and tostring(err):find "too costly", tostring(err))
+  "#
+
+  test_jwt r#"
+    local crypto = require "crypto"
+    local jwt = require "jwt"
+    local t = require "testing"
+
+    local now = os.time()
+    local token = jwt.encode({ sub = "alice", aud = "api", exp = now + 60 }, "secret")
+    local hs256 = { algorithm = "HS256" }
+    local claims, header = jwt.verify(token, "secret", { algorithm = "HS256", audience = "api" })
+    t.assert_eq(claims.sub, "alice")
+    t.assert_eq(header.alg, "HS256")
+
+    local function reason(...)
+      local ok, err = pcall(jwt.verify, ...)
+      t.assert_false(ok)
+      t.assert_eq(err.status, 401)
+      return err.detail.reason
+    end
+    t.assert_eq(reason(token, "wrong", { algorithm = "HS256", audience = "api" }), "invalid signature")
+    t.assert_eq(reason(token, "secret", { algorithm = "HS256", audience = "web" }), "invalid audience")
+    t.assert_eq(reason(token, "secret", { algorithm = "HS512", audience = "api" }), "algorithm not allowed")
+    t.assert_eq(reason("not.a.token", "secret", hs256), "malformed token")
+    local expired = jwt.encode({ exp = now - 120 }, "secret")
+    t.assert_eq(reason(expired, "secret", hs256), "token has expired")
+    local immature = jwt.encode({ exp = now + 600, nbf = now + 300 }, "secret")
+    t.assert_eq(reason(immature, "secret", hs256), "token is not valid yet")
+    t.assert_eq(reason(jwt.encode({}, "secret"), "secret", hs256), "missing required claim 'exp'")
+    t.assert(jwt.verify(jwt.encode({}, "secret"), "secret", { algorithm = "HS256", required = {} }))
+
+    -- No algorithm is assumed, and public keys cannot pass for HMAC secrets
+    local ok, err = pcall(jwt.verify, token, "secret")
+    t.assert(not ok and tostring(err):find "missing option 'algorithm'", tostring(err))
+    local pem = "-----BEGIN PUBLIC KEY-----\nMCowBQYDK2VwAyEA\n-----END PUBLIC KEY-----\n"
+    local forged = jwt.encode({ exp = now + 60 }, pem)
+    local ok, err = pcall(jwt.verify, forged, pem, hs256)
+    t.assert(not ok and tostring(err):find "PEM key used as HMAC secret", tostring(err))
+
+    local secret, public = crypto.ed25519_keygen()
+    local signed = jwt.encode({ exp = now + 60 }, secret, { algorithm = "EdDSA", kid = "k1" })
+    local _, header = jwt.decode(signed)
+    t.assert_eq(header.kid, "k1")
+    t.assert(jwt.verify(signed, public, { algorithm = "EdDSA" }))
+    t.assert_false(pcall(jwt.encode, {}, "secret", { algorithm = "none" }))
+    t.assert_false(pcall(jwt.verify, signed, "short", { algorithm = "RS256" }))
+  "#
+
+  test_encoding r#"
+    local encoding = require "encoding"
+    local stream = require "stream"
+    local t = require "testing"
+
+    t.assert_eq(encoding.base64.encode "foob", "Zm9vYg==")
+    t.assert_eq(encoding.base64_nopad.encode "foob", "Zm9vYg")
+    t.assert_eq(encoding.base64url.encode "\xfb\xff", "-_8=")
+    t.assert_eq(encoding.base64url_nopad.decode "-_8", "\xfb\xff")
+    t.assert_eq(encoding.base32.encode "foob", "MZXW6YQ=")
+    t.assert_eq(encoding.base32_nopad.decode "MZXW6YQ", "foob")
+    t.assert_eq(encoding.hex.encode "\0\xab", "00ab")
+    t.assert_eq(encoding.hex.decode "00AB", "\0\xab")
+    t.assert_eq(encoding.percent.encode "a b/c~\xff", "a%20b%2Fc~%FF")
+    t.assert_eq(encoding.percent.decode "a%20b%2fc", "a b/c")
+    t.assert_false(pcall(encoding.base64.decode, "Zm9vYg"))
+    t.assert_false(pcall(encoding.hex.decode, "abc"))
+
+    local chunks = { "f", "oo", "bar", "b", "az" }
+    local i = 0
+    local st = stream.from_iter(function()
+      i = i + 1
+      return chunks[i]
+    end)
+    local encoded = stream.read_all(stream.pipe_through(st, encoding.base64.Encoder()))
+    t.assert_eq(encoded, encoding.base64.encode "foobarbaz")
+    t.assert_eq(encoded, "Zm9vYmFyYmF6")
+
+    local encoder = encoding.base32.Encoder()
+    t.assert_eq(encoder:transform "foo", "")
+    t.assert_eq(encoder:transform "ba", "MZXW6YTB")
+    t.assert_eq(encoder:finish(), "")
+    t.assert_false(pcall(encoder.transform, encoder, "r"))
+  "#
+
   test_rand r#"
     local rand = require "rand"
     local rng = rand.ThreadRng
@@ -126,5 +286,155 @@ lua_tests! {
     t.assert_eq(type(rng:random()), "number")
     t.assert(math.tointeger(rng:gen_range(1, 5)))
     t.assert_false(pcall(rng.gen_range, rng, 1, -1))
+
+    local a, b = rand.StdRng(42), rand.StdRng(42)
+    t.assert_eq(a:gen_range(1, 1000000), b:gen_range(1, 1000000))
+    t.assert_eq(a:bytes(16), b:bytes(16))
+    t.assert_eq(#rand.OsRng:bytes(32), 32)
+    t.assert_eq(rand.OsRng:bytes(0), "")
+    t.assert_false(pcall(rng.bytes, rng, -1))
+    t.assert_false(pcall(rand.OsRng.bytes, rand.OsRng, math.maxinteger))
+    t.assert_false(pcall(rng.bytes, rng, 4 * 1024 * 1024 + 1))
+
+    local items = { 1, 2, 3, 4, 5, 6, 7, 8 }
+    a:shuffle(items)
+    local sum = 0
+    for _, v in ipairs(items) do
+      sum = sum + v
+    end
+    t.assert_eq(#items, 8)
+    t.assert_eq(sum, 36)
+    t.assert(items[rng:choose { 1, 2, 3 }])
+    t.assert_eq(rng:choose {}, nil)
+
+    local uuid = rand.uuid_v4()
+    t.assert(uuid:match "^%x+%-%x+%-4%x+%-[89ab]%x+%-%x+$")
+    t.assert(uuid ~= rand.uuid_v4())
+  "#
+
+  test_http_event_stream r#"
+    local http = require "http"
+    local t = require "testing"
+
+    local es <close> = http.EventStream(nil, { heartbeat = 0 })
+    t.assert_eq(es.last_event_id, nil)
+    es:write { event = "greeting", data = "hello\nworld", id = "1" }
+    es:write { data = { foo = "bar" } }
+    es:write "plain"
+    t.assert_false(pcall(es.write, es, { event = "a\nb", data = "" }))
+
+    es:close()
+    t.assert_false(pcall(es.write, es, "closed"))
+  "#
+
+  test_kv r#"
+    local kv = require "kv"
+    local t = require "testing"
+
+    t.assert_eq(kv.get "foo", nil)
+    kv.set("foo", "bar")
+    kv.set("user:1", "alice", { ttl = 60000 })
+    kv.set("user:2", "bob")
+    t.assert_eq(kv.get "foo", "bar")
+    t.assert_false(pcall(kv.set, "foo", "bar", { ttl = 0 }))
+
+    local users = {}
+    for k, v in kv.scan "user:" do
+      users[#users + 1] = k .. "=" .. v
+    end
+    t.assert_eq(table.concat(users, ","), "user:1=alice,user:2=bob")
+
+    t.assert(kv.cas("foo", "bar", "baz"))
+    t.assert_false(kv.cas("foo", "bar", "qux"))
+    t.assert_eq(kv.get "foo", "baz")
+    t.assert(kv.cas("new", nil, "1"))
+    t.assert_false(kv.cas("new", nil, "2"))
+
+    t.assert_eq(kv.delete "foo", "baz")
+    t.assert_eq(kv.get "foo", nil)
+    t.assert_eq(kv.delete "foo", nil)
+  "#
+
+  test_queue r#"
+    local queue = require "queue"
+    local t = require "testing"
+
+    local a = queue.push("webhook", { url = "https://example.com" })
+    local b = queue.push("webhook", "retry me", { delay = 1000, max_retries = 0 })
+    t.assert_eq(math.type(a), "integer")
+    t.assert(b > a)
+    t.assert_false(pcall(queue.push, "webhook", nil, { delay = -1 }))
+    t.assert_false(pcall(queue.push, "webhook", function() end))
+  "#
+
+  test_sql r#"
+    local sql = require "sql"
+    local t = require "testing"
+
+    sql.execute "create table users (id integer primary key, name text not null, age integer)"
+    t.assert_eq(sql.execute("insert into users (name, age) values (?, ?)", "alice", 30), 1)
+    t.assert_eq(sql.execute("insert into users (name, age) values (:name, :age)", { name = "bob" }), 1)
+
+    local rows = sql.query("select name, age from users order by id")
+    local alice, bob = rows:read(), rows:read()
+    t.assert_eq(alice.name, "alice")
+    t.assert_eq(alice.age, 30)
+    t.assert_eq(bob.name, "bob")
+    t.assert_eq(bob.age, nil)
+    t.assert_eq(rows:read(), nil)
+
+    do
+      local tx <close> = sql.begin()
+      tx:execute("update users set age = ? where name = ?", 25, "bob")
+      t.assert_eq(tx:query("select age from users where name = 'bob'"):read().age, 25)
+    end
+    t.assert_eq(sql.query("select age from users where name = 'bob'"):read().age, nil)
+
+    local tx <close> = sql.begin()
+    tx:execute("delete from users where name = ?", "alice")
+    tx:commit()
+    t.assert_false(pcall(tx.commit, tx))
+
+    local names = {}
+    for row in sql.query("select name from users"):iter() do
+      names[#names + 1] = row.name
+    end
+    t.assert_eq(table.concat(names, ","), "bob")
+    t.assert_false(pcall(sql.execute, "insert into users (name) values (?)", {}))
+
+    -- Rows are read in batches as they are consumed
+    local count = 0
+    for _ in sql.query("with recursive c(x) as (select 1 union all select x + 1 from c limit 200) select x from c"):iter() do
+      count = count + 1
+    end
+    t.assert_eq(count, 200)
+
+    local rows = sql.query("with recursive c(x) as (select 1 union all select x + 1 from c limit 200) select x from c")
+    t.assert_eq(rows:read().x, 1)
+    rows:close()
+    t.assert_eq(rows:read(), nil)
+
+    do
+      local tx <close> = sql.begin()
+      local rows = tx:query("with recursive c(x) as (select 1 union all select x + 1 from c limit 200) select x from c")
+      t.assert_eq(rows:read().x, 1)
+      tx:execute("update users set age = 1")
+      t.assert_false(pcall(rows.read, rows))
+    end
+
+    -- Other databases are out of reach
+    local ok, err = pcall(sql.execute, "attach database 'other.sqlite' as other")
+    t.assert(not ok and tostring(err):find "not authorized", tostring(err))
+    local ok, err = pcall(sql.execute, "vacuum into 'copy.sqlite'")
+    t.assert_false(ok, "VACUUM INTO should be denied")
+    t.assert_false(pcall(sql.execute, "detach database main"))
+  "#
+
+  test_os_getenv r#"
+    local t = require "testing"
+
+    t.assert_eq(os.getenv "ABEL_TEST", "foo")
+    t.assert_eq(os.getenv "ABEL_NONEXISTENT", nil)
+    t.assert_false(pcall(os.getenv))
   "#
 }
@@ -1,62 +1,202 @@
+use crate::ErrorKind::InvalidRoute;
 use crate::Result;
-use once_cell::sync::Lazy;
+use hyper::Method;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
 
-pub type Params = HashMap<Box<str>, Box<str>>;
+pub type Params = HashMap<Box<str>, ParamValue>;
+
+/// Value of a path parameter, converted according to its [`ParamType`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+  Str(Box<str>),
+  Int(i64),
+  Float(f64),
+}
+
+impl From<&str> for ParamValue {
+  fn from(x: &str) -> Self {
+    Self::Str(x.into())
+  }
+}
+
+impl From<i64> for ParamValue {
+  fn from(x: i64) -> Self {
+    Self::Int(x)
+  }
+}
+
+impl From<f64> for ParamValue {
+  fn from(x: f64) -> Self {
+    Self::Float(x)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+  Str,
+  Int,
+  Float,
+}
+
+impl ParamType {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "str" => Some(Self::Str),
+      "int" => Some(Self::Int),
+      "float" => Some(Self::Float),
+      _ => None,
+    }
+  }
+
+  fn regex(self) -> &'static str {
+    match self {
+      Self::Str => r"[^/]+",
+      Self::Int => r"[+-]?\d+",
+      Self::Float => r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?",
+    }
+  }
+
+  fn parse(self, s: &str) -> Option<ParamValue> {
+    match self {
+      Self::Str => Some(s.into()),
+      Self::Int => s.parse::<i64>().ok().map(Into::into),
+      Self::Float => s.parse::<f64>().ok().map(Into::into),
+    }
+  }
+}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Param {
+  name: Box<str>,
+  #[serde(rename = "type")]
+  ty: ParamType,
+}
+
+/// Matches paths against patterns like `/users/:id:int/*rest`.
+///
+/// Syntax of path parameters:
+///
+/// - `:name` matches one non-empty segment;
+/// - `:name<regex>` matches `regex` inside one segment;
+/// - `:name:int` and `:name:float` match numbers and convert them;
+/// - any of above followed by `?` is optional, with its leading slash if it
+///   takes the whole segment;
+/// - `*` or `*name` matches the rest, including slashes. Unnamed wildcard is
+///   named `*`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PathMatcher {
   path: Box<str>,
   #[serde(with = "serde_regex")]
   regex: Regex,
-  param_names: Vec<Box<str>>,
+  params: Vec<Param>,
 }
 
 impl PathMatcher {
   pub fn new(matcher: &str) -> Result<Self> {
-    static PATH_PARAMS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r":([^/]+)|\*").unwrap());
-
+    let invalid = |reason: &str| InvalidRoute {
+      route: matcher.into(),
+      reason: reason.into(),
+    };
     let mut regex = "^".to_owned();
-    let mut param_names = Vec::new();
+    let mut literal = String::new();
+    let mut params = Vec::<Param>::new();
 
     if !matcher.starts_with('/') {
-      regex += "/";
+      literal += "/";
     }
 
-    let mut start_pos = 0;
-    for captures in PATH_PARAMS_REGEX.captures_iter(matcher) {
-      let whole = captures.get(0).unwrap();
-      regex += &regex::escape(&matcher[start_pos..whole.start()]);
-      if whole.as_str() == "*" {
-        regex += r"(.*)";
-        param_names.push("*".into())
+    let mut rest = matcher;
+    while let Some(pos) = rest.find([':', '*']) {
+      literal += &rest[..pos];
+      let sigil = rest.as_bytes()[pos];
+      rest = &rest[pos + 1..];
+
+      let name_len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+      let (name, mut ty, mut pattern) = if sigil == b'*' {
+        let name = if name_len == 0 {
+          "*"
+        } else {
+          &rest[..name_len]
+        };
+        (name, ParamType::Str, ".*")
+      } else if name_len == 0 {
+        return Err(invalid("missing parameter name").into());
+      } else {
+        (&rest[..name_len], ParamType::Str, ParamType::Str.regex())
+      };
+      rest = &rest[name_len..];
+
+      if sigil == b':' {
+        if let Some(after) = rest.strip_prefix('<') {
+          let end = find_constraint_end(after).ok_or_else(|| invalid("unclosed '<'"))?;
+          pattern = &after[..end];
+          rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix(':') {
+          let type_len = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+          ty = ParamType::from_name(&after[..type_len])
+            .ok_or_else(|| invalid("unknown parameter type"))?;
+          pattern = ty.regex();
+          rest = &after[type_len..];
+        }
+      }
+
+      let optional = if let Some(after) = rest.strip_prefix('?') {
+        rest = after;
+        true
+      } else {
+        false
+      };
+
+      if params.iter().any(|x| &*x.name == name) {
+        return Err(invalid("duplicate parameter name").into());
+      }
+      let group = format!("(?P<p{}>{pattern})", params.len());
+      params.push(Param {
+        name: name.into(),
+        ty,
+      });
+
+      // The root slash is always kept, so that `/:name?` matches `/`.
+      let whole_segment = literal.ends_with('/')
+        && (rest.is_empty() || rest.starts_with('/'))
+        && regex.len() + literal.len() > 2;
+      if optional && whole_segment {
+        literal.pop();
+        regex += &regex::escape(&literal);
+        regex += &format!("(?:/{group})?");
       } else {
-        regex += r"([^/]+)";
-        param_names.push(captures[1].into());
+        regex += &regex::escape(&literal);
+        regex += &group;
+        if optional {
+          regex += "?";
+        }
       }
-      start_pos = whole.end();
+      literal.clear();
     }
-    regex += &regex::escape(&matcher[start_pos..]);
+    literal += rest;
+    regex += &regex::escape(&literal);
     regex += "$";
 
     Ok(Self {
       path: matcher.into(),
       regex: Regex::new(&regex)?,
-      param_names,
+      params,
     })
   }
 
   pub fn gen_params(&self, path: &str) -> Option<Params> {
-    self.regex.captures(path).map(|captures| {
-      self
-        .param_names
-        .iter()
-        .zip(captures.iter().skip(1))
-        .filter_map(|(n, m)| m.map(|m| (n.clone(), m.as_str().into())))
-        .collect()
-    })
+    let captures = self.regex.captures(path)?;
+    let mut params = Params::new();
+    for (i, param) in self.params.iter().enumerate() {
+      if let Some(m) = captures.name(&format!("p{i}")) {
+        params.insert(param.name.clone(), param.ty.parse(m.as_str())?);
+      }
+    }
+    Some(params)
   }
 
   pub fn as_str(&self) -> &str {
@@ -66,6 +206,200 @@ impl PathMatcher {
   pub fn as_regex_str(&self) -> &str {
     self.regex.as_str()
   }
+
+  /// Specificity of each segment, used for trying more specific routes first.
+  pub fn specificity(&self) -> Vec<Specificity> {
+    // Constraints are removed, as they may contain anything
+    let mut segments = vec![String::new()];
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for c in self.path.chars() {
+      if depth > 0 {
+        match c {
+          _ if escaped => escaped = false,
+          '\\' => escaped = true,
+          '<' => depth += 1,
+          '>' => depth -= 1,
+          _ => {}
+        }
+        continue;
+      }
+      match c {
+        '/' => segments.push(String::new()),
+        '<' => {
+          depth = 1;
+          segments.last_mut().unwrap().push_str("<>");
+        }
+        c => segments.last_mut().unwrap().push(c),
+      }
+    }
+
+    (segments.into_iter())
+      .filter(|x| !x.is_empty())
+      .map(|x| {
+        if x.contains('*') {
+          Specificity::Wildcard
+        } else if !x.contains(':') {
+          Specificity::Static
+        } else if x.ends_with('?') {
+          Specificity::Optional
+        } else if x.starts_with(':') && x[1..].chars().all(is_name_char) {
+          Specificity::Param
+        } else {
+          Specificity::Constrained
+        }
+      })
+      .collect()
+  }
+}
+
+/// How specific a path segment is. Lesser ones are more specific.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Specificity {
+  /// `/static`
+  Static,
+  /// `/:id<regex>`, `/:id:int` or `/file.:ext`
+  Constrained,
+  /// `/:name`
+  Param,
+  /// `/:name?`
+  Optional,
+  /// `/*rest`
+  Wildcard,
+}
+
+fn is_name_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Finds the `>` that closes a constraint, skipping escaped characters and
+/// nested `<...>`.
+fn find_constraint_end(s: &str) -> Option<usize> {
+  let mut depth = 0usize;
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' => escaped = true,
+      '<' => depth += 1,
+      '>' if depth == 0 => return Some(i),
+      '>' => depth -= 1,
+      _ => {}
+    }
+  }
+  None
+}
+
+/// A route registered by `abel.listen`, optionally restricted to one method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+  method: Option<Box<str>>,
+  #[serde(flatten)]
+  matcher: PathMatcher,
+  /// Maximum size of request body in bytes.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) max_body_size: Option<u64>,
+  /// Index of the handler in `internal.paths`, starting from 1.
+  #[serde(skip)]
+  pub(crate) handler: usize,
+}
+
+impl Route {
+  /// Parses routes like `/path/:param` or `GET /path/:param`.
+  ///
+  /// `method` is the one given separately, e.g. from options of `abel.listen`.
+  pub fn new(route: &str, method: Option<&str>) -> Result<Self> {
+    let invalid = |reason: &str| InvalidRoute {
+      route: route.into(),
+      reason: reason.into(),
+    };
+
+    let (prefix, path) = match route.split_once(' ') {
+      Some((prefix, path)) => (Some(prefix), path.trim_start()),
+      None => (None, route),
+    };
+    let method = match (prefix, method) {
+      (Some(a), Some(b)) if !a.eq_ignore_ascii_case(b) => {
+        return Err(invalid("conflicting methods").into())
+      }
+      (a, b) => a.or(b).map(str::to_ascii_uppercase),
+    };
+    if let Some(method) = &method {
+      Method::from_bytes(method.as_bytes()).map_err(|_| invalid("invalid method"))?;
+    }
+
+    Ok(Self {
+      method: method.map(Into::into),
+      matcher: PathMatcher::new(path)?,
+      max_body_size: None,
+      handler: 0,
+    })
+  }
+
+  pub fn method(&self) -> Option<&str> {
+    self.method.as_deref()
+  }
+
+  pub fn matcher(&self) -> &PathMatcher {
+    &self.matcher
+  }
+
+  pub fn max_body_size(&self) -> Option<u64> {
+    self.max_body_size
+  }
+
+  /// Checks if this route accepts the same requests as `other`, i.e. they
+  /// only differ in names of parameters.
+  pub fn duplicates(&self, other: &Self) -> bool {
+    self.method == other.method && self.matcher.as_regex_str() == other.matcher.as_regex_str()
+  }
+
+  /// Checks if this route accepts `method`. `HEAD` requests are accepted by
+  /// `GET` routes.
+  pub fn accepts(&self, method: &Method) -> bool {
+    match self.method() {
+      Some(x) => x == method.as_str() || (x == "GET" && method == Method::HEAD),
+      None => true,
+    }
+  }
+}
+
+impl Display for Route {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    if let Some(method) = &self.method {
+      write!(f, "{method} ")?;
+    }
+    f.write_str(self.matcher.as_str())
+  }
+}
+
+/// Sorts routes from the most specific to the least, and finds duplicate ones.
+///
+/// Routes of the same specificity keep their registration order. Once sorted,
+/// a route can only be hidden entirely by an earlier one accepting the same
+/// requests, which is rejected if they are written the same way, or reported
+/// in the returned warnings if only their parameter names differ.
+///
+/// Constraints are compared as written, so equivalent ones like `/:id<\d+>`
+/// and `/:id<[0-9]+>` are not considered duplicates.
+pub fn sort_routes(routes: &mut [Route]) -> Result<Vec<String>> {
+  routes.sort_by_cached_key(|x| (x.matcher.specificity(), x.method.is_none()));
+
+  let mut warnings = Vec::new();
+  for (i, route) in routes.iter().enumerate() {
+    if let Some(other) = routes[..i].iter().find(|x| route.duplicates(x)) {
+      if other.matcher.as_str() == route.matcher.as_str() {
+        return Err(From::from(InvalidRoute {
+          route: route.to_string().into(),
+          reason: "duplicate route".into(),
+        }));
+      }
+      warnings.push(format!(
+        "duplicate route '{route}' is never matched, as '{other}' is tried first"
+      ));
+    }
+  }
+  Ok(warnings)
 }
 
 /// The returned path is always relative, which is intentional and convenient
@@ -101,10 +435,70 @@ mod tests {
   #[test_case("/hello/:name", "/hello/world" => some_map!("name" => "world"); "single param")]
   #[test_case("/hello/:name", "/hello/world/" => None; "trailing slash")]
   #[test_case("/files/*", "/files/path/to/secret/file" => some_map!("*" => "path/to/secret/file"); "asterisk")]
+  #[test_case("/files/*dir/-/*rest", "/files/a/b/-/c/d" => some_map!("dir" => "a/b", "rest" => "c/d"); "named wildcards")]
+  #[test_case("/hello/:name?", "/hello" => Some(Params::new()); "optional segment absent")]
+  #[test_case("/hello/:name?", "/hello/world" => some_map!("name" => "world"); "optional segment present")]
+  #[test_case("/:name?", "/" => Some(Params::new()); "optional root segment")]
+  #[test_case("/file.:ext?", "/file." => Some(Params::new()); "optional param in segment")]
+  #[test_case("/users/:id<\\d+>", "/users/123" => some_map!("id" => "123"); "constraint")]
+  #[test_case("/users/:id<\\d+>", "/users/abc" => None; "constraint mismatch")]
+  #[test_case("/users/:id<[a-z]{2}\\d>/x", "/users/ab1/x" => some_map!("id" => "ab1"); "constraint with braces")]
+  #[test_case("/users/:id:int", "/users/-42" => some_map!("id" => -42i64); "int param")]
+  #[test_case("/users/:id:int", "/users/4x" => None; "int param mismatch")]
+  #[test_case("/users/:id:int", "/users/99999999999999999999" => None; "int param overflow")]
+  #[test_case("/price/:p:float", "/price/1.5" => some_map!("p" => 1.5f64); "float param")]
+  #[test_case("/page/:n:int?", "/page" => Some(Params::new()); "optional int param")]
   fn test_path_matcher(matcher: &str, path: &str) -> Option<Params> {
     PathMatcher::new(matcher).unwrap().gen_params(path)
   }
 
+  #[test_case("/:"; "missing name")]
+  #[test_case("/:id<\\d+"; "unclosed constraint")]
+  #[test_case("/:id:uuid"; "unknown type")]
+  #[test_case("/:a/:a"; "duplicate name")]
+  fn test_path_matcher_invalid(matcher: &str) {
+    assert!(PathMatcher::new(matcher).is_err());
+  }
+
+  #[test_case("/hello", None => Some(None); "no method")]
+  #[test_case("GET /hello", None => Some(Some("GET".into())); "method prefix")]
+  #[test_case("/hello", Some("post") => Some(Some("POST".into())); "method option")]
+  #[test_case("GET /hello", Some("POST") => None; "conflicting methods")]
+  #[test_case("G(T /hello", None => None; "invalid method")]
+  fn test_route_method(route: &str, method: Option<&str>) -> Option<Option<String>> {
+    Route::new(route, method)
+      .ok()
+      .map(|x| x.method().map(Into::into))
+  }
+
+  #[test_case(&["/:name", "/static"], &["/static", "/:name"], 0; "static before param")]
+  #[test_case(&["/*", "/:a", "/:a:int", "/:a?"], &["/:a:int", "/:a", "/:a?", "/*"], 0; "segment kinds")]
+  #[test_case(&["/:a/b", "/a/:b"], &["/a/:b", "/:a/b"], 0; "leftmost segment first")]
+  #[test_case(&["/a", "GET /a"], &["GET /a", "/a"], 0; "method before any")]
+  #[test_case(&["/:a", "/:b"], &["/:a", "/:b"], 1; "duplicate")]
+  #[test_case(&["/users", "/:id"], &["/users", "/:id"], 0; "param after static")]
+  #[test_case(&["/:a", "GET /:b"], &["GET /:b", "/:a"], 0; "any method after one")]
+  #[test_case(&["GET /:a", "POST /:b"], &["GET /:a", "POST /:b"], 0; "different methods")]
+  fn test_sort_routes(routes: &[&str], expected: &[&str], duplicates: usize) {
+    let mut routes = routes
+      .iter()
+      .map(|x| Route::new(x, None).unwrap())
+      .collect::<Vec<_>>();
+    let warnings = sort_routes(&mut routes).unwrap();
+    let sorted = routes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(sorted, expected);
+    assert_eq!(warnings.len(), duplicates);
+  }
+
+  #[test]
+  fn test_sort_routes_duplicate() {
+    let mut routes = vec![
+      Route::new("GET /a", None).unwrap(),
+      Route::new("/a", Some("GET")).unwrap(),
+    ];
+    assert!(sort_routes(&mut routes).is_err());
+  }
+
   #[test_case("" => ""; "empty string")]
   #[test_case("etc/rpc" => "etc/rpc"; "force absolute")]
   #[test_case("../../././///etc/rpc" => "etc/rpc"; "special path components")]
@@ -0,0 +1,408 @@
+use crate::service::{get_job_queue, RunningService};
+use crate::task::Pool;
+use crate::ErrorKind::ServiceDropped;
+use crate::{AbelState, Result};
+use log::warn;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, IVec, Transactional};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout_at, Instant};
+
+/// Number of retries of a job if not specified when pushed.
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Delay before the first retry of a failed job; doubled on each retry.
+const BASE_BACKOFF: u64 = 1000;
+
+/// Upper bound of delay between retries.
+const MAX_BACKOFF: u64 = 3_600_000;
+
+const DEAD_TREE: &str = "dead";
+
+/// Persistent job queue of a service, opened on first use.
+///
+/// Pending jobs are keyed by the time they are due and then their ID, so that
+/// the first entry is always the next one to run. Jobs that have used up
+/// their retries are moved to the dead-letter tree, keyed by ID.
+///
+/// Clones share the same underlying database.
+#[derive(Debug, Clone)]
+pub struct JobQueue(Arc<JobQueueInner>);
+
+#[derive(Debug)]
+struct JobQueueInner {
+  path: PathBuf,
+  db: OnceCell<Db>,
+  notify: Notify,
+  /// Held while a job is being run, so that a replacing instance of the
+  /// service does not pick up the same one.
+  lock: Mutex<()>,
+  /// Dropped along with the database, notifying those waiting for it.
+  closed: watch::Sender<()>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct JobRecord {
+  pub(crate) worker: Box<str>,
+  pub(crate) payload: serde_json::Value,
+  /// Number of times the job has failed.
+  pub(crate) attempts: u32,
+  max_retries: u32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  error: Option<Box<str>>,
+}
+
+/// A job that has failed more times than it is allowed to retry.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadJob {
+  id: u64,
+  #[serde(flatten)]
+  record: JobRecord,
+}
+
+#[rustfmt::skip]
+impl DeadJob {
+  pub fn id(&self) -> u64 { self.id }
+  pub fn worker(&self) -> &str { &self.record.worker }
+  pub fn payload(&self) -> &serde_json::Value { &self.record.payload }
+  pub fn attempts(&self) -> u32 { self.record.attempts }
+  pub fn error(&self) -> Option<&str> { self.record.error.as_deref() }
+}
+
+fn now_millis() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+fn pending_key(due: u64, id: u64) -> [u8; 16] {
+  let mut key = [0; 16];
+  key[..8].copy_from_slice(&due.to_be_bytes());
+  key[8..].copy_from_slice(&id.to_be_bytes());
+  key
+}
+
+fn split_pending_key(key: &[u8]) -> (u64, u64) {
+  let (due, id) = key.split_at(8);
+  (
+    u64::from_be_bytes(due.try_into().unwrap()),
+    u64::from_be_bytes(id.try_into().unwrap()),
+  )
+}
+
+fn encode_record(record: &JobRecord) -> Vec<u8> {
+  // Serializing JSON values into memory never fails
+  serde_json::to_vec(record).unwrap()
+}
+
+fn decode_record(raw: &[u8]) -> sled::Result<JobRecord> {
+  serde_json::from_slice(raw)
+    .map_err(|error| sled::Error::Unsupported(format!("corrupted job: {error}")))
+}
+
+/// Delay in milliseconds before retrying a job that has failed `attempts`
+/// times.
+fn backoff(attempts: u32) -> u64 {
+  let exp = attempts.saturating_sub(1).min(32);
+  BASE_BACKOFF.saturating_mul(1 << exp).min(MAX_BACKOFF)
+}
+
+impl JobQueue {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self(Arc::new(JobQueueInner {
+      path: path.into(),
+      db: OnceCell::new(),
+      notify: Notify::new(),
+      lock: Mutex::new(()),
+      closed: watch::channel(()).0,
+    }))
+  }
+
+  /// Drops this handle and waits for the others to be dropped, so that the
+  /// database is closed.
+  ///
+  /// Gives a handle back if the queue is still open at `deadline`.
+  pub(crate) async fn close(self, deadline: Instant) -> std::result::Result<(), Self> {
+    let inner = Arc::downgrade(&self.0);
+    let mut closed = self.0.closed.subscribe();
+    drop(self);
+    match timeout_at(deadline, closed.changed()).await {
+      Ok(_) => Ok(()),
+      Err(_) => inner.upgrade().map_or(Ok(()), |x| Err(Self(x))),
+    }
+  }
+
+  /// Runs a blocking operation on the database in background.
+  async fn run<T, F>(&self, f: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Db) -> sled::Result<T> + Send + 'static,
+  {
+    let this = self.0.clone();
+    let result = spawn_blocking(move || f(this.db.get_or_try_init(|| sled::open(&this.path))?))
+      .await
+      .map_err(io::Error::from)?;
+    Ok(result.map_err(io::Error::from)?)
+  }
+
+  /// Pushes a job to be run by `worker` after `delay` milliseconds, returning
+  /// its ID.
+  pub(crate) async fn push(
+    &self,
+    worker: &str,
+    payload: serde_json::Value,
+    delay: u64,
+    max_retries: u32,
+  ) -> Result<u64> {
+    let record = JobRecord {
+      worker: worker.into(),
+      payload,
+      attempts: 0,
+      max_retries,
+      error: None,
+    };
+    let id = self
+      .run(move |db| {
+        let id = db.generate_id()?;
+        let key = pending_key(now_millis().saturating_add(delay), id);
+        db.insert(key, encode_record(&record))?;
+        Ok(id)
+      })
+      .await?;
+    self.0.notify.notify_one();
+    Ok(id)
+  }
+
+  async fn first_pending(&self) -> Result<Option<(IVec, JobRecord)>> {
+    self
+      .run(|db| match db.first()? {
+        Some((key, raw)) => Ok(Some((key, decode_record(&raw)?))),
+        None => Ok(None),
+      })
+      .await
+  }
+
+  async fn complete(&self, key: IVec) -> Result<()> {
+    self.run(move |db| db.remove(key).map(drop)).await
+  }
+
+  /// Schedules a retry of a failed job, or moves it to the dead-letter tree if
+  /// it has no retries left.
+  async fn fail(&self, key: IVec, mut record: JobRecord, error: String) -> Result<()> {
+    record.attempts += 1;
+    record.error = Some(error.into());
+    let (_, id) = split_pending_key(&key);
+    let raw = encode_record(&record);
+    let is_dead = record.attempts > record.max_retries;
+    let due = now_millis().saturating_add(backoff(record.attempts));
+    self
+      .run(move |db| {
+        let dead = db.open_tree(DEAD_TREE)?;
+        // Both trees are updated at once, so that the job is neither lost nor
+        // duplicated if either write fails.
+        (&**db, &dead)
+          .transaction(|(pending, dead)| {
+            if is_dead {
+              dead.insert(&id.to_be_bytes(), &*raw)?;
+            } else {
+              pending.insert(&pending_key(due, id), &*raw)?;
+            }
+            pending.remove(&key)?;
+            Ok::<_, ConflictableTransactionError<sled::Error>>(())
+          })
+          .map_err(|error| match error {
+            TransactionError::Abort(error) | TransactionError::Storage(error) => error,
+          })
+      })
+      .await
+  }
+
+  pub(crate) async fn dead_jobs(&self) -> Result<Vec<DeadJob>> {
+    self
+      .run(|db| {
+        (db.open_tree(DEAD_TREE)?.iter())
+          .map(|x| {
+            let (key, raw) = x?;
+            Ok(DeadJob {
+              id: u64::from_be_bytes(key.as_ref().try_into().unwrap()),
+              record: decode_record(&raw)?,
+            })
+          })
+          .collect()
+      })
+      .await
+  }
+
+  /// Removes all dead-lettered jobs, returning how many there were.
+  pub(crate) async fn purge_dead_jobs(&self) -> Result<usize> {
+    self
+      .run(|db| {
+        let dead = db.open_tree(DEAD_TREE)?;
+        let count = dead.len();
+        dead.clear()?;
+        Ok(count)
+      })
+      .await
+  }
+}
+
+/// Spawns the dispatcher of a running service's job queue onto the runtime
+/// pool, if the service has any workers.
+///
+/// Jobs are run one at a time in the order they are due, until the service's
+/// [`JobsHandle`](crate::schedule::JobsHandle) is cancelled. Since jobs stay
+/// in the queue until they are done, ones left from before a restart are
+/// picked up as soon as the service is started again.
+pub(crate) fn spawn_queue_dispatcher(
+  rt_pool: &Arc<Pool>,
+  state: &AbelState,
+  service: RunningService,
+) {
+  let (name, token) = match service.try_upgrade() {
+    Ok(guard) if !guard.workers.is_empty() => (guard.name.clone(), guard.jobs_handle.token()),
+    _ => return,
+  };
+  let queue = get_job_queue(state, &name);
+  let rt_pool = rt_pool.clone();
+
+  tokio::spawn(async move {
+    // Consecutive failures of the database, backed off the same way as jobs
+    let mut db_errors = 0;
+    loop {
+      let guard = tokio::select! {
+        _ = token.cancelled() => break,
+        x = queue.0.lock.lock() => x,
+      };
+      let delay = match queue.first_pending().await {
+        Ok(Some((key, record))) if split_pending_key(&key).0 <= now_millis() => {
+          let (_, id) = split_pending_key(&key);
+          let service = service.clone();
+          let record2 = record.clone();
+          let result = rt_pool
+            .scope(move |rt| async move { rt.run_worker(service, id, &record2).await })
+            .await;
+          let result = match result {
+            Ok(()) => queue.complete(key).await,
+            Err(error) if matches!(error.kind(), ServiceDropped) => break,
+            Err(error) => {
+              let worker = &record.worker;
+              warn!(
+                "Lua error when running job {id} on worker '{worker}' of service '{name}': {error}"
+              );
+              queue.fail(key, record, error.to_string()).await
+            }
+          };
+          match result {
+            Ok(()) => {
+              db_errors = 0;
+              continue;
+            }
+            Err(error) => {
+              db_errors += 1;
+              let delay = backoff(db_errors);
+              warn!(
+                "failed to update job queue of service '{name}', retrying in {delay}ms: {error}"
+              );
+              Some(delay)
+            }
+          }
+        }
+        Ok(next) => {
+          db_errors = 0;
+          next.map(|(key, _)| split_pending_key(&key).0.saturating_sub(now_millis()))
+        }
+        Err(error) => {
+          db_errors += 1;
+          let delay = backoff(db_errors);
+          warn!("failed to read job queue of service '{name}', retrying in {delay}ms: {error}");
+          Some(delay)
+        }
+      };
+      drop(guard);
+
+      let wait = async {
+        match delay {
+          Some(ms) => sleep(Duration::from_millis(ms)).await,
+          None => futures::future::pending().await,
+        }
+      };
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = queue.0.notify.notified() => {}
+        _ = wait => {}
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use tempfile::TempDir;
+  use test_case::test_case;
+
+  #[test_case(1 => 1000)]
+  #[test_case(2 => 2000)]
+  #[test_case(5 => 16000)]
+  #[test_case(20 => MAX_BACKOFF; "capped")]
+  #[test_case(u32::MAX => MAX_BACKOFF; "overflow")]
+  fn test_backoff(attempts: u32) -> u64 {
+    backoff(attempts)
+  }
+
+  #[tokio::test]
+  async fn test_job_queue() -> Result<()> {
+    let dir = TempDir::new()?;
+    let queue = JobQueue::new(dir.path());
+    let later = queue.push("foo", json!(2), 60000, 0).await?;
+    let now = queue.push("foo", json!(1), 0, 0).await?;
+
+    let (key, record) = queue.first_pending().await?.unwrap();
+    assert_eq!(split_pending_key(&key).1, now);
+    assert_eq!(record.payload, json!(1));
+
+    queue.fail(key, record, "oops".into()).await?;
+    let dead = queue.dead_jobs().await?;
+    assert_eq!(dead.len(), 1);
+    assert_eq!((dead[0].id(), dead[0].attempts()), (now, 1));
+    assert_eq!(dead[0].error(), Some("oops"));
+
+    let (key, _) = queue.first_pending().await?.unwrap();
+    assert_eq!(split_pending_key(&key).1, later);
+    queue.complete(key).await?;
+    assert!(queue.first_pending().await?.is_none());
+
+    assert_eq!(queue.purge_dead_jobs().await?, 1);
+    assert!(queue.dead_jobs().await?.is_empty());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_job_queue_retry() -> Result<()> {
+    let dir = TempDir::new()?;
+    let queue = JobQueue::new(dir.path());
+    let id = queue.push("foo", json!(1), 0, 1).await?;
+
+    let (key, record) = queue.first_pending().await?.unwrap();
+    queue.fail(key.clone(), record, "oops".into()).await?;
+    let (key2, record) = queue.first_pending().await?.unwrap();
+    assert_ne!(key, key2);
+    assert_eq!(split_pending_key(&key2).1, id);
+    assert!(split_pending_key(&key2).0 >= split_pending_key(&key).0 + BASE_BACKOFF);
+    assert_eq!((record.attempts, record.error.as_deref()), (1, Some("oops")));
+    assert!(queue.dead_jobs().await?.is_empty());
+
+    queue.fail(key2, record, "oops again".into()).await?;
+    assert!(queue.first_pending().await?.is_none());
+    assert_eq!(queue.dead_jobs().await?[0].attempts(), 2);
+    Ok(())
+  }
+}
@@ -1,26 +1,56 @@
+use super::sync::{create_fn_channel, create_fn_mutex, create_fn_notify, create_fn_semaphore};
 use crate::lua::error::{
-  arg_error, check_integer, check_userdata_mut, check_value, rt_error, tag_error, tag_handler,
+  arg_error, check_integer, check_string, check_userdata, check_value, http_error, rt_error,
+  rt_error_fmt, tag_error, tag_handler,
 };
+use crate::lua::http::{check_request, LuaResponse};
 use crate::lua::LuaCacheExt;
-use crate::task::{LocalTask, TaskContext};
-use futures::future::BoxFuture;
-use futures::{Future, FutureExt};
+use crate::service::{get_callee, ServiceName};
+use crate::task::{close_table_values, LocalTask, TaskContext, MAX_CALL_DEPTH};
+use crate::AbelState;
+use futures::future::{join_all, select_all, AbortHandle, Abortable, LocalBoxFuture, Shared};
+use futures::FutureExt;
+use hyper::{Body, Request, Response};
 use mlua::Value::Nil;
 use mlua::{Function, Lua, MultiValue, RegistryKey, Table, UserData};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::oneshot::error::RecvError;
-
-pub fn side_effect_abel(lua: &Lua, local_env: Table, internal: Table) -> mlua::Result<()> {
-  use mlua::Value::Function as Func;
-  let abel = lua.create_table_from([
-    ("listen", Func(create_fn_listen(lua, internal)?)),
-    ("spawn", Func(create_fn_spawn(lua)?)),
-    ("await_all", Func(create_fn_await_all(lua)?)),
-    ("sleep", Func(create_fn_sleep(lua)?)),
-    ("current_worker", lua.pack(std::thread::current().name())?),
-  ])?;
-  local_env.raw_set("abel", abel.clone())?;
-  Ok(())
+
+pub fn side_effect_abel(
+  name: &str,
+  state: Arc<AbelState>,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
+  move |lua, local_env, internal| {
+    use mlua::Value::Function as Func;
+    let abel = lua.create_table_from([
+      ("listen", Func(create_fn_listen(lua, internal.clone())?)),
+      ("use", Func(create_fn_use(lua, internal.clone())?)),
+      ("every", Func(create_fn_every(lua, internal.clone())?)),
+      ("schedule", Func(create_fn_schedule(lua, internal.clone())?)),
+      (
+        "subscribe",
+        Func(create_fn_subscribe(lua, internal.clone())?),
+      ),
+      ("worker", Func(create_fn_worker(lua, internal)?)),
+      ("publish", Func(create_fn_publish(lua, state.clone())?)),
+      ("spawn", Func(create_fn_spawn(lua)?)),
+      ("await_all", Func(create_fn_await_all(lua)?)),
+      ("race", Func(create_fn_race(lua)?)),
+      ("any", Func(create_fn_any(lua)?)),
+      ("timeout", Func(create_fn_timeout(lua)?)),
+      ("sleep", Func(create_fn_sleep(lua)?)),
+      ("channel", Func(create_fn_channel(lua)?)),
+      ("Mutex", Func(create_fn_mutex(lua)?)),
+      ("Semaphore", Func(create_fn_semaphore(lua)?)),
+      ("Notify", Func(create_fn_notify(lua)?)),
+      ("call", Func(create_fn_call(lua, name.into(), state)?)),
+      ("current_worker", lua.pack(std::thread::current().name())?),
+    ])?;
+    local_env.raw_set("abel", abel.clone())?;
+    Ok(())
+  }
 }
 
 pub fn is_in_abel_context(lua: &Lua) -> bool {
@@ -29,11 +59,13 @@ pub fn is_in_abel_context(lua: &Lua) -> bool {
 
 fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
   const SRC: &str = r#"
-    local internal, path, handler = ...
+    local internal, path, handler, options = ...
     assert(
       not internal.sealed,
       "cannot call `listen` from places other than the top level of `main.lua`"
     )
+    assert(type(path) == "string", "path must be a string")
+    assert(options == nil or type(options) == "table", "options must be a table")
     local type_handler = type(handler)
     if type_handler ~= "function" then
       if type_handler == "table" then
@@ -46,7 +78,7 @@ fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Funct
     end
 
     ::ok::
-    table.insert(internal.paths, { path, handler })
+    table.insert(internal.paths, { path, handler, options or {} })
   "#;
   let f = lua.create_cached_value("abel:abel.listen::meta", || {
     lua.load(SRC).set_name("@[abel.listen]")?.into_function()
@@ -54,47 +86,319 @@ fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Funct
   f.bind(internal)
 }
 
-pub struct LuaPromise {
-  inner: BoxFuture<'static, Result<Box<mlua::Result<RegistryKey>>, RecvError>>,
+fn create_fn_use<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, middleware = ...
+    assert(
+      not internal.sealed,
+      "cannot call `use` from places other than the top level of `main.lua`"
+    )
+    local type_middleware = type(middleware)
+    if type_middleware ~= "function" then
+      if type_middleware == "table" then
+        local mt = getmetatable(middleware)
+        if type(mt) == "table" and type(mt.__call) == "function" then
+          goto ok
+        end
+      end
+      error "middleware must either be a function or a callable table"
+    end
+
+    ::ok::
+    table.insert(internal.middlewares, middleware)
+  "#;
+  let f = lua.create_cached_value("abel:abel.use::meta", || {
+    lua.load(SRC).set_name("@[abel.use]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+fn create_fn_every<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, interval, job = ...
+    assert(
+      not internal.sealed,
+      "cannot call `every` from places other than the top level of `main.lua`"
+    )
+    assert(math.type(interval) == "integer", "interval must be an integer")
+    assert(interval > 0, "interval must be positive")
+    assert(type(job) == "function", "job must be a function")
+    table.insert(internal.jobs, { "every", interval, job })
+  "#;
+  let f = lua.create_cached_value("abel:abel.every::meta", || {
+    lua.load(SRC).set_name("@[abel.every]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+fn create_fn_schedule<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, cron, job = ...
+    assert(
+      not internal.sealed,
+      "cannot call `schedule` from places other than the top level of `main.lua`"
+    )
+    assert(type(cron) == "string", "cron expression must be a string")
+    assert(type(job) == "function", "job must be a function")
+    table.insert(internal.jobs, { "cron", cron, job })
+  "#;
+  let f = lua.create_cached_value("abel:abel.schedule::meta", || {
+    lua.load(SRC).set_name("@[abel.schedule]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+fn create_fn_subscribe<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, topic, handler = ...
+    assert(
+      not internal.sealed,
+      "cannot call `subscribe` from places other than the top level of `main.lua`"
+    )
+    assert(type(topic) == "string", "topic must be a string")
+    assert(type(handler) == "function", "handler must be a function")
+    table.insert(internal.subscriptions, { topic, handler })
+  "#;
+  let f = lua.create_cached_value("abel:abel.subscribe::meta", || {
+    lua.load(SRC).set_name("@[abel.subscribe]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+fn create_fn_worker<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, name, worker = ...
+    assert(
+      not internal.sealed,
+      "cannot call `worker` from places other than the top level of `main.lua`"
+    )
+    assert(type(name) == "string", "worker name must be a string")
+    assert(type(worker) == "function", "worker must be a function")
+    assert(internal.workers[name] == nil, "worker '" .. name .. "' already exists")
+    internal.workers[name] = worker
+  "#;
+  let f = lua.create_cached_value("abel:abel.worker::meta", || {
+    lua.load(SRC).set_name("@[abel.worker]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Publishes an event to subscribers of every service on this instance,
+/// returning how many of them it reached.
+///
+/// The value is serialized the same way as `json.stringify`.
+fn create_fn_publish(lua: &Lua, state: Arc<AbelState>) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let topic = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+    let value = args.pop_front().unwrap_or(Nil);
+    let value =
+      serde_json::to_value(&value).map_err(|error| arg_error(lua, 2, &error.to_string(), 1))?;
+    Ok(state.event_bus.publish(topic.to_str()?, value))
+  })
+}
+
+/// Creates a function that runs `handler` through the middleware chain.
+///
+/// Each middleware is called with the request and a `next` function, which
+/// calls the rest of the chain with an optionally replaced request and returns
+/// its response.
+pub(crate) fn create_fn_run_middlewares(lua: &Lua) -> mlua::Result<Function> {
+  const SRC: &str = r#"
+    local middlewares, handler, req = ...
+    local function dispatch(i, req)
+      local middleware = middlewares[i]
+      if middleware == nil then
+        return handler(req)
+      end
+      return middleware(req, function(new_req)
+        return dispatch(i + 1, new_req or req)
+      end)
+    end
+    return dispatch(1, req)
+  "#;
+  lua.create_cached_value("abel:run_middlewares", || {
+    lua
+      .load(SRC)
+      .set_name("@[abel.middleware]")?
+      .into_function()
+  })
+}
+
+type PromiseResult = Rc<mlua::Result<RegistryKey>>;
+
+/// Handle of a task spawned with `abel.spawn`.
+///
+/// - `promise:await()` waits for the task, returning its values or raising its
+///   error.
+/// - `promise:status() -> "pending" | "fulfilled" | "rejected" | "cancelled"`
+/// - `promise:cancel() -> boolean` drops the task if it is still pending, and
+///   closes what it has registered to be closed.
+#[derive(Clone)]
+pub struct LuaPromise(Rc<PromiseInner>);
+
+struct PromiseInner {
+  result: Shared<LocalBoxFuture<'static, PromiseResult>>,
+  abort: AbortHandle,
+  /// The spawned task's own close table.
+  close_table: RegistryKey,
+  cancelled: Cell<bool>,
+}
+
+impl LuaPromise {
+  async fn wait(&self) -> PromiseResult {
+    self.0.result.clone().await
+  }
+
+  fn status(&self) -> &'static str {
+    if self.0.cancelled.get() {
+      return "cancelled";
+    }
+    match self.0.result.clone().now_or_never() {
+      None => "pending",
+      Some(result) if result.is_ok() => "fulfilled",
+      Some(_) => "rejected",
+    }
+  }
+
+  /// Cancels the task, returning `false` if it has already settled.
+  fn cancel(&self, lua: &Lua) -> mlua::Result<bool> {
+    if self.0.cancelled.get() || self.0.result.clone().now_or_never().is_some() {
+      return Ok(false);
+    }
+    self.0.abort.abort();
+    self.0.cancelled.set(true);
+    close_table_values(lua.registry_value(&self.0.close_table)?)?;
+    Ok(true)
+  }
+}
+
+fn unpack_result<'lua>(
+  lua: &'lua Lua,
+  result: &mlua::Result<RegistryKey>,
+) -> mlua::Result<MultiValue<'lua>> {
+  match result {
+    Ok(key) => lua
+      .registry_value::<Table>(key)?
+      .raw_sequence_values()
+      .collect(),
+    Err(error) => Err(error.clone()),
+  }
+}
+
+fn check_promise(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<LuaPromise> {
+  check_userdata::<LuaPromise>(value, "Promise")
+    .map(|x| x.with_borrowed(|x| LuaPromise::clone(x)))
+    .map_err(tag_handler(lua, pos, 1))
+}
+
+/// Checks arguments of promise combinators, spawning functions as new tasks.
+///
+/// Also returns whether each promise is spawned here, so that it can be
+/// cancelled when no longer needed.
+fn check_promises_or_spawn(lua: &Lua, args: MultiValue) -> mlua::Result<Vec<(LuaPromise, bool)>> {
+  (args.into_iter().enumerate())
+    .map(|(i, x)| match x {
+      mlua::Value::Function(f) => Ok((abel_spawn(lua, f)?, true)),
+      x => Ok((check_promise(lua, Some(x), i + 1)?, false)),
+    })
+    .collect()
+}
+
+fn cancel_spawned(lua: &Lua, promises: &[(LuaPromise, bool)]) -> mlua::Result<()> {
+  for (promise, spawned) in promises {
+    if *spawned {
+      promise.cancel(lua)?;
+    }
+  }
+  Ok(())
 }
 
 impl UserData for LuaPromise {
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
     methods.add_async_function("await", |lua, mut args: MultiValue| async move {
-      let mut this =
-        check_userdata_mut::<Self>(args.pop_front(), "Promise").map_err(tag_handler(lua, 1, 1))?;
-      let result = this
-        .with_borrowed_mut(|x| &mut x.inner)
-        .await
-        .map_err(rt_error)?;
-      lua
-        .registry_value::<Table>(&(*result)?)?
-        .raw_sequence_values()
-        .collect::<mlua::Result<MultiValue>>()
-    })
+      let this = check_promise(lua, args.pop_front(), 1)?;
+      unpack_result(lua, &*this.wait().await)
+    });
+
+    methods.add_function("status", |lua, mut args: MultiValue| {
+      Ok(check_promise(lua, args.pop_front(), 1)?.status())
+    });
+
+    methods.add_function("cancel", |lua, mut args: MultiValue| {
+      check_promise(lua, args.pop_front(), 1)?.cancel(lua)
+    });
   }
 }
 
-pub(crate) fn abel_spawn(
-  lua: &Lua,
-  f: Function,
-) -> mlua::Result<impl Future<Output = Result<Box<mlua::Result<RegistryKey>>, RecvError>> + Send> {
+pub(crate) fn abel_spawn(lua: &Lua, f: Function) -> mlua::Result<LuaPromise> {
   let key = lua.create_registry_value(f)?;
-  let ctx = TaskContext::get_current(lua)
-    .map(|x| x.clone())
-    .unwrap_or_default();
+  let ctx = TaskContext::new_spawned(lua)?;
+  // Spawned contexts always have a close table
+  let close_table: Table = lua.registry_value(ctx.close_table.as_deref().unwrap())?;
+  let close_table = lua.create_registry_value(close_table)?;
+  let (abort, registration) = AbortHandle::new_pair();
   let (task, rx) = LocalTask::new(ctx, |rt| async move {
-    let lua = rt.lua();
-    let f: Function = lua.registry_value(&key)?;
-    let result: MultiValue = f.call_async(()).await?;
-    let table = lua.create_sequence_from(result)?;
-    lua.create_registry_value(table)
+    let task = async {
+      let lua = rt.lua();
+      let f: Function = lua.registry_value(&key)?;
+      let result: MultiValue = f.call_async(()).await?;
+      let table = lua.create_sequence_from(result)?;
+      lua.create_registry_value(table)
+    };
+    (Abortable::new(task, registration).await).unwrap_or_else(|_| Err(rt_error("task cancelled")))
   });
   {
     let mut x = lua.app_data_mut::<Vec<LocalTask>>().unwrap();
     x.push(task);
   }
-  Ok(rx)
+  let result = rx
+    .map(|x| Rc::new(x.map_err(rt_error).and_then(|x| *x)))
+    .boxed_local()
+    .shared();
+  Ok(LuaPromise(Rc::new(PromiseInner {
+    result,
+    abort,
+    close_table,
+    cancelled: Cell::new(false),
+  })))
+}
+
+/// Calls another service on this instance in-process, as if requesting it
+/// through HTTP.
+///
+/// Errors, including the callee's HTTP errors, are raised as HTTP errors in the
+/// caller.
+fn create_fn_call(lua: &Lua, caller: ServiceName, state: Arc<AbelState>) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let caller = caller.clone();
+    let state = state.clone();
+    async move {
+      let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let req = check_request(lua, args.pop_front(), 2)?;
+      let to_lua_error = |error: crate::Error| {
+        let kind = error.kind();
+        match http_error(lua, kind.status(), kind.error(), kind.detail()) {
+          Ok(x) | Err(x) => x,
+        }
+      };
+
+      let service = get_callee(&state, &caller, name.to_str()?).map_err(to_lua_error)?;
+      let path = req.uri.path().to_string();
+      let req = Request::<Body>::from(req);
+      let ctx = TaskContext::new_called(lua)?
+        .ok_or_else(|| rt_error_fmt!("calls nested deeper than {MAX_CALL_DEPTH} levels"))?;
+      let (task, rx) = LocalTask::new(ctx, |rt| async move {
+        let resp = rt.handle_request(service, &path, req).await?;
+        crate::Result::Ok(Response::<Body>::from(resp))
+      });
+      {
+        let mut x = lua.app_data_mut::<Vec<LocalTask>>().unwrap();
+        x.push(task);
+      }
+      let resp = (*rx.await.map_err(rt_error)?).map_err(to_lua_error)?;
+      Ok(LuaResponse::from_hyper(resp))
+    }
+  })
 }
 
 pub(crate) fn create_fn_spawn(lua: &Lua) -> mlua::Result<Function> {
@@ -102,48 +406,94 @@ pub(crate) fn create_fn_spawn(lua: &Lua) -> mlua::Result<Function> {
     let f: Function =
       check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 1, 1))?;
     let f = if args.is_empty() { f } else { f.bind(args)? };
-    let rx = abel_spawn(lua, f)?;
-    Ok(LuaPromise { inner: rx.boxed() })
+    abel_spawn(lua, f)
   })
 }
 
 fn create_fn_await_all(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function("abel:abel.await_all", |lua, args: MultiValue| async move {
-    let args = args
-      .into_iter()
-      .enumerate()
-      .map(|(i, x)| match x {
-        mlua::Value::UserData(u) => {
-          if let Ok(p) = u.take::<LuaPromise>() {
-            Ok(p.inner)
-          } else {
-            Err(tag_error(lua, i + 1, "Promise", "other userdata", 1))
-          }
-        }
-        mlua::Value::Function(f) => abel_spawn(lua, f).map(FutureExt::boxed),
-        #[rustfmt::skip]
-        _ => Err(tag_error(lua, i + 1, "Promise or function", x.type_name(), 1)),
-      })
-      .collect::<mlua::Result<Vec<_>>>()?;
-    let mut result = futures::future::join_all(args).await;
+    let promises = check_promises_or_spawn(lua, args)?;
+    let mut result = join_all(promises.iter().map(|(x, _)| x.wait())).await;
     let mut mv = result
       .pop()
-      .map(|x| {
-        lua
-          .registry_value::<Table>(&(*x.map_err(rt_error)?)?)?
-          .raw_sequence_values()
-          .collect::<mlua::Result<MultiValue>>()
-      })
+      .map(|x| unpack_result(lua, &x))
       .unwrap_or(Ok(MultiValue::new()))?;
     for x in result.into_iter().rev() {
-      let table = lua.registry_value::<Table>(&(*x.map_err(rt_error)?)?)?;
-      let value = table.raw_get(1).unwrap_or(Nil);
+      let value = unpack_result(lua, &x)?.pop_front().unwrap_or(Nil);
       mv.push_front(value)
     }
     Ok(mv)
   })
 }
 
+/// Waits for the first of the promises to settle, returning its values or
+/// raising its error.
+///
+/// Tasks spawned from functions passed in are cancelled once settled; promises
+/// passed in are left running.
+fn create_fn_race(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:abel.race", |lua, args: MultiValue| async move {
+    let promises = check_promises_or_spawn(lua, args)?;
+    if promises.is_empty() {
+      return Err(tag_error(lua, 1, "Promise or function", "no value", 1));
+    }
+    let (result, _, _) = select_all(promises.iter().map(|(x, _)| x.wait().boxed_local())).await;
+    cancel_spawned(lua, &promises)?;
+    unpack_result(lua, &result)
+  })
+}
+
+/// Waits for the first of the promises to be fulfilled, returning its values.
+///
+/// If all of them are rejected, the last error is raised. Spawned tasks are
+/// cancelled the same way as `abel.race`.
+fn create_fn_any(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:abel.any", |lua, args: MultiValue| async move {
+    let promises = check_promises_or_spawn(lua, args)?;
+    if promises.is_empty() {
+      return Err(tag_error(lua, 1, "Promise or function", "no value", 1));
+    }
+    let mut pending = (promises.iter())
+      .map(|(x, _)| x.wait().boxed_local())
+      .collect::<Vec<_>>();
+    loop {
+      let (result, _, rest) = select_all(pending).await;
+      if result.is_ok() || rest.is_empty() {
+        cancel_spawned(lua, &promises)?;
+        return unpack_result(lua, &result);
+      }
+      pending = rest;
+    }
+  })
+}
+
+/// Waits for a promise, or a function run as a new task, for at most the given
+/// milliseconds, raising an error if it takes longer.
+///
+/// The task is cancelled on timeout if it is spawned from a function.
+fn create_fn_timeout(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function(
+    "abel:abel.timeout",
+    |lua, mut args: MultiValue| async move {
+      let ms = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let ms = u64::try_from(ms).map_err(|_| arg_error(lua, 1, "timeout cannot be negative", 1))?;
+      let (promise, spawned) = match args.pop_front() {
+        Some(mlua::Value::Function(f)) => (abel_spawn(lua, f)?, true),
+        x => (check_promise(lua, x, 2)?, false),
+      };
+      tokio::select! {
+        result = promise.wait() => unpack_result(lua, &result),
+        _ = tokio::time::sleep(Duration::from_millis(ms)) => {
+          if spawned {
+            promise.cancel(lua)?;
+          }
+          Err(rt_error_fmt!("timed out after {ms}ms"))
+        }
+      }
+    },
+  )
+}
+
 fn create_fn_sleep(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function("abel:abel.sleep", |lua, mut args: MultiValue| async move {
     let ms = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
@@ -1,24 +1,31 @@
 pub(super) mod abel;
 
 mod logging;
+mod sync;
+#[cfg(test)]
+mod tests;
 
+use crate::event::{Event, Subscription};
 use crate::lua::error::rt_error_fmt;
 use crate::lua::http::{LuaRequest, LuaResponse};
 use crate::lua::isolate::Isolate;
-use crate::lua::sandbox::Sandbox;
+use crate::lua::sandbox::{Sandbox, ServiceHandles};
 use crate::lua::{sanitize_error, LuaTableExt};
-use crate::path::PathMatcher;
-use crate::service::{get_local_storage_path, RunningService};
-use crate::source::Source;
-use crate::task::TaskContext;
+use crate::path::{sort_routes, Route};
+use crate::queue::JobRecord;
+use crate::schedule::{Job, Schedule};
+use crate::service::{
+  get_job_queue, get_kv_store, get_local_storage_path, RunningService, ServiceImpl,
+};
+use crate::task::{IsolateMemory, MemoryQuota, TaskContext};
 use crate::ErrorKind::*;
 use crate::{AbelState, Result};
-use abel::side_effect_abel;
+use abel::{create_fn_run_middlewares, side_effect_abel};
 use clru::CLruCache;
 use hyper::{Body, Request};
 use log::{debug, info};
 use logging::side_effect_log;
-use mlua::{self, FromLuaMulti, Function, Table, TableExt, ToLuaMulti};
+use mlua::{self, FromLuaMulti, Function, LuaSerdeExt, Table, TableExt, ToLuaMulti};
 use nonzero_ext::nonzero;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -38,6 +45,13 @@ struct LoadedService {
   isolate: Isolate,
 }
 
+impl LoadedService {
+  /// Memory quota of a task running in this isolate.
+  fn memory_quota(&self, limit: usize) -> MemoryQuota {
+    MemoryQuota::new(self.isolate.memory.clone(), Some(limit))
+  }
+}
+
 impl Runtime {
   pub fn new(state: Arc<AbelState>) -> mlua::Result<Self> {
     let loaded = RefCell::new(CLruCache::new(nonzero!(16usize)));
@@ -49,6 +63,10 @@ impl Runtime {
     })
   }
 
+  pub(crate) fn state(&self) -> &AbelState {
+    &self.state
+  }
+
   async fn call_extract_error<'a, T, R>(&'a self, f: mlua::Value<'a>, v: T) -> Result<R>
   where
     T: ToLuaMulti<'a>,
@@ -69,64 +87,149 @@ impl Runtime {
     req: Request<Body>,
   ) -> Result<LuaResponse> {
     let guard = service.try_upgrade()?;
-    let (params, matcher) = guard
-      .paths
-      .iter()
-      .find_map(|m| m.gen_params(path).map(|p| (p, m)))
-      .ok_or_else(|| ServicePathNotFound {
-        service: guard.name.clone(),
-        path: path.into(),
-      })?;
+    let mut allowed = Vec::<Box<str>>::new();
+    let mut matched = None;
+    for route in guard.paths.iter() {
+      if let Some(params) = route.matcher().gen_params(path) {
+        if route.accepts(req.method()) {
+          matched = Some((route, params));
+          break;
+        }
+        let method = route.method().unwrap();
+        if method == "GET" {
+          allowed.push("HEAD".into());
+        }
+        allowed.push(method.into());
+      }
+    }
+    let (route, params) = match matched {
+      Some(x) => x,
+      None if allowed.is_empty() => {
+        return Err(From::from(ServicePathNotFound {
+          service: guard.name.clone(),
+          path: path.into(),
+        }))
+      }
+      None => {
+        allowed.sort_unstable();
+        allowed.dedup();
+        return Err(From::from(MethodNotAllowed {
+          got: req.method().as_str().into(),
+          allowed,
+        }));
+      }
+    };
 
     // `loaded` is a mapped, immutable, checked-at-runtime borrow from
     // `self.loaded`. Dropping it early here prevents `self.loaded` being borrowed
     // more than once at a time.
-    let internal = {
+    let (internal, memory) = {
       let loaded = self.load_service(service.clone()).await?;
-      self.get_internal(&loaded.isolate)?
+      let memory = loaded.memory_quota(guard.memory_limit);
+      (self.get_internal(&loaded.isolate)?, memory)
     };
+    TaskContext::reset_limits(self.lua(), guard.cpu_time.request, memory)?;
 
-    for f in internal
+    let handler = internal
       .raw_get_path::<Table>("<internal>", &["paths"])?
-      .sequence_values::<Table>()
-    {
-      let f = f?;
-      let path = f.raw_get::<u8, String>(1)?;
-      if path == matcher.as_str() {
-        let handler = f.raw_get::<u8, mlua::Value>(2)?;
+      .raw_get::<_, Table>(route.handler)?
+      .raw_get::<u8, mlua::Value>(2)?;
+    let middlewares = internal.raw_get_path::<Table>("<internal>", &["middlewares"])?;
 
-        // Request object in handler should be ephemeral, otherwise graceful shutdown
-        // would be blocked.
-        let req = self.lua().create_userdata(LuaRequest::new(req, params))?;
-        TaskContext::register(self.lua(), req.clone())?;
+    // Request object in handler should be ephemeral, otherwise graceful shutdown
+    // would be blocked.
+    let mut req = LuaRequest::new(req, params);
+    if let Some(max) = route.max_body_size {
+      req.limit_body(max)?;
+    }
+    let req = self.lua().create_userdata(req)?;
+    TaskContext::register(self.lua(), req.clone())?;
 
-        let resp = self.call_extract_error(handler, req).await?;
-        return Ok(resp);
-      }
+    if middlewares.raw_len() == 0 {
+      self.call_extract_error(handler, req).await
+    } else {
+      let run = create_fn_run_middlewares(self.lua())?;
+      let args = (middlewares, handler, req);
+      self
+        .call_extract_error(mlua::Value::Function(run), args)
+        .await
     }
-    unreachable!("path matched but no handler found")
   }
 
-  /// Extracts information from the code, but does not create the service yet
+  /// Extracts information from the code, but does not create the service yet.
+  ///
+  /// Routes, jobs, event subscriptions and queue workers found are filled into
+  /// the service's info. Routes are sorted by specificity; warnings about
+  /// duplicate routes are returned along with the isolate.
   pub(crate) async fn prepare_service(
     &self,
-    name: &str,
-    source: Source,
-  ) -> Result<(Vec<PathMatcher>, Isolate)> {
-    check_name(name)?;
-    let (isolate, internal) = self.run_source(name, source).await?;
+    service: &mut ServiceImpl,
+  ) -> Result<(Vec<String>, Isolate)> {
+    check_name(&service.name)?;
+    let (isolate, internal) = self.run_source(service).await?;
 
     let mut paths = Vec::new();
-    for f in internal
+    for (i, f) in internal
       .raw_get_path::<Table>("<internal>", &["paths"])?
       .sequence_values::<Table>()
+      .enumerate()
+    {
+      let f = f?;
+      let path = f.raw_get::<_, String>(1u8)?;
+      let options = f.raw_get::<_, Table>(3u8)?;
+      let method = options.raw_get::<_, Option<String>>("method")?;
+      let max_body_size = options.raw_get::<_, Option<u64>>("max_body_size")?;
+      let mut route = Route::new(&path, method.as_deref())?;
+      route.max_body_size = match (max_body_size, self.state.max_body_size) {
+        (Some(x), Some(max)) => Some(x.min(max)),
+        (x, _) => x.or(service.max_body_size),
+      };
+      route.handler = i + 1;
+      paths.push(route);
+    }
+    let warnings = sort_routes(&mut paths)?;
+
+    let mut jobs = Vec::new();
+    for (i, f) in internal
+      .raw_get_path::<Table>("<internal>", &["jobs"])?
+      .sequence_values::<Table>()
+      .enumerate()
+    {
+      let f = f?;
+      let schedule = match &*f.raw_get::<_, String>(1u8)? {
+        "every" => Schedule::every(f.raw_get(2u8)?)?,
+        _ => Schedule::cron(&f.raw_get::<_, String>(2u8)?)?,
+      };
+      let mut job = Job::new(schedule);
+      job.handler = i + 1;
+      jobs.push(job);
+    }
+
+    let mut subscriptions = Vec::new();
+    for (i, f) in internal
+      .raw_get_path::<Table>("<internal>", &["subscriptions"])?
+      .sequence_values::<Table>()
+      .enumerate()
+    {
+      let mut subscription = Subscription::new(f?.raw_get::<_, String>(1u8)?);
+      subscription.handler = i + 1;
+      subscriptions.push(subscription);
+    }
+
+    let mut workers = Vec::new();
+    for kv in internal
+      .raw_get_path::<Table>("<internal>", &["workers"])?
+      .pairs::<String, mlua::Value>()
     {
-      let path = f?.raw_get::<_, String>(1u8)?;
-      let path = PathMatcher::new(&path)?;
-      paths.push(path);
+      workers.push(kv?.0);
     }
+    workers.sort();
 
-    Ok((paths, isolate))
+    service.info.paths = paths;
+    service.info.jobs = jobs;
+    service.info.subscriptions = subscriptions;
+    service.info.workers = workers;
+    Ok((warnings, isolate))
   }
 
   pub(crate) async fn create_service(
@@ -152,40 +255,141 @@ impl Runtime {
 
   pub(crate) async fn run_start(&self, service: RunningService) -> Result<()> {
     // TODO: check validity
-    let start_fn: Option<Function> = {
+    let (cpu_time, memory) = {
+      let guard = service.try_upgrade()?;
+      (guard.cpu_time.lifecycle, guard.memory_limit)
+    };
+    let (start_fn, memory): (Option<Function>, _) = {
       let loaded = self.load_service(service).await?;
-      self
-        .get_local_env(&loaded.isolate)?
-        .raw_get_path("<local_env>", &["abel", "start"])?
+      let local_env = self.get_local_env(&loaded.isolate)?;
+      let f = local_env.raw_get_path("<local_env>", &["abel", "start"])?;
+      (f, loaded.memory_quota(memory))
     };
     if let Some(f) = start_fn {
-      f.call_async(()).await.map_err(sanitize_error)?;
+      TaskContext::reset_limits(self.lua(), cpu_time, memory)?;
+      f.call_async::<_, ()>(()).await.map_err(sanitize_error)?;
     }
     Ok(())
   }
 
   pub(crate) async fn run_stop(&self, service: RunningService) -> Result<()> {
-    let stop_fn: Option<Function> = {
+    let (cpu_time, memory) = {
+      let guard = service.try_upgrade()?;
+      (guard.cpu_time.lifecycle, guard.memory_limit)
+    };
+    let (stop_fn, memory): (Option<Function>, _) = {
       let loaded = self.load_service(service).await?;
-      self
-        .get_local_env(&loaded.isolate)?
-        .raw_get_path("<local_env>", &["abel", "stop"])?
+      let local_env = self.get_local_env(&loaded.isolate)?;
+      let f = local_env.raw_get_path("<local_env>", &["abel", "stop"])?;
+      (f, loaded.memory_quota(memory))
     };
     if let Some(f) = stop_fn {
-      f.call_async(()).await.map_err(sanitize_error)?;
+      TaskContext::reset_limits(self.lua(), cpu_time, memory)?;
+      f.call_async::<_, ()>(()).await.map_err(sanitize_error)?;
     }
     // Call modules' `stop`
     Ok(())
   }
 
-  async fn run_source<'a>(&'a self, name: &str, source: Source) -> Result<(Isolate, Table<'a>)> {
+  pub(crate) async fn run_job(&self, service: RunningService, handler: usize) -> Result<()> {
+    let (cpu_time, memory) = {
+      let guard = service.try_upgrade()?;
+      (guard.cpu_time.request, guard.memory_limit)
+    };
+    let (f, memory) = {
+      let loaded = self.load_service(service).await?;
+      let f = self
+        .get_internal(&loaded.isolate)?
+        .raw_get_path::<Table>("<internal>", &["jobs"])?
+        .raw_get::<_, Table>(handler)?
+        .raw_get::<u8, mlua::Value>(3)?;
+      (f, loaded.memory_quota(memory))
+    };
+    TaskContext::reset_limits(self.lua(), cpu_time, memory)?;
+    self.call_extract_error(f, ()).await
+  }
+
+  pub(crate) async fn run_worker(
+    &self,
+    service: RunningService,
+    id: u64,
+    job: &JobRecord,
+  ) -> Result<()> {
+    let (cpu_time, memory) = {
+      let guard = service.try_upgrade()?;
+      (guard.cpu_time.request, guard.memory_limit)
+    };
+    let (f, memory) = {
+      let loaded = self.load_service(service).await?;
+      let f = self
+        .get_internal(&loaded.isolate)?
+        .raw_get_path::<Table>("<internal>", &["workers"])?
+        .raw_get::<_, mlua::Value>(&*job.worker)?;
+      (f, loaded.memory_quota(memory))
+    };
+    if let mlua::Value::Nil = f {
+      return Err(rt_error_fmt!("no worker named '{}'", job.worker).into());
+    }
+    let lua = self.lua();
+    let payload = lua.to_value(&job.payload)?;
+    let info = lua.create_table_from([
+      ("id", mlua::Value::Integer(id as _)),
+      ("worker", lua.pack(&*job.worker)?),
+      ("attempt", mlua::Value::Integer(job.attempts as i64 + 1)),
+    ])?;
+    TaskContext::reset_limits(lua, cpu_time, memory)?;
+    self.call_extract_error(f, (payload, info)).await
+  }
+
+  pub(crate) async fn run_subscriber(
+    &self,
+    service: RunningService,
+    handler: usize,
+    topic: &str,
+    event: Event,
+  ) -> Result<()> {
+    let (cpu_time, memory) = {
+      let guard = service.try_upgrade()?;
+      (guard.cpu_time.request, guard.memory_limit)
+    };
+    let (f, memory) = {
+      let loaded = self.load_service(service).await?;
+      let f = self
+        .get_internal(&loaded.isolate)?
+        .raw_get_path::<Table>("<internal>", &["subscriptions"])?
+        .raw_get::<_, Table>(handler)?
+        .raw_get::<u8, mlua::Value>(2)?;
+      (f, loaded.memory_quota(memory))
+    };
+    let value = self.lua().to_value(&*event)?;
+    TaskContext::reset_limits(self.lua(), cpu_time, memory)?;
+    self.call_extract_error(f, (value, topic)).await
+  }
+
+  async fn run_source<'a>(&'a self, service: &ServiceImpl) -> Result<(Isolate, Table<'a>)> {
+    let name = &*service.name;
     let local_storage_path = get_local_storage_path(&self.state, name);
-    let isolate = self
-      .isolate_builder_with_stdlib(source.clone(), local_storage_path)?
-      .add_side_effect(side_effect_abel)?
+    let memory = Arc::new(IsolateMemory::new(service.memory_usage.clone()));
+    let quota = MemoryQuota::new(memory.clone(), Some(service.memory_limit));
+    TaskContext::reset_limits(self.lua(), service.cpu_time.load, quota)?;
+    let mut isolate = self
+      .isolate_builder_with_stdlib(
+        service.source.clone(),
+        local_storage_path,
+        ServiceHandles {
+          env: service.env.clone(),
+          kv: get_kv_store(&self.state, name),
+          sql_max_rows: service.sql_max_rows,
+          queue: get_job_queue(&self.state, name),
+          http_client: (self.state.http_client).with_egress_rules(service.egress.as_deref()),
+          argon2_permits: service.argon2_permits.clone(),
+        },
+      )?
+      .add_side_effect(side_effect_abel(name, self.state.clone()))?
       .add_side_effect(side_effect_log(name))?
       .build()?;
-    self.run_isolate(&isolate, "main.lua", ()).await?;
+    isolate.memory = memory;
+    self.run_isolate::<_, ()>(&isolate, "main.lua", ()).await?;
 
     let internal = self.get_internal(&isolate)?;
     internal.raw_set("sealed", true)?;
@@ -217,8 +421,7 @@ impl Runtime {
         std::thread::current().name().unwrap_or("<unnamed>")
       );
     }
-    let source = service_guard.source();
-    let (isolate, _) = self.run_source(name, source.clone()).await?;
+    let (isolate, _) = self.run_source(&service_guard).await?;
 
     let loaded = LoadedService {
       service: service.clone(),
@@ -231,6 +434,17 @@ impl Runtime {
     Ok(Ref::map(self.loaded.borrow(), |x| x.peek(name).unwrap()))
   }
 
+  /// Drops the cached isolate of a service, collecting it so that handles it
+  /// holds (e.g. to its KV store) are dropped as well.
+  pub fn unload_service(&self, name: &str) -> mlua::Result<()> {
+    let loaded = self.loaded.borrow_mut().pop(name);
+    if let Some(loaded) = loaded {
+      self.remove_isolate(loaded.isolate)?;
+      self.lua().gc_collect()?;
+    }
+    Ok(())
+  }
+
   pub fn cleanup(&self) {
     let mut count = 0;
     self.loaded.borrow_mut().retain(|_, v| {
@@ -0,0 +1,459 @@
+//! Synchronization primitives between tasks spawned on the same worker.
+//!
+//! All of them live in one Lua state, so they cannot be shared with other
+//! workers or services.
+
+use crate::lua::error::{arg_error, check_integer, check_userdata, rt_error, tag_handler};
+use crate::lua::stream::create_table_stream;
+use crate::lua::LuaCacheExt;
+use crate::task::TaskContext;
+use mlua::Value::Nil;
+use mlua::{
+  AnyUserData, Function, Integer, Lua, MultiValue, RegistryKey, Table, UserData, UserDataFields,
+  UserDataMethods,
+};
+use std::cell::{Cell, RefCell};
+use std::rc::{self, Rc};
+use std::sync::Arc;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// Same as Tokio's limit of permits in a semaphore.
+const MAX_PERMITS: usize = usize::MAX >> 3;
+
+/// State of a channel, whose buffered items are kept in a table shared by its
+/// senders and receiver as their user value.
+struct Channel {
+  /// Index of the first buffered item in the table.
+  head: Cell<Integer>,
+  /// Index after the last buffered item in the table.
+  tail: Cell<Integer>,
+  capacity: usize,
+  senders: Cell<usize>,
+  /// Whether the receiver is closed.
+  closed: Cell<bool>,
+  readable: Notify,
+  writable: Notify,
+}
+
+impl Channel {
+  fn len(&self) -> usize {
+    (self.tail.get() - self.head.get()) as usize
+  }
+
+  async fn send<'lua>(&self, buffer: Table<'lua>, item: mlua::Value<'lua>) -> mlua::Result<()> {
+    loop {
+      if self.closed.get() {
+        // Items left by a receiver dropped without closing
+        self.clear(&buffer)?;
+        return Err(rt_error("channel closed"));
+      }
+      if self.len() < self.capacity {
+        buffer.raw_set(self.tail.get(), item)?;
+        self.tail.set(self.tail.get() + 1);
+        self.readable.notify_one();
+        return Ok(());
+      }
+      self.writable.notified().await;
+    }
+  }
+
+  async fn recv<'lua>(&self, buffer: Table<'lua>) -> mlua::Result<mlua::Value<'lua>> {
+    loop {
+      let head = self.head.get();
+      if head < self.tail.get() {
+        let item = buffer.raw_get(head)?;
+        buffer.raw_set(head, Nil)?;
+        self.head.set(head + 1);
+        self.writable.notify_one();
+        return Ok(item);
+      }
+      if self.senders.get() == 0 || self.closed.get() {
+        return Ok(Nil);
+      }
+      self.readable.notified().await;
+    }
+  }
+
+  fn clear(&self, buffer: &Table) -> mlua::Result<()> {
+    for i in self.head.get()..self.tail.get() {
+      buffer.raw_set(i, Nil)?;
+    }
+    self.head.set(self.tail.get());
+    Ok(())
+  }
+}
+
+/// Keeps the channel open for sending while alive.
+struct SenderHandle(Rc<Channel>);
+
+impl SenderHandle {
+  fn new(chan: Rc<Channel>) -> Self {
+    chan.senders.set(chan.senders.get() + 1);
+    Self(chan)
+  }
+}
+
+impl Drop for SenderHandle {
+  fn drop(&mut self) {
+    let senders = self.0.senders.get() - 1;
+    self.0.senders.set(senders);
+    if senders == 0 {
+      self.0.readable.notify_waiters();
+    }
+  }
+}
+
+struct ReceiverHandle(Rc<Channel>);
+
+impl ReceiverHandle {
+  fn close(self, buffer: &Table) -> mlua::Result<()> {
+    self.0.clear(buffer)
+  }
+}
+
+impl Drop for ReceiverHandle {
+  fn drop(&mut self) {
+    self.0.closed.set(true);
+    self.0.writable.notify_waiters();
+  }
+}
+
+/// Sending half of a channel, following the sink protocol.
+///
+/// - `sender:write(item)` waits until there is room in the channel; raises an
+///   error if the receiver is closed.
+/// - `sender:clone() -> Sender`
+/// - `sender:close()`; the receiver reaches its end once every sender is
+///   closed.
+pub struct LuaSender(RefCell<Option<SenderHandle>>);
+
+impl LuaSender {
+  fn chan(&self) -> mlua::Result<Rc<Channel>> {
+    (self.0.borrow().as_ref())
+      .map(|x| x.0.clone())
+      .ok_or_else(|| rt_error("sender is closed"))
+  }
+}
+
+impl UserData for LuaSender {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self<'lua>(
+      lua: &'lua Lua,
+      value: Option<mlua::Value<'lua>>,
+    ) -> mlua::Result<(Rc<Channel>, Table<'lua>)> {
+      let this = check_userdata::<LuaSender>(value, "sender").map_err(tag_handler(lua, 1, 1))?;
+      let chan = this.with_borrowed(|x| x.chan())?;
+      Ok((chan, this.borrow_userdata().get_user_value()?))
+    }
+
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      this.borrow::<Self>()?.0.borrow_mut().take();
+      Ok(())
+    });
+
+    methods.add_async_function("write", |lua, mut args: MultiValue| async move {
+      let (chan, buffer) = check_self(lua, args.pop_front())?;
+      let item = match args.pop_front() {
+        Some(Nil) | None => return Err(arg_error(lua, 2, "cannot send nil", 1)),
+        Some(item) => item,
+      };
+      chan.send(buffer, item).await
+    });
+
+    methods.add_function("clone", |lua, mut args: MultiValue| {
+      let (chan, buffer) = check_self(lua, args.pop_front())?;
+      let sender = lua.create_userdata(Self(RefCell::new(Some(SenderHandle::new(chan)))))?;
+      sender.set_user_value(buffer)?;
+      Ok(sender)
+    });
+
+    methods.add_function("close", |lua, mut args: MultiValue| {
+      check_userdata::<Self>(args.pop_front(), "sender")
+        .map_err(tag_handler(lua, 1, 1))?
+        .with_borrowed(|x| x.0.borrow_mut().take());
+      Ok(())
+    });
+  }
+}
+
+/// Receiving half of a channel, following the stream protocol.
+///
+/// - `receiver:read() -> any?` waits for the next item, returning `nil` once
+///   every sender is closed and the channel is drained.
+/// - `receiver:close()` drops buffered items and rejects further sends.
+pub struct LuaReceiver(RefCell<Option<ReceiverHandle>>);
+
+impl LuaReceiver {
+  fn close(this: AnyUserData) -> mlua::Result<()> {
+    if let Some(handle) = this.borrow::<Self>()?.0.borrow_mut().take() {
+      handle.close(&this.get_user_value()?)?;
+    }
+    Ok(())
+  }
+}
+
+impl UserData for LuaReceiver {
+  fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+    fields.add_meta_field_with("__index", create_table_stream);
+  }
+
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| Self::close(this));
+
+    methods.add_async_function("read", |lua, mut args: MultiValue| async move {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "receiver").map_err(tag_handler(lua, 1, 1))?;
+      match this.with_borrowed(|x| x.0.borrow().as_ref().map(|x| x.0.clone())) {
+        Some(chan) => chan.recv(this.borrow_userdata().get_user_value()?).await,
+        None => Ok(Nil),
+      }
+    });
+
+    methods.add_function("close", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "receiver").map_err(tag_handler(lua, 1, 1))?;
+      Self::close(this.into_any())
+    });
+  }
+}
+
+/// Creates a channel, returning its sender and receiver.
+///
+/// Without `capacity`, the channel is unbounded.
+pub(crate) fn create_fn_channel(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_function("abel:abel.channel", |lua, mut args: MultiValue| {
+    let capacity = match args.pop_front() {
+      Some(Nil) | None => usize::MAX,
+      capacity => {
+        let capacity = check_integer(capacity).map_err(tag_handler(lua, 1, 1))?;
+        usize::try_from(capacity)
+          .ok()
+          .filter(|x| *x > 0)
+          .ok_or_else(|| arg_error(lua, 1, "capacity must be positive", 1))?
+      }
+    };
+    let chan = Rc::new(Channel {
+      head: Cell::new(1),
+      tail: Cell::new(1),
+      capacity,
+      senders: Cell::new(0),
+      closed: Cell::new(false),
+      readable: Notify::new(),
+      writable: Notify::new(),
+    });
+    let buffer = lua.create_table()?;
+    let tx = LuaSender(RefCell::new(Some(SenderHandle::new(chan.clone()))));
+    let tx = lua.create_userdata(tx)?;
+    tx.set_user_value(buffer.clone())?;
+    let rx = LuaReceiver(RefCell::new(Some(ReceiverHandle(chan))));
+    let rx = lua.create_userdata(rx)?;
+    rx.set_user_value(buffer)?;
+    Ok((tx, rx))
+  })
+}
+
+/// Permits held from a mutex or semaphore, released when closed or with
+/// `guard:release()`.
+///
+/// It is also released when the task holding it finishes or is cancelled.
+pub struct LuaGuard {
+  permit: RefCell<Option<OwnedSemaphorePermit>>,
+  /// Close table of the task that acquired it, which it leaves when released.
+  close_table: Option<rc::Weak<RegistryKey>>,
+}
+
+impl LuaGuard {
+  fn register(lua: &Lua, permit: OwnedSemaphorePermit) -> mlua::Result<AnyUserData<'_>> {
+    let guard = lua.create_userdata(Self {
+      permit: RefCell::new(Some(permit)),
+      close_table: TaskContext::current_close_table(lua),
+    })?;
+    TaskContext::register(lua, guard.clone())?;
+    Ok(guard)
+  }
+
+  fn release<'lua>(lua: &'lua Lua, this: AnyUserData<'lua>) -> mlua::Result<()> {
+    let close_table = {
+      let this = this.borrow::<Self>()?;
+      let released = this.permit.borrow_mut().take().is_some();
+      this.close_table.clone().filter(|_| released)
+    };
+    if let Some(close_table) = close_table {
+      TaskContext::unregister(lua, &close_table, this)?;
+    }
+    Ok(())
+  }
+}
+
+impl UserData for LuaGuard {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", Self::release);
+
+    methods.add_function("release", |lua, mut args: MultiValue| {
+      let this = check_userdata::<Self>(args.pop_front(), "guard")
+        .map_err(tag_handler(lua, 1, 1))?
+        .into_any();
+      Self::release(lua, this)
+    });
+  }
+}
+
+fn check_permits(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<u32> {
+  match value {
+    Some(Nil) | None => Ok(1),
+    value => {
+      let n = check_integer(value).map_err(tag_handler(lua, pos, 1))?;
+      u32::try_from(n).map_err(|_| arg_error(lua, pos, "number of permits out of range", 1))
+    }
+  }
+}
+
+/// - `mutex:lock() -> Guard` waits until the mutex is unlocked.
+/// - `mutex:try_lock() -> Guard?`
+/// - `mutex:is_locked() -> boolean`
+pub struct LuaMutex(Arc<Semaphore>);
+
+impl UserData for LuaMutex {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<Arc<Semaphore>> {
+      check_userdata::<LuaMutex>(value, "mutex")
+        .map(|x| x.with_borrowed(|x| x.0.clone()))
+        .map_err(tag_handler(lua, 1, 1))
+    }
+
+    methods.add_async_function("lock", |lua, mut args: MultiValue| async move {
+      let this = check_self(lua, args.pop_front())?;
+      let permit = this.acquire_owned().await.map_err(rt_error)?;
+      LuaGuard::register(lua, permit)
+    });
+
+    methods.add_function("try_lock", |lua, mut args: MultiValue| {
+      let this = check_self(lua, args.pop_front())?;
+      (this.try_acquire_owned().ok())
+        .map(|permit| LuaGuard::register(lua, permit))
+        .transpose()
+    });
+
+    methods.add_function("is_locked", |lua, mut args: MultiValue| {
+      Ok(check_self(lua, args.pop_front())?.available_permits() == 0)
+    });
+  }
+}
+
+pub(crate) fn create_fn_mutex(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_function("abel:abel.Mutex", |_lua, ()| {
+    Ok(LuaMutex(Arc::new(Semaphore::new(1))))
+  })
+}
+
+/// - `semaphore:acquire(n?: integer) -> Guard` waits until `n` (default 1)
+///   permits are available; `n` must not exceed the semaphore's total permits.
+/// - `semaphore:try_acquire(n?: integer) -> Guard?`
+/// - `semaphore:available() -> integer`
+/// - `semaphore:add(n: integer)` adds permits to the semaphore.
+pub struct LuaSemaphore {
+  inner: Arc<Semaphore>,
+  /// Permits both available and held, which must not exceed [`MAX_PERMITS`]
+  /// once held ones are released.
+  total: Cell<usize>,
+}
+
+impl UserData for LuaSemaphore {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<Arc<Semaphore>> {
+      check_userdata::<LuaSemaphore>(value, "semaphore")
+        .map(|x| x.with_borrowed(|x| x.inner.clone()))
+        .map_err(tag_handler(lua, 1, 1))
+    }
+
+    methods.add_async_function("acquire", |lua, mut args: MultiValue| async move {
+      let (this, total) = check_userdata::<Self>(args.pop_front(), "semaphore")
+        .map_err(tag_handler(lua, 1, 1))?
+        .with_borrowed(|x| (x.inner.clone(), x.total.get()));
+      let n = check_permits(lua, args.pop_front(), 2)?;
+      if n as usize > total {
+        return Err(arg_error(lua, 2, "more permits than the semaphore has", 1));
+      }
+      let permit = this.acquire_many_owned(n).await.map_err(rt_error)?;
+      LuaGuard::register(lua, permit)
+    });
+
+    methods.add_function("try_acquire", |lua, mut args: MultiValue| {
+      let this = check_self(lua, args.pop_front())?;
+      let n = check_permits(lua, args.pop_front(), 2)?;
+      (this.try_acquire_many_owned(n).ok())
+        .map(|permit| LuaGuard::register(lua, permit))
+        .transpose()
+    });
+
+    methods.add_function("available", |lua, mut args: MultiValue| {
+      Ok(check_self(lua, args.pop_front())?.available_permits())
+    });
+
+    methods.add_function("add", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "semaphore").map_err(tag_handler(lua, 1, 1))?;
+      let n = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      this.with_borrowed(|this| {
+        let total = (usize::try_from(n).ok())
+          .and_then(|n| this.total.get().checked_add(n))
+          .filter(|x| *x <= MAX_PERMITS)
+          .ok_or_else(|| arg_error(lua, 2, "number of permits out of range", 1))?;
+        this.total.set(total);
+        this.inner.add_permits(n as usize);
+        Ok(())
+      })
+    });
+  }
+}
+
+pub(crate) fn create_fn_semaphore(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_function("abel:abel.Semaphore", |lua, mut args: MultiValue| {
+    let permits = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+    let permits = usize::try_from(permits)
+      .ok()
+      .filter(|x| *x <= MAX_PERMITS)
+      .ok_or_else(|| arg_error(lua, 1, "number of permits out of range", 1))?;
+    Ok(LuaSemaphore {
+      inner: Arc::new(Semaphore::new(permits)),
+      total: Cell::new(permits),
+    })
+  })
+}
+
+/// - `notify:wait()` waits until notified.
+/// - `notify:notify_one()` wakes up one waiting task, or the next one to wait
+///   if there is none.
+/// - `notify:notify_all()` wakes up all tasks currently waiting.
+pub struct LuaNotify(Rc<Notify>);
+
+impl UserData for LuaNotify {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<Rc<Notify>> {
+      check_userdata::<LuaNotify>(value, "notify")
+        .map(|x| x.with_borrowed(|x| x.0.clone()))
+        .map_err(tag_handler(lua, 1, 1))
+    }
+
+    methods.add_async_function("wait", |lua, mut args: MultiValue| async move {
+      check_self(lua, args.pop_front())?.notified().await;
+      Ok(())
+    });
+
+    methods.add_function("notify_one", |lua, mut args: MultiValue| {
+      check_self(lua, args.pop_front())?.notify_one();
+      Ok(())
+    });
+
+    methods.add_function("notify_all", |lua, mut args: MultiValue| {
+      check_self(lua, args.pop_front())?.notify_waiters();
+      Ok(())
+    });
+  }
+}
+
+pub(crate) fn create_fn_notify(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_function("abel:abel.Notify", |_lua, ()| {
+    Ok(LuaNotify(Rc::new(Notify::new())))
+  })
+}
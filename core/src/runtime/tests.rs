@@ -0,0 +1,1317 @@
+//! Tests running services on a whole Abel instance.
+
+use crate::source::{Metadata, Source, SourceVfs};
+use crate::{
+  Abel, AbelOptions, Config, CpuTimeConfig, ErrorKind, Result, DEFAULT_STORAGE_CLOSE_TIMEOUT,
+};
+use async_trait::async_trait;
+use hyper::header::LOCATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io;
+
+/// Source with only `main.lua`.
+struct MainSource(&'static str);
+
+#[async_trait]
+impl SourceVfs for MainSource {
+  type File = Cursor<&'static [u8]>;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    match path {
+      "main.lua" => Ok(Cursor::new(self.0.as_bytes())),
+      _ => Err(io::ErrorKind::NotFound.into()),
+    }
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    Ok(path == "main.lua")
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    match path {
+      "main.lua" => Ok(Metadata::File {
+        size: self.0.len() as _,
+      }),
+      _ => Err(io::ErrorKind::NotFound.into()),
+    }
+  }
+}
+
+pub(crate) struct TestAbel {
+  pub abel: Arc<Abel>,
+  local_storage: TempDir,
+}
+
+impl TestAbel {
+  pub fn new() -> Self {
+    Self::with_options(|_| {})
+  }
+
+  pub fn with_options(f: impl FnOnce(&mut AbelOptions)) -> Self {
+    let local_storage = TempDir::new().unwrap();
+    let mut options = AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path: local_storage.path().into(),
+      remote_cache_path: None,
+      default_cpu_time: Duration::from_secs(5),
+      max_cpu_time: Duration::from_secs(5),
+      default_memory_limit: 64 * 1024 * 1024,
+      max_memory_limit: 64 * 1024 * 1024,
+      default_sql_max_rows: 100,
+      max_sql_max_rows: 1000,
+      max_body_size: None,
+      http_client: Default::default(),
+      egress: Default::default(),
+      storage_close_timeout: DEFAULT_STORAGE_CLOSE_TIMEOUT,
+    };
+    f(&mut options);
+    Self {
+      abel: Arc::new(Abel::new(options).unwrap()),
+      local_storage,
+    }
+  }
+
+  pub fn local_storage_path(&self) -> &Path {
+    self.local_storage.path()
+  }
+
+  /// Creates and starts a service, failing if it does not start.
+  pub async fn create(&self, name: &str, code: &'static str, config: Config) -> Result<()> {
+    let source = Source::new(MainSource(code));
+    let (_, _, error_payload) = (self.abel)
+      .cold_update_or_create_service(name, None, source, config)
+      .await?;
+    match error_payload.start {
+      Some(error) => Err(error),
+      None => Ok(()),
+    }
+  }
+
+  /// Sends a request to a service, returning the response's status and body.
+  pub async fn request(&self, name: &str, req: Request<Body>) -> Result<(StatusCode, String)> {
+    let service = self.abel.get_running_service(name)?;
+    let path = req.uri().path().to_string();
+    let resp = self.abel.run_service(service, path, req).await?;
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    Ok((status, String::from_utf8_lossy(&body).into()))
+  }
+
+  pub async fn get(&self, name: &str, path: &str) -> Result<(StatusCode, String)> {
+    let req = Request::get(path).body(Body::empty()).unwrap();
+    self.request(name, req).await
+  }
+
+  /// Serves a service over HTTP on a random local port, for requests that
+  /// need a real connection, e.g. WebSocket upgrades.
+  pub fn serve(&self, name: &str) -> SocketAddr {
+    let abel = self.abel.clone();
+    let service = abel.get_running_service(name).unwrap();
+    let make_svc = make_service_fn(move |_| {
+      let (abel, service) = (abel.clone(), service.clone());
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+          let (abel, service) = (abel.clone(), service.clone());
+          async move {
+            let path = req.uri().path().to_string();
+            abel.run_service(service, path, req).await
+          }
+        }))
+      }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+  }
+}
+
+/// Runs the code as the handler of `GET /`, which should return "ok".
+async fn run_handler(code: &'static str) {
+  let abel = TestAbel::new();
+  abel.create("test", code, Config::default()).await.unwrap();
+  match abel.get("test", "/").await {
+    Ok((status, body)) => assert_eq!((status, &*body), (StatusCode::OK, "ok")),
+    Err(error) => panic!("{error}"),
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_channel() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local tx, rx = abel.channel(2)
+      local tx2 = tx:clone()
+      local producer = abel.spawn(function()
+        for i = 1, 5 do tx:write(i) end
+        tx:close()
+      end)
+      tx2:write(0)
+      tx2:close()
+
+      local items = {}
+      for item in rx:iter() do
+        items[#items + 1] = item
+      end
+      producer:await()
+      assert(#items == 6, "channel should be drained before ending")
+      assert(rx:read() == nil)
+      assert(not pcall(tx.write, tx, 1), "closed sender should not write")
+
+      local tx, rx = abel.channel()
+      tx:write "dropped"
+      rx:close()
+      assert(not pcall(tx.write, tx, "x"), "writing to closed receiver should fail")
+      assert(rx:read() == nil)
+      assert(not pcall(abel.channel, 0))
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mutex() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local mutex = abel.Mutex()
+      local log = {}
+      local guard = mutex:lock()
+      assert(mutex:is_locked())
+      assert(mutex:try_lock() == nil)
+      local waiter = abel.spawn(function()
+        local _ <close> = mutex:lock()
+        log[#log + 1] = "waiter"
+      end)
+      abel.sleep(10)
+      log[#log + 1] = "holder"
+      guard:release()
+      waiter:await()
+      assert(table.concat(log, ",") == "holder,waiter")
+      assert(not mutex:is_locked(), "guard should be released when closed")
+
+      -- Guards held by cancelled tasks are released
+      local holder = abel.spawn(function()
+        local _ = mutex:lock()
+        abel.sleep(100000)
+      end)
+      abel.sleep(10)
+      assert(mutex:is_locked())
+      holder:cancel()
+      assert(not mutex:is_locked())
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_semaphore() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local sem = abel.Semaphore(2)
+      local a = sem:acquire()
+      local b = sem:try_acquire()
+      assert(b and sem:available() == 0)
+      assert(sem:try_acquire() == nil)
+      a:release()
+      b:release()
+      assert(sem:available() == 2)
+      assert(sem:try_acquire(3) == nil)
+      assert(not pcall(sem.acquire, sem, 3), "acquiring more than total permits should fail")
+
+      sem:add(1)
+      local c = sem:acquire(3)
+      assert(sem:available() == 0)
+      c:release()
+
+      -- Held permits count towards the limit
+      local max_permits = -1 >> 3
+      local guard = sem:acquire(1)
+      assert(sem:available() == 2)
+      assert(not pcall(sem.add, sem, max_permits - 2))
+      sem:add(max_permits - 3)
+      guard:release()
+      assert(not pcall(sem.add, sem, 1))
+      assert(not pcall(sem.add, sem, -1))
+      assert(not pcall(abel.Semaphore, -1))
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_guards_released_in_long_task() {
+  let abel = TestAbel::new();
+  let config = Config {
+    memory_limit: Some(2 * 1024 * 1024),
+    ..Default::default()
+  };
+  let code = r#"
+    abel.listen("/", function()
+      local mutex = abel.Mutex()
+      local sem = abel.Semaphore(1)
+      for _ = 1, 20000 do
+        mutex:try_lock():release()
+        local _ <close> = sem:try_acquire()
+      end
+      return "ok"
+    end)
+  "#;
+  abel.create("test", code, config).await.unwrap();
+  let (status, body) = abel.get("test", "/").await.unwrap();
+  assert_eq!((status, &*body), (StatusCode::OK, "ok"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_notify() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local notify = abel.Notify()
+      local woken = 0
+      local waiters = {}
+      for i = 1, 3 do
+        waiters[i] = abel.spawn(function()
+          notify:wait()
+          woken = woken + 1
+        end)
+      end
+      abel.sleep(10)
+      notify:notify_one()
+      abel.sleep(10)
+      assert(woken == 1)
+      notify:notify_all()
+      abel.await_all(table.unpack(waiters))
+      assert(woken == 3)
+
+      -- A permit is stored for the next waiter if no one is waiting
+      notify:notify_one()
+      abel.timeout(100, function() notify:wait() end)
+      assert(not pcall(abel.timeout, 10, function() notify:wait() end))
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory_limit() {
+  let abel = TestAbel::new();
+  let config = Config {
+    memory_limit: Some(8 * 1024 * 1024),
+    ..Default::default()
+  };
+  let code = r#"
+    local cache = {}
+    abel.listen("/big", function()
+      local t = {}
+      for i = 1, 10000000 do t[i] = i end
+      cache[#cache + 1] = t
+    end)
+    abel.listen("/", function() return "ok" end)
+  "#;
+  abel.create("test", code, config).await.unwrap();
+
+  let error = abel.get("test", "/big").await.unwrap_err();
+  assert!(
+    matches!(error.kind(), ErrorKind::MemoryLimitExceeded),
+    "{error}"
+  );
+  for _ in 0..3 {
+    let (status, body) = abel.get("test", "/").await.unwrap();
+    assert_eq!((status, &*body), (StatusCode::OK, "ok"));
+  }
+
+  let service = abel.abel.get_running_service("test").unwrap();
+  let memory_usage = service.upgrade().memory_usage();
+  assert!(memory_usage > 0 && memory_usage < 8 * 1024 * 1024);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory_limit_retained() {
+  let abel = TestAbel::new();
+  let config = Config {
+    memory_limit: Some(8 * 1024 * 1024),
+    ..Default::default()
+  };
+  let code = r#"
+    local cache = {}
+    abel.listen("/grow", function()
+      cache[#cache + 1] = string.rep("x", 1024 * 1024)
+      return tostring(#cache)
+    end)
+  "#;
+  abel.create("test", code, config).await.unwrap();
+  let service = abel.abel.get_running_service("test").unwrap();
+
+  for i in 1..=3 {
+    let (_, body) = abel.get("test", "/grow").await.unwrap();
+    assert_eq!(body, i.to_string());
+  }
+  let memory_usage = service.upgrade().memory_usage();
+  assert!(memory_usage >= 3 * 1024 * 1024, "{memory_usage}");
+
+  let error = loop {
+    match abel.get("test", "/grow").await {
+      Ok((_, body)) => assert!(body.parse::<usize>().unwrap() < 8),
+      Err(error) => break error,
+    }
+  };
+  assert!(
+    matches!(error.kind(), ErrorKind::MemoryLimitExceeded),
+    "{error}"
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory_limit_garbage_collected_elsewhere() {
+  let abel = TestAbel::new();
+  let config = || Config {
+    memory_limit: Some(8 * 1024 * 1024),
+    ..Default::default()
+  };
+  let code_a = r#"
+    abel.listen("/", function()
+      local garbage = {}
+      for i = 1, 3 do garbage[i] = string.rep(tostring(i), 1024 * 1024) end
+      return "a"
+    end)
+  "#;
+  let code_b = r#"
+    local kept = string.rep("x", 1024 * 1024)
+    abel.listen("/", function()
+      local t = {}
+      for i = 1, 10000 do t[i] = { i } end
+      return tostring(#kept)
+    end)
+  "#;
+  abel.create("a", code_a, config()).await.unwrap();
+  abel.create("b", code_b, config()).await.unwrap();
+
+  // Garbage left by either service may be collected while the other one runs,
+  // but is still charged to its own service
+  let a = abel.abel.get_running_service("a").unwrap();
+  let b = abel.abel.get_running_service("b").unwrap();
+  for _ in 0..20 {
+    let (_, body) = abel.get("a", "/").await.unwrap();
+    assert_eq!(body, "a");
+    let (_, body) = abel.get("b", "/").await.unwrap();
+    assert_eq!(body, (1024 * 1024).to_string());
+
+    let memory_usage = a.upgrade().memory_usage();
+    assert!(memory_usage < 1024 * 1024, "{memory_usage}");
+    let memory_usage = b.upgrade().memory_usage();
+    assert!(
+      (1024 * 1024..2 * 1024 * 1024).contains(&memory_usage),
+      "{memory_usage}"
+    );
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory_isolates_taking_turns() {
+  let abel = TestAbel::new();
+  let config = || Config {
+    memory_limit: Some(8 * 1024 * 1024),
+    ..Default::default()
+  };
+  let code_a = r#"
+    local kept = string.rep("x", 1024 * 1024)
+    local old = {}
+    local weak = setmetatable({ old }, { __mode = "v" })
+    abel.listen("/drop", function()
+      old = nil
+      return "dropped"
+    end)
+    abel.listen("/", function()
+      return tostring(#kept) .. " " .. tostring(weak[1] ~= nil)
+    end)
+  "#;
+  let code_b = r#"
+    abel.listen("/", function()
+      local t = {}
+      for i = 1, 1000 do t[i] = { i } end
+      return "b"
+    end)
+  "#;
+  abel.create("a", code_a, config()).await.unwrap();
+  abel.create("b", code_b, config()).await.unwrap();
+
+  // Let the object held by `a` live through a few collections and become old,
+  // so that only a full collection can free it once dropped
+  for _ in 0..5 {
+    abel.get("a", "/").await.unwrap();
+    abel.get("b", "/").await.unwrap();
+  }
+  let (_, body) = abel.get("a", "/drop").await.unwrap();
+  assert_eq!(body, "dropped");
+
+  // Switching between them does not run a full collection, which would free
+  // the dropped object, yet garbage of `b` is still charged to it
+  let a = abel.abel.get_running_service("a").unwrap();
+  let b = abel.abel.get_running_service("b").unwrap();
+  for _ in 0..20 {
+    let (_, body) = abel.get("a", "/").await.unwrap();
+    assert_eq!(body, format!("{} true", 1024 * 1024));
+    let (_, body) = abel.get("b", "/").await.unwrap();
+    assert_eq!(body, "b");
+
+    let memory_usage = a.upgrade().memory_usage();
+    assert!(
+      (1024 * 1024..2 * 1024 * 1024).contains(&memory_usage),
+      "{memory_usage}"
+    );
+    let memory_usage = b.upgrade().memory_usage();
+    assert!(memory_usage < 512 * 1024, "{memory_usage}");
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sql_max_rows_clamped() {
+  let abel = TestAbel::new();
+  let code = r#"abel.listen("/", function() return "ok" end)"#;
+  for (name, requested, expected) in [
+    ("a", None, 100),
+    ("b", Some(500), 500),
+    ("c", Some(5000), 1000),
+  ] {
+    let config = Config {
+      sql_max_rows: requested,
+      ..Default::default()
+    };
+    abel.create(name, code, config).await.unwrap();
+    let service = abel.abel.get_running_service(name).unwrap();
+    assert_eq!(service.upgrade().sql_max_rows(), expected);
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sql_limits() {
+  let abel = TestAbel::new();
+  let code = r#"
+    local sql = require "sql"
+    local count = "with recursive c(x) as (select 1 union all select x + 1 from c) "
+    abel.listen("/spin", function()
+      return tostring(sql.query(count .. "select count(*) as n from c"):read().n)
+    end)
+    abel.listen("/rows/:n:int", function(req)
+      local rows = sql.query(count .. "select x from c limit ?", req.params.n)
+      local read = 0
+      for _ in rows:iter() do
+        read = read + 1
+      end
+      return tostring(read)
+    end)
+  "#;
+  let config = Config {
+    cpu_time: CpuTimeConfig {
+      request: Some(100),
+      ..Default::default()
+    },
+    sql_max_rows: Some(150),
+    ..Default::default()
+  };
+  abel.create("sql", code, config).await.unwrap();
+
+  // Runaway statements are interrupted at the CPU time budget.
+  let error = abel.get("sql", "/spin").await.unwrap_err();
+  assert!(error.to_string().contains("timeout"), "{error}");
+
+  let (_, body) = abel.get("sql", "/rows/150").await.unwrap();
+  assert_eq!(body, "150");
+  let error = abel.get("sql", "/rows/151").await.unwrap_err();
+  assert!(error.to_string().contains("more than 150 rows"), "{error}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remove_closes_storage() {
+  use crate::service::get_kv_store;
+
+  let abel = TestAbel::with_options(|x| x.runtime_pool_size = 2);
+  let code = r#"
+    local kv = require "kv"
+    abel.listen("/", function()
+      local count = (tonumber(kv.get "count") or 0) + 1
+      kv.set("count", tostring(count))
+      return tostring(count)
+    end)
+  "#;
+  let kv_path = abel.local_storage_path().join(".kv/test");
+
+  abel.create("test", code, Config::default()).await.unwrap();
+  for _ in 0..4 {
+    let (status, _) = abel.get("test", "/").await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+  }
+  assert!(kv_path.exists());
+
+  // Removing waits for handles held elsewhere to be dropped
+  let kv_store = get_kv_store(&abel.abel.state, "test");
+  tokio::spawn(async move {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(kv_store);
+  });
+  abel.abel.stop_service("test").await.unwrap();
+  abel.abel.remove_service("test").await.unwrap();
+  assert!(!kv_path.exists());
+
+  abel.create("test", code, Config::default()).await.unwrap();
+  let (_, body) = abel.get("test", "/").await.unwrap();
+  assert_eq!(body, "1");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remove_storage_in_use() {
+  use crate::service::get_kv_store;
+
+  let abel = TestAbel::with_options(|x| x.storage_close_timeout = Duration::from_millis(100));
+  let code = r#"abel.listen("/", function() return "ok" end)"#;
+  abel.create("test", code, Config::default()).await.unwrap();
+  abel.abel.stop_service("test").await.unwrap();
+
+  let kv_store = get_kv_store(&abel.abel.state, "test");
+  let error = abel.abel.remove_service("test").await.unwrap_err();
+  assert!(
+    matches!(error.kind(), ErrorKind::ServiceStorageInUse { .. }),
+    "{error}"
+  );
+  assert!(!abel.abel.get_service("test").unwrap().is_running());
+
+  drop(kv_store);
+  abel.abel.remove_service("test").await.unwrap();
+  assert!(abel.abel.get_service("test").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_password_hashing_cpu_time() {
+  let abel = TestAbel::new();
+  let config = Config {
+    cpu_time: CpuTimeConfig {
+      request: Some(1),
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+  let code = r#"
+    local crypto = require "crypto"
+    abel.listen("/", function() return crypto.hash_password "password" end)
+  "#;
+  abel.create("test", code, config).await.unwrap();
+  let error = abel.get("test", "/").await.unwrap_err();
+  assert!(error.to_string().contains("timeout"), "{error}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_password_hashing_cancelled() {
+  let abel = TestAbel::new();
+  let code = r#"
+    local crypto = require "crypto"
+    abel.listen("/", function()
+      local p = abel.spawn(function() crypto.hash_password "password" end)
+      abel.sleep(50)
+      p:cancel()
+      return p:status()
+    end)
+  "#;
+  abel.create("test", code, Config::default()).await.unwrap();
+  let (_, body) = abel.get("test", "/").await.unwrap();
+  assert_eq!(body, "cancelled");
+
+  // The service's permit is held until hashing actually stops
+  let service = abel.abel.get_running_service("test").unwrap();
+  let permits = service.upgrade().argon2_permits.clone();
+  assert_eq!(permits.available(), 0);
+  tokio::time::timeout(Duration::from_secs(30), async {
+    while permits.available() == 0 {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_promise() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local p = abel.spawn(function(a, b)
+        abel.sleep(5)
+        return a + b, "x"
+      end, 1, 2)
+      assert(p:status() == "pending")
+      local a, b = p:await()
+      assert(a == 3 and b == "x")
+      -- Awaiting again returns the same values
+      local a, b = p:await()
+      assert(a == 3 and b == "x")
+      assert(p:status() == "fulfilled")
+      assert(p:cancel() == false)
+
+      local bad = abel.spawn(function() error "boom" end)
+      for _ = 1, 2 do
+        local ok, err = pcall(bad.await, bad)
+        assert(not ok and tostring(err):find "boom", tostring(err))
+      end
+      assert(bad:status() == "rejected")
+
+      -- Cancelling a pending promise closes what its task holds
+      local mutex = abel.Mutex()
+      local finished = false
+      local p = abel.spawn(function()
+        local _ = mutex:lock()
+        abel.sleep(100000)
+        finished = true
+      end)
+      abel.sleep(5)
+      assert(mutex:is_locked())
+      assert(p:cancel() == true)
+      assert(p:status() == "cancelled")
+      assert(not mutex:is_locked())
+      assert(p:cancel() == false)
+      local ok, err = pcall(p.await, p)
+      assert(not ok and tostring(err):find "cancelled", tostring(err))
+      assert(not finished)
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_promise_combinators() {
+  run_handler(
+    r#"
+    abel.listen("/", function()
+      local slow_finished = false
+      local function slow()
+        abel.sleep(50)
+        slow_finished = true
+        return "slow"
+      end
+      local function fast()
+        abel.sleep(5)
+        return "fast", 1
+      end
+      local function fail(msg)
+        return function()
+          abel.sleep(5)
+          error(msg)
+        end
+      end
+
+      -- race: the first to settle wins, and the loser is cancelled
+      local a, b = abel.race(slow, fast)
+      assert(a == "fast" and b == 1)
+      local ok, err = pcall(abel.race, slow, fail "race failure")
+      assert(not ok and tostring(err):find "race failure", tostring(err))
+      abel.sleep(80)
+      assert(not slow_finished, "spawned tasks should be cancelled")
+
+      -- ...but promises passed in are left running
+      local p = abel.spawn(slow)
+      assert(abel.race(p, fast) == "fast")
+      assert(p:await() == "slow" and slow_finished)
+
+      -- any: the first to be fulfilled wins, or the last error is raised
+      assert(abel.any(fail "first failure", slow) == "slow")
+      local ok, err = pcall(abel.any, fail "first failure", function()
+        abel.sleep(20)
+        error "last failure"
+      end)
+      assert(not ok and tostring(err):find "last failure", tostring(err))
+      assert(not pcall(abel.race))
+      assert(not pcall(abel.any))
+
+      -- timeout
+      local a, b = abel.timeout(100, fast)
+      assert(a == "fast" and b == 1)
+      slow_finished = false
+      local ok, err = pcall(abel.timeout, 10, slow)
+      assert(not ok and tostring(err):find "timed out after 10ms", tostring(err))
+      abel.sleep(80)
+      assert(not slow_finished, "timed out task should be cancelled")
+      local p = abel.spawn(slow)
+      assert(not pcall(abel.timeout, 10, p))
+      assert(p:status() == "pending")
+      assert(p:await() == "slow")
+      return "ok"
+    end)
+    "#,
+  )
+  .await
+}
+
+/// Serves redirects to the cloud metadata endpoint on a random local port.
+async fn serve_metadata_redirect() -> SocketAddr {
+  let make_svc = make_service_fn(|_| async {
+    Ok::<_, Infallible>(service_fn(|_| async {
+      let resp = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "http://169.254.169.254/latest/meta-data/")
+        .body(Body::empty())
+        .unwrap();
+      Ok::<_, Infallible>(resp)
+    }))
+  });
+  let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+  let addr = server.local_addr();
+  tokio::spawn(server);
+  addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_egress_private() {
+  const CODE: &str = r#"
+    local http = require "http"
+    abel.listen("/", function()
+      local uris = { "http://" .. os.getenv "ADDR" .. "/", "http://localhost:" .. os.getenv "PORT" .. "/" }
+      for _, uri in ipairs(uris) do
+        local ok, err = pcall(http.request, uri)
+        assert(not ok and tostring(err):find "egress to '[%x.:]+' is denied", tostring(err))
+      end
+      return "ok"
+    end)
+    abel.listen("/redirect", function()
+      local ok, err = pcall(http.request, "http://" .. os.getenv "ADDR" .. "/")
+      assert(not ok and tostring(err):find "egress to '169.254.169.254' is denied", tostring(err))
+      return "ok"
+    end)
+  "#;
+
+  let addr = serve_metadata_redirect().await;
+  let config = || Config {
+    env: [
+      ("ADDR".into(), addr.to_string()),
+      ("PORT".into(), addr.port().to_string()),
+    ]
+    .into(),
+    ..Default::default()
+  };
+
+  // The server is private itself, as well as the name resolving to it
+  let abel = TestAbel::new();
+  abel.create("test", CODE, config()).await.unwrap();
+  let (status, body) = abel.get("test", "/").await.unwrap();
+  assert_eq!((status, &*body), (StatusCode::OK, "ok"));
+
+  // Allowing the server does not allow where it redirects to
+  let abel = TestAbel::with_options(|opts| {
+    opts.egress.allow = Some(vec!["127.0.0.1".parse().unwrap()]);
+  });
+  abel.create("test", CODE, config()).await.unwrap();
+  let (status, body) = abel.get("test", "/redirect").await.unwrap();
+  assert_eq!((status, &*body), (StatusCode::OK, "ok"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_egress_networks() {
+  const CODE: &str = r#"
+    local http = require "http"
+    abel.listen("/", function()
+      local uri = "http://localhost:" .. os.getenv "PORT" .. "/"
+      local ok, resp = pcall(http.request, { uri = uri, follow_redirects = false })
+      return ok and tostring(resp.status) or tostring(resp)
+    end)
+  "#;
+
+  let addr = serve_metadata_redirect().await;
+  let config = |egress: &[&str]| Config {
+    env: [("PORT".into(), addr.port().to_string())].into(),
+    egress: Some(egress.iter().map(|x| x.parse().unwrap()).collect()),
+    ..Default::default()
+  };
+
+  // Host names resolving into allowed networks are allowed, by the server and
+  // by services alike
+  let abel = TestAbel::with_options(|opts| {
+    opts.egress.allow = Some(vec!["127.0.0.0/8".parse().unwrap()]);
+  });
+  abel.create("allowed", CODE, config(&["127.0.0.1"])).await.unwrap();
+  let (_, body) = abel.get("allowed", "/").await.unwrap();
+  assert_eq!(body, "302");
+
+  abel.create("denied", CODE, config(&["127.0.0.2"])).await.unwrap();
+  let (_, body) = abel.get("denied", "/").await.unwrap();
+  assert!(body.contains("is denied"), "{body}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_event_stream() {
+  let abel = TestAbel::new();
+  let code = r#"
+    local http = require "http"
+    abel.listen("/", function(req)
+      local es = http.EventStream(req, { heartbeat = 0 })
+      abel.spawn(function()
+        es:write { event = "greeting", data = "hello\nworld", id = "1" }
+        es:write { data = { foo = "bar" }, retry = 1000 }
+        es:write "plain"
+        es:write "crlf\r\nlines"
+        es:write "x\revent: evil\rid: 666"
+        es:write ""
+        es:write(es.last_event_id)
+        es:close()
+      end)
+      return es
+    end)
+  "#;
+  abel.create("test", code, Config::default()).await.unwrap();
+  let req = Request::get("/")
+    .header("last-event-id", "42")
+    .body(Body::empty())
+    .unwrap();
+  let (status, body) = abel.request("test", req).await.unwrap();
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(
+    body,
+    concat!(
+      "event: greeting\nid: 1\ndata: hello\ndata: world\n\n",
+      "retry: 1000\ndata: {\"foo\":\"bar\"}\n\n",
+      "data: plain\n\n",
+      "data: crlf\ndata: lines\n\n",
+      "data: x\ndata: event: evil\ndata: id: 666\n\n",
+      "data: \n\n",
+      "data: 42\n\n",
+    )
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_middleware_order() {
+  let abel = TestAbel::new();
+  let code = r#"
+    local trace
+    abel.use(function(req, next)
+      trace = { "outer" }
+      local resp = next()
+      trace[#trace + 1] = "outer done"
+      return table.concat(trace, ", ") .. ": " .. resp
+    end)
+    abel.use(function(req, next)
+      trace[#trace + 1] = "inner"
+      if req.uri.path == "/blocked" then
+        return "blocked"
+      end
+      local resp = next()
+      trace[#trace + 1] = "inner done"
+      return resp
+    end)
+    abel.listen("/", function(req)
+      trace[#trace + 1] = "handler"
+      assert(not pcall(abel.use, function() end), "middleware should be sealed")
+      return "ok"
+    end)
+    abel.listen("/blocked", function()
+      error "middleware should not call the handler"
+    end)
+  "#;
+  abel.create("test", code, Config::default()).await.unwrap();
+
+  let (_, body) = abel.get("test", "/").await.unwrap();
+  assert_eq!(body, "outer, inner, handler, inner done, outer done: ok");
+  let (_, body) = abel.get("test", "/blocked").await.unwrap();
+  assert_eq!(body, "outer, inner, outer done: blocked");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call() {
+  let abel = TestAbel::new();
+  let callee = r#"
+    abel.listen("/hello/:name", function(req)
+      return "hello " .. req.params.name
+    end)
+  "#;
+  let caller = r#"
+    local stream = require "stream"
+    abel.listen("/:callee", function(req)
+      local ok, resp = pcall(abel.call, req.params.callee, "/hello/abel")
+      if not ok then
+        return resp.status .. " " .. resp.error
+      end
+      return resp.status .. " " .. stream.read_all(resp.body)
+    end)
+  "#;
+  let allow = |callers: &[&str]| Config {
+    allow_callers: callers.iter().map(|x| x.to_string()).collect(),
+    ..Default::default()
+  };
+  abel
+    .create("callee", callee, allow(&["caller"]))
+    .await
+    .unwrap();
+  abel.create("public", callee, allow(&["*"])).await.unwrap();
+  abel
+    .create("private", callee, Config::default())
+    .await
+    .unwrap();
+  abel.create("stopped", callee, allow(&["*"])).await.unwrap();
+  abel.abel.stop_service("stopped").await.unwrap();
+  abel
+    .create("caller", caller, Config::default())
+    .await
+    .unwrap();
+  abel
+    .create("other", caller, Config::default())
+    .await
+    .unwrap();
+
+  for (name, path, expected) in [
+    ("caller", "/callee", "200 hello abel"),
+    ("caller", "/public", "200 hello abel"),
+    ("other", "/public", "200 hello abel"),
+    ("other", "/callee", "403 service call forbidden"),
+    ("caller", "/private", "403 service call forbidden"),
+    ("caller", "/stopped", "409 service is stopped"),
+    ("caller", "/missing", "404 service not found"),
+  ] {
+    let (status, body) = abel.get(name, path).await.unwrap();
+    assert_eq!(
+      (status, &*body),
+      (StatusCode::OK, expected),
+      "{name} {path}"
+    );
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_websocket_echo() {
+  use futures::{SinkExt, StreamExt};
+  use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+  use tokio_tungstenite::tungstenite::protocol::Role;
+  use tokio_tungstenite::tungstenite::Message;
+  use tokio_tungstenite::WebSocketStream;
+
+  let abel = TestAbel::new();
+  let code = r#"
+    abel.listen("/ws", function(req)
+      local ws = req:upgrade_websocket()
+      abel.spawn(function()
+        while true do
+          local data, kind = ws:read()
+          if not data then break end
+          ws:write(kind .. ": " .. data)
+        end
+      end)
+      return ws
+    end)
+  "#;
+  abel.create("test", code, Config::default()).await.unwrap();
+  let addr = abel.serve("test");
+
+  let error = abel.get("test", "/ws").await.unwrap_err();
+  assert!(
+    error.to_string().contains("not a WebSocket handshake"),
+    "{error}"
+  );
+
+  // Example handshake from RFC 6455
+  let req = Request::get(format!("http://{addr}/ws"))
+    .header(CONNECTION, "upgrade")
+    .header(UPGRADE, "websocket")
+    .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+    .header("sec-websocket-version", "13")
+    .body(Body::empty())
+    .unwrap();
+  let resp = hyper::Client::new().request(req).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+  assert_eq!(
+    resp.headers()[SEC_WEBSOCKET_ACCEPT],
+    "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+  );
+
+  let upgraded = hyper::upgrade::on(resp).await.unwrap();
+  let mut ws = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+  ws.send(Message::Text("hello".into())).await.unwrap();
+  ws.send(Message::Binary(vec![0xff])).await.unwrap();
+  assert_eq!(
+    ws.next().await.unwrap().unwrap(),
+    Message::Text("text: hello".into())
+  );
+  assert_eq!(
+    ws.next().await.unwrap().unwrap(),
+    Message::Binary(b"binary: \xff".to_vec())
+  );
+  ws.close(None).await.unwrap();
+  while let Some(Ok(_)) = ws.next().await {}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_scheduled_jobs() {
+  use crate::schedule::Schedule;
+
+  const JOBS: &str = r#"
+    local kv = require "kv"
+    abel.every(50, function()
+      kv.set("count", tostring(tonumber(kv.get "count" or "0") + 1))
+    end)
+    abel.schedule("0 0 1 1 *", function() end)
+    abel.listen("/count", function() return kv.get "count" or "0" end)
+  "#;
+  const NO_JOBS: &str = r#"
+    local kv = require "kv"
+    abel.listen("/count", function() return kv.get "count" or "0" end)
+  "#;
+  let abel = TestAbel::new();
+  let count = |name| {
+    let abel = &abel;
+    async move {
+      let (_, body) = abel.get(name, "/count").await.unwrap();
+      body.parse::<u32>().unwrap()
+    }
+  };
+
+  abel
+    .create("updated", JOBS, Config::default())
+    .await
+    .unwrap();
+  abel
+    .create("stopped", JOBS, Config::default())
+    .await
+    .unwrap();
+  let service = abel.abel.get_running_service("updated").unwrap();
+  let jobs = (service.upgrade().jobs().iter())
+    .map(|x| x.schedule().clone())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    jobs,
+    [Schedule::Every(50), Schedule::Cron("0 0 1 1 *".into())]
+  );
+
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(count("updated").await >= 2, "jobs should run periodically");
+
+  // Jobs of the old instance stop once hot updated
+  let source = Source::new(MainSource(NO_JOBS));
+  (abel.abel)
+    .hot_update_service("updated", None, source, Config::default())
+    .await
+    .unwrap();
+  let before = count("updated").await;
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  assert!(count("updated").await <= before + 1);
+
+  // ...and once stopped
+  let before = count("stopped").await;
+  abel.abel.stop_service("stopped").await.unwrap();
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  abel
+    .create("stopped", NO_JOBS, Config::default())
+    .await
+    .unwrap();
+  assert!(count("stopped").await <= before + 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_event_bus() {
+  const PUBLISHER: &str = r#"
+    abel.listen("/:n:int", function(req)
+      return tostring(abel.publish("greet", { n = req.params.n }))
+    end)
+  "#;
+  // Events with `n` of 0 only probe the number of subscribers
+  const SUBSCRIBER: &str = r#"
+    local kv = require "kv"
+    abel.subscribe("greet", function(value, topic)
+      assert(topic == "greet")
+      if value.n < 0 then error "bad event" end
+      if value.n > 0 then
+        kv.set("events", (kv.get "events" or "") .. value.n .. ",")
+      end
+    end)
+    abel.listen("/events", function() return kv.get "events" or "" end)
+  "#;
+  const NO_SUBSCRIBER: &str = r#"abel.listen("/", function() return "ok" end)"#;
+
+  let abel = TestAbel::new();
+  let publish = |n: i32| {
+    let abel = &abel;
+    async move {
+      let (_, body) = abel.get("pub", &format!("/{n}")).await.unwrap();
+      body.parse::<usize>().unwrap()
+    }
+  };
+
+  abel.create("pub", PUBLISHER, Config::default()).await.unwrap();
+  abel.create("sub", SUBSCRIBER, Config::default()).await.unwrap();
+  assert_eq!(publish(1).await, 1);
+
+  // Errors in handlers are logged without stopping later events
+  assert_eq!(publish(-1).await, 1);
+  assert_eq!(publish(2).await, 1);
+  let mut events = String::new();
+  for _ in 0..100 {
+    events = abel.get("sub", "/events").await.unwrap().1;
+    if events == "1,2," {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  assert_eq!(events, "1,2,");
+
+  // Subscriptions of the old instance stop once hot updated...
+  let subscribers_become = |expected: usize| {
+    let publish = &publish;
+    async move {
+      for _ in 0..100 {
+        if publish(0).await == expected {
+          return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+      }
+      panic!("subscriber count never became {expected}");
+    }
+  };
+  let source = Source::new(MainSource(SUBSCRIBER));
+  (abel.abel)
+    .hot_update_service("sub", None, source, Config::default())
+    .await
+    .unwrap();
+  subscribers_become(1).await;
+  let source = Source::new(MainSource(NO_SUBSCRIBER));
+  (abel.abel)
+    .hot_update_service("sub", None, source, Config::default())
+    .await
+    .unwrap();
+  subscribers_become(0).await;
+
+  // ...and once stopped
+  let source = Source::new(MainSource(SUBSCRIBER));
+  (abel.abel)
+    .hot_update_service("sub", None, source, Config::default())
+    .await
+    .unwrap();
+  subscribers_become(1).await;
+  abel.abel.stop_service("sub").await.unwrap();
+  subscribers_become(0).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_job_queue() {
+  const CODE: &str = r#"
+    local kv = require "kv"
+    local queue = require "queue"
+    abel.worker("record", function(payload, info)
+      assert(info.worker == "record")
+      kv.set("done", (kv.get "done" or "") .. payload.n .. "@" .. info.attempt .. ",")
+      if payload.fail then error "job failed" end
+    end)
+    abel.listen("/push/:n:int", function(req)
+      local n = req.params.n
+      local options = { delay = n == 3 and 300 or 0, max_retries = 1 }
+      return tostring(queue.push("record", { n = n, fail = n == 2 }, options))
+    end)
+    abel.listen("/done", function() return kv.get "done" or "" end)
+  "#;
+
+  let abel = TestAbel::new();
+  let done_contains = |expected: &'static str| {
+    let abel = &abel;
+    async move {
+      for _ in 0..250 {
+        let (_, done) = abel.get("test", "/done").await.unwrap();
+        if done.contains(expected) {
+          return done;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+      }
+      panic!("job '{expected}' never ran");
+    }
+  };
+  abel.create("test", CODE, Config::default()).await.unwrap();
+
+  abel.get("test", "/push/1").await.unwrap();
+  done_contains("1@1,").await;
+
+  // Failed jobs are retried, then dead-lettered once out of retries
+  let (_, id) = abel.get("test", "/push/2").await.unwrap();
+  let done = done_contains("2@2,").await;
+  assert_eq!(done, "1@1,2@1,2@2,");
+  let mut dead_jobs = Vec::new();
+  for _ in 0..250 {
+    dead_jobs = abel.abel.list_dead_jobs("test").await.unwrap();
+    if !dead_jobs.is_empty() {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  assert_eq!(dead_jobs.len(), 1);
+  let job = &dead_jobs[0];
+  assert_eq!(job.id().to_string(), id);
+  assert_eq!(job.worker(), "record");
+  assert_eq!(job.payload(), &serde_json::json!({ "n": 2, "fail": true }));
+  assert_eq!(job.attempts(), 2);
+  assert!(job.error().unwrap().contains("job failed"), "{job:?}");
+  assert_eq!(abel.abel.purge_dead_jobs("test").await.unwrap(), 1);
+  assert!(abel.abel.list_dead_jobs("test").await.unwrap().is_empty());
+
+  // Jobs left when the service stops are picked up once it starts again
+  abel.get("test", "/push/3").await.unwrap();
+  abel.abel.stop_service("test").await.unwrap();
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  abel.abel.start_service("test").await.unwrap();
+  let done = done_contains("3@1,").await;
+  assert_eq!(done, "1@1,2@1,2@2,3@1,");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_recursion() {
+  let abel = TestAbel::new();
+  let code = r#"
+    local stream = require "stream"
+    abel.listen("/depth/:n:int", function(req)
+      local n = req.params.n
+      local ok, resp = pcall(abel.call, "rec", "/depth/" .. (n + 1))
+      if not ok then
+        return n .. ": " .. tostring(resp)
+      end
+      return stream.read_all(resp.body)
+    end)
+    abel.listen("/spin", function()
+      for _ = 1, 10000000 do end
+      return "done"
+    end)
+    abel.listen("/spin_more", function()
+      for _ = 1, 5 do
+        abel.call("rec", "/spin")
+      end
+      return "done more"
+    end)
+  "#;
+  let config = Config {
+    cpu_time: CpuTimeConfig {
+      request: Some(100),
+      ..Default::default()
+    },
+    allow_callers: vec!["rec".into()],
+    ..Default::default()
+  };
+  abel.create("rec", code, config).await.unwrap();
+
+  let (_, body) = abel.get("rec", "/depth/0").await.unwrap();
+  assert!(body.starts_with("8: "), "{body}");
+  assert!(body.contains("nested deeper than 8 levels"), "{body}");
+
+  // Callees are charged to the caller's budget. Each spin takes well under the
+  // budget, but five of them do not.
+  let error = abel.get("rec", "/spin_more").await.unwrap_err();
+  assert!(error.to_string().contains("timeout"), "{error}");
+}
@@ -0,0 +1,200 @@
+use crate::service::RunningService;
+use crate::task::Pool;
+use crate::ErrorKind::{InvalidSchedule, ServiceDropped};
+use crate::Result;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{interval_at, sleep, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// When a job should be run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+  /// Every given milliseconds.
+  Every(u64),
+  /// Cron expression, evaluated in UTC.
+  ///
+  /// Both the standard five-field form and the six-field form with seconds
+  /// are accepted.
+  Cron(Box<str>),
+}
+
+impl Schedule {
+  pub fn every(ms: u64) -> Result<Self> {
+    if ms == 0 {
+      return Err(From::from(InvalidSchedule {
+        schedule: "every 0ms".into(),
+        reason: "interval must be positive".into(),
+      }));
+    }
+    Ok(Self::Every(ms))
+  }
+
+  pub fn cron(expr: &str) -> Result<Self> {
+    parse_cron(expr)?;
+    Ok(Self::Cron(expr.into()))
+  }
+
+  async fn run(&self, token: &CancellationToken, mut f: impl FnMut() -> bool) {
+    match self {
+      Self::Every(ms) => {
+        let period = Duration::from_millis(*ms);
+        let mut interval = interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+          tokio::select! {
+            _ = token.cancelled() => break,
+            _ = interval.tick() => {}
+          }
+          if !f() {
+            break;
+          }
+        }
+      }
+      Self::Cron(expr) => {
+        // Already checked when creating
+        let schedule = parse_cron(expr).unwrap();
+        for next in schedule.upcoming(Utc) {
+          let delay = (next - Utc::now()).to_std().unwrap_or_default();
+          tokio::select! {
+            _ = token.cancelled() => break,
+            _ = sleep(delay) => {}
+          }
+          if !f() {
+            break;
+          }
+        }
+      }
+    }
+  }
+}
+
+fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+  // `cron` requires the seconds field
+  let result = if expr.split_whitespace().count() == 5 {
+    cron::Schedule::from_str(&format!("0 {expr}"))
+  } else {
+    cron::Schedule::from_str(expr)
+  };
+  result.map_err(|error| {
+    From::from(InvalidSchedule {
+      schedule: expr.into(),
+      reason: error.to_string().into(),
+    })
+  })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+  #[serde(flatten)]
+  schedule: Schedule,
+  /// Index of the handler in `internal.jobs`, starting from 1.
+  #[serde(skip)]
+  pub(crate) handler: usize,
+}
+
+impl Job {
+  pub fn new(schedule: Schedule) -> Self {
+    Self {
+      schedule,
+      handler: 0,
+    }
+  }
+
+  pub fn schedule(&self) -> &Schedule {
+    &self.schedule
+  }
+}
+
+/// Cancels scheduled jobs and event subscriptions of a running service.
+///
+/// Cloning creates a new handle, so that jobs are owned by only one instance
+/// of a service.
+#[derive(Debug, Default)]
+pub(crate) struct JobsHandle(CancellationToken);
+
+impl JobsHandle {
+  pub fn cancel(&self) {
+    self.0.cancel()
+  }
+
+  pub fn token(&self) -> CancellationToken {
+    self.0.clone()
+  }
+}
+
+impl Clone for JobsHandle {
+  fn clone(&self) -> Self {
+    Self::default()
+  }
+}
+
+/// Spawns scheduled jobs of a running service onto the runtime pool.
+///
+/// Jobs run until the service's [`JobsHandle`] is cancelled, i.e. when it
+/// stops or is replaced.
+pub(crate) fn spawn_jobs(rt_pool: &Arc<Pool>, service: RunningService) {
+  let (name, jobs, token) = match service.try_upgrade() {
+    Ok(guard) => (
+      guard.name.clone(),
+      guard.jobs.clone(),
+      guard.jobs_handle.0.clone(),
+    ),
+    Err(_) => return,
+  };
+  for Job { schedule, handler } in jobs {
+    let rt_pool = rt_pool.clone();
+    let service = service.clone();
+    let name = name.clone();
+    let token = token.clone();
+    let (tx, mut rx) = mpsc::channel::<()>(1);
+
+    // Runs one at a time; ticks are dropped if there is already one pending.
+    tokio::spawn(async move {
+      while rx.recv().await.is_some() {
+        let service = service.clone();
+        let result = rt_pool
+          .scope(move |rt| async move { rt.run_job(service, handler).await })
+          .await;
+        match result {
+          Ok(()) => {}
+          Err(error) if matches!(error.kind(), ServiceDropped) => break,
+          Err(error) => warn!("Lua error when running job {handler} of service '{name}': {error}"),
+        }
+      }
+    });
+    tokio::spawn(async move {
+      schedule
+        .run(&token, || {
+          !matches!(tx.try_send(()), Err(TrySendError::Closed(_)))
+        })
+        .await
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("*/5 * * * *" => true; "five fields")]
+  #[test_case("0 */5 * * * *" => true; "with seconds")]
+  #[test_case("0 0 12 * * Mon-Fri" => true; "weekdays")]
+  #[test_case("* * *" => false; "too few fields")]
+  #[test_case("61 * * * *" => false; "out of range")]
+  fn test_schedule_cron(expr: &str) -> bool {
+    Schedule::cron(expr).is_ok()
+  }
+
+  #[test]
+  fn test_schedule_every_zero() {
+    assert!(Schedule::every(0).is_err());
+  }
+}
@@ -2,8 +2,11 @@ use super::{
   get_local_storage_path, RunningService, Service, ServiceImpl, ServiceInfo, ServiceName,
   ServicePool, ServiceState, StoppedService,
 };
+use crate::event::spawn_subscriptions;
 use crate::lua::isolate::Isolate;
+use crate::queue::spawn_queue_dispatcher;
 use crate::runtime::Runtime;
+use crate::schedule::spawn_jobs;
 use crate::source::Source;
 use crate::task::Pool;
 use crate::ErrorKind::{self, ServiceNotFound, ServiceStopped};
@@ -17,6 +20,9 @@ use uuid::Uuid;
 pub struct ErrorPayload {
   pub stop: Option<Error>,
   pub start: Option<Error>,
+  /// Problems found in the service that do not prevent it from running, e.g.
+  /// duplicate routes.
+  pub warnings: Vec<String>,
 }
 
 impl ErrorPayload {
@@ -25,7 +31,7 @@ impl ErrorPayload {
   }
 
   pub fn is_empty(&self) -> bool {
-    self.stop.is_none() && self.start.is_none()
+    self.stop.is_none() && self.start.is_none() && self.warnings.is_empty()
   }
 }
 
@@ -35,23 +41,52 @@ async fn prepare_service(
   uuid: Option<Uuid>,
   source: Source,
   config: Config,
-) -> Result<(ServiceImpl, Isolate)> {
+) -> Result<(ServiceImpl, Isolate, Vec<String>)> {
   let Config {
     pkg_name,
     description,
+    cpu_time,
+    memory_limit,
+    env,
+    sql_max_rows,
+    max_body_size,
+    allow_callers,
+    egress,
   } = config;
-  let (paths, isolate) = rt.prepare_service(&name, source.clone()).await?;
-  let service_impl = ServiceImpl {
+  let state = rt.state();
+  let memory_limit = memory_limit
+    .unwrap_or(state.default_memory_limit)
+    .min(state.max_memory_limit);
+  let mut service_impl = ServiceImpl {
     info: ServiceInfo {
       name,
       pkg_name,
       description,
-      paths,
+      paths: Vec::new(),
       uuid: uuid.unwrap_or_else(Uuid::new_v4),
+      cpu_time: cpu_time.resolve(state.default_cpu_time, state.max_cpu_time),
+      memory_limit,
+      memory_usage: Default::default(),
+      sql_max_rows: sql_max_rows
+        .unwrap_or(state.default_sql_max_rows)
+        .min(state.max_sql_max_rows),
+      max_body_size: match (max_body_size, state.max_body_size) {
+        (Some(x), Some(max)) => Some(x.min(max)),
+        (x, max) => x.or(max),
+      },
+      allow_callers,
+      egress,
+      jobs: Vec::new(),
+      subscriptions: Vec::new(),
+      workers: Vec::new(),
     },
     source,
+    env: Arc::new(env),
+    jobs_handle: Default::default(),
+    argon2_permits: Default::default(),
   };
-  Ok((service_impl, isolate))
+  let (warnings, isolate) = rt.prepare_service(&mut service_impl).await?;
+  Ok((service_impl, isolate, warnings))
 }
 
 impl ServicePool {
@@ -67,11 +102,13 @@ impl ServicePool {
     let name2 = name.clone();
     let (service_impl, error_payload) = rt_pool
       .scope(move |rt| async move {
-        let mut error_payload = ErrorPayload::empty();
-
-        let (service_impl, isolate) =
+        let (service_impl, isolate, warnings) =
           prepare_service(&rt, name2.clone(), uuid, source, config).await?;
         rt.remove_isolate(isolate)?;
+        let mut error_payload = ErrorPayload {
+          warnings,
+          ..Default::default()
+        };
 
         match Self::scope_stop(services, &rt, &*name2).await {
           Ok(_) => {}
@@ -88,7 +125,7 @@ impl ServicePool {
       .map(|(_name, service)| service.into_impl());
     assert!(self
       .services
-      .insert(name.clone(), ServiceState::Stopped(service_impl))
+      .insert(name.clone(), ServiceState::Stopped(Box::new(service_impl)))
       .is_none());
     let service = self.services.get(&*name).unwrap();
     Ok((StoppedService::from_ref(service), replaced, error_payload))
@@ -96,7 +133,7 @@ impl ServicePool {
 
   pub async fn cold_update_or_create(
     &self,
-    rt_pool: &Pool,
+    rt_pool: &Arc<Pool>,
     name: ServiceName,
     uuid: Option<Uuid>,
     source: Source,
@@ -107,14 +144,16 @@ impl ServicePool {
     let name2 = name.clone();
     let (service_state, error_payload) = rt_pool
       .scope(move |rt| async move {
-        let mut error_payload = ErrorPayload::default();
-
         let local_storage_path = get_local_storage_path(&state, &name2);
         if !local_storage_path.exists() {
           tokio::fs::create_dir(&local_storage_path).await?;
         }
-        let (service_impl, isolate) =
+        let (service_impl, isolate, warnings) =
           prepare_service(&rt, name2.clone(), uuid, source, config).await?;
+        let mut error_payload = ErrorPayload {
+          warnings,
+          ..Default::default()
+        };
 
         match Self::scope_stop(services, &rt, &*name2).await {
           Ok(_) => {}
@@ -133,7 +172,7 @@ impl ServicePool {
             error_payload.start = Some(err);
             let service_impl = state.into_impl();
             rt.expire_registry_values();
-            ServiceState::Stopped(service_impl)
+            ServiceState::Stopped(Box::new(service_impl))
           }
         };
 
@@ -151,6 +190,9 @@ impl ServicePool {
           .services
           .insert(name, ServiceState::Running(service_impl))
           .is_none());
+        spawn_jobs(rt_pool, service.clone());
+        spawn_subscriptions(rt_pool, &self.state.event_bus, service.clone());
+        spawn_queue_dispatcher(rt_pool, &self.state, service.clone());
         Ok((Service::Running(service), replaced, error_payload))
       }
       ServiceState::Stopped(_) => {
@@ -170,12 +212,12 @@ impl ServicePool {
 
   pub async fn hot_update(
     &self,
-    rt_pool: &Pool,
+    rt_pool: &Arc<Pool>,
     name: ServiceName,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
-  ) -> Result<(RunningService, ServiceImpl)> {
+  ) -> Result<(RunningService, ServiceImpl, ErrorPayload)> {
     match self.get(&*name) {
       Some(x) if x.is_stopped() => return Err(ErrorKind::ServiceStopped { name }.into()),
       None => return Err(ErrorKind::ServiceNotFound { name }.into()),
@@ -183,13 +225,14 @@ impl ServicePool {
     }
 
     let name2 = name.clone();
-    let service_impl = rt_pool
+    let (service_impl, warnings) = rt_pool
       .scope(move |rt| async move {
-        let (service_impl, isolate) = prepare_service(&rt, name2, uuid, source, config).await?;
+        let (service_impl, isolate, warnings) =
+          prepare_service(&rt, name2, uuid, source, config).await?;
         let service_impl = Arc::new(service_impl);
         rt.create_service(&service_impl.name, service_impl.downgrade(), isolate, true)
           .await?;
-        Ok::<_, crate::Error>(service_impl)
+        Ok::<_, crate::Error>((service_impl, warnings))
       })
       .await?;
 
@@ -202,7 +245,14 @@ impl ServicePool {
       .services
       .insert(name, ServiceState::Running(service_impl))
       .is_none());
+    spawn_jobs(rt_pool, service.clone());
+    spawn_subscriptions(rt_pool, &self.state.event_bus, service.clone());
+    spawn_queue_dispatcher(rt_pool, &self.state, service.clone());
 
-    Ok((service, replaced))
+    let error_payload = ErrorPayload {
+      warnings,
+      ..Default::default()
+    };
+    Ok((service, replaced, error_payload))
   }
 }
@@ -1,26 +1,38 @@
 use super::ServiceName;
-use crate::path::PathMatcher;
+use crate::event::Subscription;
+use crate::lua::crypto::Argon2Permits;
+use crate::path::Route;
+use crate::schedule::{Job, JobsHandle};
 use crate::source::Source;
+use crate::task::MemoryUsage;
 use crate::ErrorKind::ServiceDropped;
-use crate::Result;
+use crate::{CpuTimeLimits, EgressRule, Result};
 use dashmap::mapref::multiple::RefMulti;
 use dashmap::mapref::one::Ref;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::{Arc, Weak};
 use uuid::Uuid;
 
-pub(super) enum ServiceState {
+#[derive(Debug)]
+pub(crate) enum ServiceState {
   Running(Arc<ServiceImpl>),
-  Stopped(ServiceImpl),
+  Stopped(Box<ServiceImpl>),
 }
 
 impl ServiceState {
   pub fn into_impl(self) -> ServiceImpl {
     match self {
-      Self::Running(x) => Arc::try_unwrap(x).unwrap_or_else(|arc| arc.as_ref().clone()),
-      Self::Stopped(x) => x,
+      Self::Running(x) => {
+        x.jobs_handle.cancel();
+        let mut x = Arc::try_unwrap(x).unwrap_or_else(|arc| arc.as_ref().clone());
+        // Give it a fresh handle for the next start
+        x.jobs_handle = Default::default();
+        x
+      }
+      Self::Stopped(x) => *x,
     }
   }
 }
@@ -29,6 +41,9 @@ impl ServiceState {
 pub struct ServiceImpl {
   pub(crate) info: ServiceInfo,
   pub(crate) source: Source,
+  pub(crate) env: Arc<HashMap<String, String>>,
+  pub(crate) jobs_handle: JobsHandle,
+  pub(crate) argon2_permits: Argon2Permits,
 }
 
 impl ServiceImpl {
@@ -45,6 +60,10 @@ impl ServiceImpl {
   pub fn source(&self) -> &Source {
     &self.source
   }
+
+  pub fn env(&self) -> &HashMap<String, String> {
+    &self.env
+  }
 }
 
 impl Deref for ServiceImpl {
@@ -60,8 +79,18 @@ pub struct ServiceInfo {
   pub(crate) name: ServiceName,
   pub(crate) pkg_name: Option<String>,
   pub(crate) description: Option<String>,
-  pub(crate) paths: Vec<PathMatcher>,
+  pub(crate) paths: Vec<Route>,
   pub(crate) uuid: Uuid,
+  pub(crate) cpu_time: CpuTimeLimits,
+  pub(crate) memory_limit: usize,
+  pub(crate) memory_usage: MemoryUsage,
+  pub(crate) sql_max_rows: usize,
+  pub(crate) max_body_size: Option<u64>,
+  pub(crate) allow_callers: Vec<String>,
+  pub(crate) egress: Option<Vec<EgressRule>>,
+  pub(crate) jobs: Vec<Job>,
+  pub(crate) subscriptions: Vec<Subscription>,
+  pub(crate) workers: Vec<String>,
 }
 
 #[rustfmt::skip]
@@ -69,8 +98,24 @@ impl ServiceInfo {
   pub fn name(&self) -> &str { &self.name }
   pub fn pkg_name(&self) -> Option<&str> { self.pkg_name.as_deref() }
   pub fn description(&self) -> Option<&str> { self.description.as_deref() }
-  pub fn paths(&self) -> &[PathMatcher] { &self.paths }
+  pub fn paths(&self) -> &[Route] { &self.paths }
   pub fn uuid(&self) -> Uuid { self.uuid }
+  pub fn cpu_time(&self) -> &CpuTimeLimits { &self.cpu_time }
+  pub fn memory_limit(&self) -> usize { self.memory_limit }
+  pub fn memory_usage(&self) -> usize { self.memory_usage.get() }
+  pub fn sql_max_rows(&self) -> usize { self.sql_max_rows }
+  pub fn max_body_size(&self) -> Option<u64> { self.max_body_size }
+  pub fn allow_callers(&self) -> &[String] { &self.allow_callers }
+  pub fn egress(&self) -> Option<&[EgressRule]> { self.egress.as_deref() }
+  pub fn jobs(&self) -> &[Job] { &self.jobs }
+  pub fn subscriptions(&self) -> &[Subscription] { &self.subscriptions }
+  pub fn workers(&self) -> &[String] { &self.workers }
+}
+
+impl ServiceInfo {
+  pub(crate) fn allows_caller(&self, caller: &str) -> bool {
+    (self.allow_callers.iter()).any(|x| x == "*" || x == caller)
+  }
 }
 
 pub enum Service<'a> {
@@ -4,7 +4,11 @@ mod impls;
 pub use create::ErrorPayload;
 pub use impls::*;
 
+use crate::event::spawn_subscriptions;
+use crate::lua::kv::KvStore;
+use crate::queue::{spawn_queue_dispatcher, JobQueue};
 use crate::runtime::Runtime;
+use crate::schedule::spawn_jobs;
 use crate::task::Pool;
 use crate::ErrorKind::*;
 use crate::{AbelState, Result};
@@ -14,9 +18,15 @@ use replace_with::{replace_with_or_abort, replace_with_or_abort_and_return};
 use smallstr::SmallString;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long removing a service waits for its databases to be released, unless
+/// set otherwise in [`AbelOptions`](crate::AbelOptions).
+pub const DEFAULT_STORAGE_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub type ServiceName = SmallString<[u8; 16]>;
-type Services = DashMap<ServiceName, ServiceState>;
+pub(crate) type Services = DashMap<ServiceName, ServiceState>;
 
 pub struct ServicePool {
   services: Arc<Services>,
@@ -26,7 +36,7 @@ pub struct ServicePool {
 impl ServicePool {
   pub fn new(state: Arc<AbelState>) -> Self {
     Self {
-      services: Default::default(),
+      services: state.services.clone(),
       state,
     }
   }
@@ -65,7 +75,7 @@ impl ServicePool {
             Ok::<_, crate::Error>(())
           })
           .await;
-        replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+        replace_with_or_abort(state, |x| ServiceState::Stopped(Box::new(x.into_impl())));
         result.map(|_| StoppedService::from_ref(service.downgrade()))
       } else {
         Err(ServiceStopped { name: name.into() }.into())
@@ -81,7 +91,7 @@ impl ServicePool {
       if let ServiceState::Running(service2) = state {
         let x = service2.downgrade();
         let result = rt.run_stop(x).await;
-        replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+        replace_with_or_abort(state, |x| ServiceState::Stopped(Box::new(x.into_impl())));
         result
       } else {
         Err(ServiceStopped { name: name.into() }.into())
@@ -102,7 +112,7 @@ impl ServicePool {
             Ok::<_, crate::Error>(())
           })
           .await;
-        replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+        replace_with_or_abort(state, |x| ServiceState::Stopped(Box::new(x.into_impl())));
         if let Err(error) = result {
           warn!(
             "Lua error when stopping service '{}': {error}",
@@ -113,12 +123,12 @@ impl ServicePool {
     }
   }
 
-  pub async fn start(&self, rt_pool: &Pool, name: &str) -> Result<RunningService> {
+  pub async fn start(&self, rt_pool: &Arc<Pool>, name: &str) -> Result<RunningService> {
     if let Some(mut service) = self.services.get_mut(name) {
       if let state @ ServiceState::Stopped(_) = service.value_mut() {
         let running = replace_with_or_abort_and_return(state, |x| {
           if let ServiceState::Stopped(s) = x {
-            let s = Arc::new(s);
+            let s = Arc::new(*s);
             (s.downgrade(), ServiceState::Running(s))
           } else {
             unreachable!()
@@ -132,9 +142,14 @@ impl ServicePool {
           })
           .await;
         match result {
-          Ok(_) => Ok(running),
+          Ok(_) => {
+            spawn_jobs(rt_pool, running.clone());
+            spawn_subscriptions(rt_pool, &self.state.event_bus, running.clone());
+            spawn_queue_dispatcher(rt_pool, &self.state, running.clone());
+            Ok(running)
+          }
           Err(error) => {
-            replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+            replace_with_or_abort(state, |x| ServiceState::Stopped(Box::new(x.into_impl())));
             Err(error)
           }
         }
@@ -146,12 +161,22 @@ impl ServicePool {
     }
   }
 
-  pub async fn remove(&self, state: &AbelState, name: &str) -> Result<ServiceImpl> {
+  /// Removes a stopped service along with its storage.
+  ///
+  /// Databases are closed before their directories are removed, which means
+  /// dropping isolates of the service cached on executors, and waiting for
+  /// background tasks (e.g. its job queue dispatcher) to release them. The
+  /// service is put back if any of this fails.
+  pub async fn remove(&self, rt_pool: &Pool, state: &AbelState, name: &str) -> Result<ServiceImpl> {
     if let Some((name2, old_service)) = self.services.remove(name) {
       if let ServiceState::Stopped(x) = old_service {
-        let local_storage_path = get_local_storage_path(state, name);
-        tokio::fs::remove_dir_all(local_storage_path).await?;
-        Ok(x)
+        match remove_storage(rt_pool, state, &name2).await {
+          Ok(()) => Ok(*x),
+          Err(error) => {
+            (self.services.entry(name2)).or_insert(ServiceState::Stopped(x));
+            Err(error)
+          }
+        }
       } else {
         assert!(self.services.insert(name2, old_service).is_none());
         Err(ServiceRunning { name: name.into() }.into())
@@ -162,6 +187,84 @@ impl ServicePool {
   }
 }
 
+async fn remove_storage(rt_pool: &Pool, state: &AbelState, name: &ServiceName) -> Result<()> {
+  let name2 = name.clone();
+  for result in rt_pool
+    .broadcast(move |rt| async move { rt.unload_service(&name2) })
+    .await
+  {
+    result?;
+  }
+
+  // Handles still open at the deadline are put back, so that the service's
+  // databases are not opened twice if it is kept.
+  let deadline = Instant::now() + state.storage_close_timeout;
+  let kv_store = match state.kv_stores.remove(name) {
+    Some((_, x)) => x.close(deadline).await.err(),
+    None => None,
+  };
+  let job_queue = match state.job_queues.remove(name) {
+    Some((_, x)) => x.close(deadline).await.err(),
+    None => None,
+  };
+  if kv_store.is_some() || job_queue.is_some() {
+    if let Some(x) = kv_store {
+      state.kv_stores.entry(name.clone()).or_insert(x);
+    }
+    if let Some(x) = job_queue {
+      state.job_queues.entry(name.clone()).or_insert(x);
+    }
+    return Err(ServiceStorageInUse { name: name.clone() }.into());
+  }
+
+  let local_storage_path = get_local_storage_path(state, name);
+  tokio::fs::remove_dir_all(local_storage_path).await?;
+  for path in [
+    get_kv_store_path(state, name),
+    get_job_queue_path(state, name),
+  ] {
+    if path.exists() {
+      tokio::fs::remove_dir_all(path).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Gets a running service for `caller` to call in-process.
+pub(crate) fn get_callee(state: &AbelState, caller: &str, name: &str) -> Result<RunningService> {
+  match state.services.get(name).as_deref() {
+    Some(ServiceState::Running(x)) if x.allows_caller(caller) => Ok(x.downgrade()),
+    Some(ServiceState::Running(_)) => Err(From::from(ServiceCallForbidden {
+      caller: caller.into(),
+      callee: name.into(),
+    })),
+    Some(ServiceState::Stopped(_)) => Err(ServiceStopped { name: name.into() }.into()),
+    None => Err(ServiceNotFound { name: name.into() }.into()),
+  }
+}
+
 pub(crate) fn get_local_storage_path(state: &AbelState, name: &str) -> PathBuf {
   state.local_storage_path.join(name)
 }
+
+// Kept out of the service's local storage, so that `fs` cannot touch it.
+// Service names never start with a dot. Same for job queues below.
+fn get_kv_store_path(state: &AbelState, name: &str) -> PathBuf {
+  state.local_storage_path.join(".kv").join(name)
+}
+
+pub(crate) fn get_kv_store(state: &AbelState, name: &str) -> KvStore {
+  (state.kv_stores.entry(name.into()))
+    .or_insert_with(|| KvStore::new(get_kv_store_path(state, name)))
+    .clone()
+}
+
+fn get_job_queue_path(state: &AbelState, name: &str) -> PathBuf {
+  state.local_storage_path.join(".queue").join(name)
+}
+
+pub(crate) fn get_job_queue(state: &AbelState, name: &str) -> JobQueue {
+  (state.job_queues.entry(name.into()))
+    .or_insert_with(|| JobQueue::new(get_job_queue_path(state, name)))
+    .clone()
+}
@@ -1,14 +1,208 @@
 use mlua::{Function, Lua, RegistryKey, Table, ToLua};
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::cell::Ref;
-use std::rc::Rc;
-use std::sync::Arc;
+use std::os::raw::c_int;
+use std::rc::{self, Rc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
+/// Default CPU time budget of a task.
+pub const DEFAULT_CPU_TIME_LIMIT: Duration = Duration::from_secs(1);
+
+/// Maximum depth of nested calls between services with `abel.call`.
+pub const MAX_CALL_DEPTH: usize = 8;
+
+/// Heap growth in percent after which Lua runs a minor collection in
+/// generational mode.
+pub(crate) const GC_MINOR_MULTIPLIER: c_int = 20;
+
 #[derive(Debug, Clone, Default)]
 pub struct TaskContext {
   pub close_table: Option<Rc<RegistryKey>>,
-  pub cpu_time: Arc<Mutex<Duration>>,
+  /// Close tables of the tasks this one is spawned from, kept open until this
+  /// one finishes.
+  pub outer_close_tables: Vec<Rc<RegistryKey>>,
+  pub cpu_time: Arc<Mutex<CpuTime>>,
+  pub memory: Arc<Mutex<MemoryQuota>>,
+  /// Number of `abel.call`s this task is nested in.
+  pub call_depth: usize,
+}
+
+/// CPU time used by a task and the tasks it spawns, and the budget they share.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuTime {
+  pub used: Duration,
+  pub limit: Duration,
+}
+
+impl CpuTime {
+  pub fn new(limit: Duration) -> Self {
+    Self {
+      used: Duration::ZERO,
+      limit,
+    }
+  }
+
+  pub fn is_exceeded(&self) -> bool {
+    self.used >= self.limit
+  }
+}
+
+impl Default for CpuTime {
+  fn default() -> Self {
+    Self::new(DEFAULT_CPU_TIME_LIMIT)
+  }
+}
+
+/// Lua memory allocated by a task and the tasks it spawns, and the quota they
+/// share.
+///
+/// Allocations are accounted only while the task is being polled, since the
+/// Lua state is shared by all isolates on an executor. They are charged to the
+/// footprint of the isolate the task runs in, so that what it keeps there
+/// counts towards the quota of later tasks as well.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryQuota {
+  pub footprint: Arc<IsolateMemory>,
+  pub limit: Option<usize>,
+  checkpoint: usize,
+}
+
+impl MemoryQuota {
+  pub fn new(footprint: Arc<IsolateMemory>, limit: Option<usize>) -> Self {
+    Self {
+      footprint,
+      limit,
+      checkpoint: 0,
+    }
+  }
+
+  /// Starts accounting allocations, limiting the Lua state to the remaining
+  /// quota.
+  pub fn enter(&mut self, lua: &Lua) -> mlua::Result<()> {
+    self.footprint.switch_to(lua)?;
+    self.checkpoint = lua.used_memory();
+    if let Some(limit) = self.limit {
+      let remaining = limit.saturating_sub(self.footprint.get());
+      lua.set_memory_limit(self.checkpoint + remaining)?;
+    }
+    Ok(())
+  }
+
+  /// Stops accounting allocations and lifts the limit on the Lua state.
+  pub fn exit(&mut self, lua: &Lua) -> mlua::Result<()> {
+    lua.set_memory_limit(0)?;
+    let used = self.footprint.get() + lua.used_memory();
+    self.footprint.set(used.saturating_sub(self.checkpoint));
+    Ok(())
+  }
+}
+
+/// Memory footprint of an isolate in bytes, including what tasks running in it
+/// currently hold.
+///
+/// It is the net of allocations and frees made while its tasks are polled.
+/// The Lua state runs its garbage collector in generational mode, and when it
+/// switches to another isolate's tasks, a minor collection is run and what it
+/// frees is credited to the previous isolate. Objects allocated since the last
+/// switch are still young then, so garbage is credited to the isolate that made
+/// it without collecting every isolate's heap on each switch. If the previous
+/// isolate grew the heap enough for Lua to run minor collections on its own,
+/// its objects may have become old, and a full collection is run instead. Its
+/// own garbage is counted until the switch, but going over the quota makes Lua
+/// collect it before giving up.
+///
+/// Changes are added to the memory usage of the isolate's service, and taken
+/// back out of it when the isolate is dropped.
+#[derive(Debug, Default)]
+pub struct IsolateMemory {
+  used: AtomicUsize,
+  service: MemoryUsage,
+}
+
+impl IsolateMemory {
+  pub fn new(service: MemoryUsage) -> Self {
+    Self {
+      used: AtomicUsize::new(0),
+      service,
+    }
+  }
+
+  pub fn get(&self) -> usize {
+    self.used.load(Ordering::Relaxed)
+  }
+
+  /// Makes this isolate the one whose tasks are polled on the Lua state,
+  /// collecting young garbage left by the previous one.
+  fn switch_to(self: &Arc<Self>, lua: &Lua) -> mlua::Result<()> {
+    let (last, start) = match lua.app_data_ref::<LastIsolate>() {
+      Some(last) if Weak::as_ptr(&last.isolate) == Arc::as_ptr(self) => return Ok(()),
+      Some(last) => (last.isolate.upgrade(), last.memory),
+      None => (None, 0),
+    };
+    let before = lua.used_memory();
+    let growth = before.saturating_sub(start);
+    if growth >= start / 100 * GC_MINOR_MULTIPLIER as usize {
+      lua.gc_collect()?;
+    } else {
+      // A basic step is a minor collection in generational mode.
+      lua.gc_step()?;
+    }
+    let after = lua.used_memory();
+    if let Some(last) = last {
+      last.set(last.get().saturating_sub(before.saturating_sub(after)));
+    }
+    lua.set_app_data(LastIsolate {
+      isolate: Arc::downgrade(self),
+      memory: after,
+    });
+    Ok(())
+  }
+
+  fn set(&self, x: usize) {
+    let old = self.used.swap(x, Ordering::Relaxed);
+    self.service.0.fetch_add(x, Ordering::Relaxed);
+    self.service.0.fetch_sub(old, Ordering::Relaxed);
+  }
+}
+
+impl Drop for IsolateMemory {
+  fn drop(&mut self) {
+    self.service.0.fetch_sub(self.get(), Ordering::Relaxed);
+  }
+}
+
+/// Isolate whose tasks were last polled on a Lua state.
+struct LastIsolate {
+  isolate: Weak<IsolateMemory>,
+  /// Memory used by the Lua state when switched to the isolate.
+  memory: usize,
+}
+
+/// Memory used by the isolates of a service on every executor, in bytes.
+///
+/// Shared between clones of the same service info.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryUsage(Arc<AtomicUsize>);
+
+impl MemoryUsage {
+  pub fn get(&self) -> usize {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+impl Serialize for MemoryUsage {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    self.get().serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for MemoryUsage {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    usize::deserialize(deserializer).map(|x| Self(Arc::new(AtomicUsize::new(x))))
+  }
 }
 
 impl TaskContext {
@@ -20,6 +214,50 @@ impl TaskContext {
     })
   }
 
+  /// Creates a context for a task spawned from the current one.
+  ///
+  /// The new task shares CPU time and memory budgets with the current one, but
+  /// has its own close table, so that it can be closed on its own if the task
+  /// is cancelled.
+  pub fn new_spawned(lua: &Lua) -> mlua::Result<Self> {
+    let current = Self::get_current(lua)
+      .map(|x| x.clone())
+      .unwrap_or_default();
+    let close_table = lua.create_registry_value(lua.create_table()?)?;
+    let mut outer_close_tables = current.outer_close_tables;
+    outer_close_tables.extend(current.close_table);
+    Ok(Self {
+      close_table: Some(Rc::new(close_table)),
+      outer_close_tables,
+      cpu_time: current.cpu_time,
+      memory: current.memory,
+      call_depth: current.call_depth,
+    })
+  }
+
+  /// Creates a context for a call from the current task to another service
+  /// with `abel.call`.
+  ///
+  /// The callee is charged to the caller's CPU time and memory budgets, so that
+  /// services calling each other, or themselves, cannot multiply them. Returns
+  /// `None` if calls are nested deeper than [`MAX_CALL_DEPTH`].
+  pub fn new_called(lua: &Lua) -> mlua::Result<Option<Self>> {
+    let current = Self::get_current(lua)
+      .map(|x| x.clone())
+      .unwrap_or_default();
+    if current.call_depth >= MAX_CALL_DEPTH {
+      return Ok(None);
+    }
+    let close_table = lua.create_registry_value(lua.create_table()?)?;
+    Ok(Some(Self {
+      close_table: Some(Rc::new(close_table)),
+      outer_close_tables: Vec::new(),
+      cpu_time: current.cpu_time,
+      memory: current.memory,
+      call_depth: current.call_depth + 1,
+    }))
+  }
+
   pub fn set_current(&self, lua: &Lua) {
     lua.set_app_data(self.clone());
   }
@@ -32,17 +270,45 @@ impl TaskContext {
     lua.remove_app_data::<Self>()
   }
 
+  /// Closes this task's close table, along with outer ones that are no longer
+  /// shared with other tasks.
   pub fn try_close(&mut self, lua: &Lua) -> mlua::Result<()> {
-    if let Some(context) = self.close_table.take().and_then(|x| Rc::try_unwrap(x).ok()) {
-      let context_table: Table = lua.registry_value(&context)?;
-      lua.remove_registry_value(context)?;
-      for v in context_table.sequence_values() {
-        close_value(v?)?;
+    let outer = std::mem::take(&mut self.outer_close_tables);
+    for context in self
+      .close_table
+      .take()
+      .into_iter()
+      .chain(outer.into_iter().rev())
+    {
+      if let Ok(context) = Rc::try_unwrap(context) {
+        let context_table: Table = lua.registry_value(&context)?;
+        lua.remove_registry_value(context)?;
+        close_table_values(context_table)?;
       }
     }
     Ok(())
   }
 
+  /// Resets CPU time usage of the current task and gives it new budgets,
+  /// charging its allocations from now on to the given memory quota.
+  ///
+  /// Does nothing if not running inside a task, or if the task is a call from
+  /// another service, which keeps using the caller's budgets.
+  pub fn reset_limits(lua: &Lua, cpu_time: Duration, memory: MemoryQuota) -> mlua::Result<()> {
+    // Switching quotas may collect garbage, which needs the app data unborrowed
+    let budgets = Self::get_current(lua)
+      .filter(|x| x.call_depth == 0)
+      .map(|x| (x.cpu_time.clone(), x.memory.clone()));
+    if let Some((current_cpu_time, current_memory)) = budgets {
+      *current_cpu_time.lock() = CpuTime::new(cpu_time);
+      let mut quota = current_memory.lock();
+      quota.exit(lua)?;
+      *quota = memory;
+      quota.enter(lua)?;
+    }
+    Ok(())
+  }
+
   pub fn register<'lua, T: ToLua<'lua>>(lua: &'lua Lua, value: T) -> mlua::Result<()> {
     if let Some(ctx) = Self::get_current(lua) {
       if let Some(close_table) = &ctx.close_table {
@@ -52,14 +318,57 @@ impl TaskContext {
     }
     Ok(())
   }
+
+  /// Close table of the current task, for unregistering values from it later.
+  pub fn current_close_table(lua: &Lua) -> Option<rc::Weak<RegistryKey>> {
+    Self::get_current(lua).and_then(|x| x.close_table.as_ref().map(Rc::downgrade))
+  }
+
+  /// Removes a value closed early from the close table it was registered to,
+  /// so that long-running tasks do not keep it until they finish.
+  pub fn unregister<'lua, T: ToLua<'lua>>(
+    lua: &'lua Lua,
+    close_table: &rc::Weak<RegistryKey>,
+    value: T,
+  ) -> mlua::Result<()> {
+    // Already closed if the task has finished
+    if let Some(close_table) = close_table.upgrade() {
+      let context: Table = lua.registry_value(&close_table)?;
+      let value = value.to_lua(lua)?;
+      // Values closed early are most likely registered recently
+      for i in (1..=context.raw_len()).rev() {
+        if context.raw_get::<_, mlua::Value>(i)? == value {
+          context.raw_remove(i)?;
+          break;
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 impl PartialEq for TaskContext {
   fn eq(&self, other: &Self) -> bool {
-    self.close_table == other.close_table && Arc::ptr_eq(&self.cpu_time, &other.cpu_time)
+    self.close_table == other.close_table
+      && self.outer_close_tables == other.outer_close_tables
+      && Arc::ptr_eq(&self.cpu_time, &other.cpu_time)
+      && Arc::ptr_eq(&self.memory, &other.memory)
+      && self.call_depth == other.call_depth
   }
 }
 
+/// Closes and removes every value in a close table.
+pub fn close_table_values(table: Table) -> mlua::Result<()> {
+  let values = (table.clone().sequence_values()).collect::<mlua::Result<Vec<mlua::Value>>>()?;
+  for i in 1..=values.len() {
+    table.raw_set(i, mlua::Value::Nil)?;
+  }
+  for v in values {
+    close_value(v)?;
+  }
+  Ok(())
+}
+
 pub fn close_value(v: mlua::Value) -> mlua::Result<()> {
   let close: Option<mlua::Result<Function>> = match &v {
     mlua::Value::Table(x) => x.get_metatable().map(|x| x.raw_get("__close")),
@@ -67,16 +67,19 @@ impl Executor {
           let mut clean_interval = tokio::time::interval_at(Instant::now() + dur, dur);
 
           loop {
-            {
+            // Polling local tasks may push new ones, e.g. nested `abel.call`s,
+            // which would otherwise wait for an unrelated wake-up.
+            loop {
               let mut local_tasks = rt.lua().app_data_mut::<Vec<LocalTask>>().unwrap();
-              if !local_tasks.is_empty() {
-                let iter = local_tasks
-                  .drain(..)
-                  .map(|task| TaskFuture::from_local_task(rt.clone(), task));
-                tasks.extend(iter);
-                drop(local_tasks);
-                waker_poll(&waker, &mut tasks);
+              if local_tasks.is_empty() {
+                break;
               }
+              let iter = local_tasks
+                .drain(..)
+                .map(|task| TaskFuture::from_local_task(rt.clone(), task));
+              tasks.extend(iter);
+              drop(local_tasks);
+              waker_poll(&waker, &mut tasks);
             }
 
             let stop_rx_mut = Pin::new(&mut stop_rx);
@@ -3,7 +3,11 @@ mod executor;
 mod pool;
 mod task_future;
 
-pub use context::{close_value, TaskContext};
+pub(crate) use context::GC_MINOR_MULTIPLIER;
+pub use context::{
+  close_table_values, close_value, CpuTime, IsolateMemory, MemoryQuota, MemoryUsage, TaskContext,
+  MAX_CALL_DEPTH,
+};
 pub use executor::Executor;
 pub use pool::Pool;
 pub use task_future::TimeoutError;
@@ -16,7 +20,6 @@ use parking_lot::Mutex;
 use std::any::Any;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::RecvError;
 
@@ -27,7 +30,7 @@ pub struct SharedTask(Arc<Mutex<Option<OwnedTask>>>);
 
 impl SharedTask {
   pub fn new<'a, F, Fut>(
-    init_cpu_time: Arc<Mutex<Duration>>,
+    init_cpu_time: Arc<Mutex<CpuTime>>,
     task_fn: F,
   ) -> (
     Self,
@@ -61,12 +64,12 @@ impl Clone for SharedTask {
 pub struct OwnedTask {
   task_fn: TaskFn,
   tx: oneshot::Sender<AnyBox>,
-  init_cpu_time: Arc<Mutex<Duration>>,
+  init_cpu_time: Arc<Mutex<CpuTime>>,
 }
 
 impl OwnedTask {
   pub fn new<'a, F, Fut>(
-    cpu_time: Arc<Mutex<Duration>>,
+    cpu_time: Arc<Mutex<CpuTime>>,
     task_fn: F,
   ) -> (
     Self,
@@ -1,6 +1,7 @@
 use crate::runtime::Runtime;
-use crate::task::{Executor, SharedTask};
+use crate::task::{Executor, OwnedTask, SharedTask};
 use crate::Result;
+use futures::future::join_all;
 use futures::Future;
 use log::error;
 use std::rc::Rc;
@@ -58,4 +59,31 @@ impl Pool {
 
     *rx.await.unwrap()
   }
+
+  /// Runs a task on every executor, e.g. to drop what they have cached.
+  ///
+  /// Executors that have panicked are skipped, as their runtimes are gone.
+  pub async fn broadcast<F, Fut, R>(&self, task_fn: F) -> Vec<R>
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = R> + 'static,
+    R: Send + 'static,
+  {
+    let mut rxs = Vec::with_capacity(self.executors.len());
+    for e in &self.executors {
+      let rl = e.read().await;
+      if rl.is_panicked() {
+        continue;
+      }
+      let (task, rx) = OwnedTask::new(Default::default(), task_fn.clone());
+      if rl.send(task).await.is_err() {
+        error!("task send failed");
+        continue;
+      }
+      rxs.push(rx);
+    }
+    (join_all(rxs).await.into_iter())
+      .filter_map(|x| x.ok().map(|x| *x))
+      .collect()
+  }
 }
@@ -9,7 +9,7 @@ use std::cell::RefCell;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::oneshot;
 
@@ -54,31 +54,39 @@ impl Future for TaskFuture {
     this.context.set_current(lua);
 
     let hook_triggers = HookTriggers::every_nth_instruction(1048576);
-    lua.set_hook(hook_triggers, {
+    let hooked = lua.set_hook(hook_triggers, {
       let t1 = RefCell::new(Instant::now());
       let cpu_time = this.context.cpu_time.clone();
       move |_lua, _| {
         let mut cpu_time = cpu_time.lock();
         let t2 = Instant::now();
         let dur = t2.duration_since(*t1.borrow());
-        *cpu_time += dur;
+        cpu_time.used += dur;
 
-        if *cpu_time >= Duration::from_secs(1) {
+        if cpu_time.is_exceeded() {
           Err(TimeoutError(()).to_lua_err())
         } else {
           *t1.borrow_mut() = t2;
           Ok(())
         }
       }
-    })?;
+    });
 
-    let poll = this.task.poll(cx);
+    // Errors are propagated only after restoring the state below, as the Lua
+    // state is shared with other tasks.
+    let memory = &this.context.memory;
+    let poll = hooked
+      .and_then(|()| memory.lock().enter(lua))
+      .and_then(|()| {
+        let poll = this.task.poll(cx);
+        memory.lock().exit(lua).map(|()| poll)
+      });
     lua.remove_hook();
     let x = TaskContext::remove_current(lua);
     assert_eq!(x.as_ref(), Some(&*this.context));
     drop(x);
 
-    match poll {
+    match poll? {
       Poll::Ready(result) => {
         if let Some(tx) = this.tx.take() {
           let _ = tx.send(result);
@@ -1,7 +1,4 @@
-local function hello(req)
+abel.listen("/:name?", function(req)
   local name = req.params.name or "world"
   return { greeting = "Hello, " .. name .. "!" }
-end
-
-abel.listen("/", hello)
-abel.listen("/:name", hello)
+end)
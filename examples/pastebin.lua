@@ -3,34 +3,14 @@
 -- This example will keep updating with upcoming new features of Abel.
 
 local rand = require "rand"
+local encoding = require "encoding"
 local fs = require "fs"
 local http = require "http"
 
 local SIZE_THRESHOLD = 1048576
 
-local MethodNotAllowed = HttpError {
-  status = 405,
-  error = "method not allowed",
-  detail = function(got, allowed)
-    return { got = got, allowed = allowed }
-  end
-}
-
-local FileTooLarge = HttpError {
-  status = 413,
-  error = "file too large",
-  detail = {
-    max = SIZE_THRESHOLD
-  }
-}
-
 local function gen_uid()
-  local result = ""
-  for _ = 1, 8 do
-    local v = rand.ThreadRng:gen_range(0, 0xf)
-    result = result .. ("%x"):format(v)
-  end
-  return result
+  return encoding.hex.encode(rand.OsRng:bytes(4))
 end
 
 function abel.start()
@@ -38,45 +18,25 @@ function abel.start()
 end
 
 -- Upload file
-abel.listen("/", function(req)
-  if req.method ~= "POST" then
-    error(MethodNotAllowed(req.method, { "POST" }))
-  end
-
-  local size = tonumber(req.headers.content_length)
-  if size and size > SIZE_THRESHOLD then
-    error(FileTooLarge { got = size })
-  end
-
+abel.listen("POST /", function(req)
   local uid = gen_uid()
-  local file <close> = fs.open("files/" .. uid, "w")
-
-  local limiter = {
-    size = 0,
-    transform = function(self, bytes)
-      self.size = self.size + #bytes
-      if self.size > SIZE_THRESHOLD then
-        fs.remove("files/" .. uid)
-        file:close()
-        error(FileTooLarge)
-      end
-      return bytes
-    end
-  }
-
-  req.body
-    :pipe_through(limiter)
-    :pipe_to(file)
+  local path = "files/" .. uid
+  local file <close> = fs.open(path, "w")
+
+  -- Reading the body fails with a 413 error once it grows too large
+  local body = req.body
+  local ok, err = pcall(body.pipe_to, body, file)
+  if not ok then
+    file:close()
+    fs.remove(path)
+    error(err)
+  end
 
   return { uid = uid }
-end)
+end, { max_body_size = SIZE_THRESHOLD })
 
 -- Download file
-abel.listen("/:uid", function(req)
-  if req.method ~= "GET" then
-    error(MethodNotAllowed(req.method, { "GET" }))
-  end
-
+abel.listen("GET /:uid", function(req)
   local uid = req.params.uid
   if #uid ~= 8 then
     error { status = 400, error = "invalid UID" }
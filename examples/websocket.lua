@@ -0,0 +1,22 @@
+local HandshakeError = HttpError {
+  status = 400,
+  error = "WebSocket handshake failed",
+  detail = function(msg)
+    return { msg = msg }
+  end
+}
+
+abel.listen("GET /echo", function(req)
+  local success, ws_or_err = pcall(req.upgrade_websocket, req)
+  if not success then
+    error(HandshakeError(ws_or_err))
+  end
+
+  local ws = ws_or_err
+  abel.spawn(function()
+    for msg, kind in ws.read, ws do
+      ws:write(msg, kind)
+    end
+  end)
+  return ws
+end)